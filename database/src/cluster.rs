@@ -0,0 +1,733 @@
+/// Copyright (c) Algorealm, Inc.
+use reqwest::{Client, Method};
+use rocket::{
+    futures::future::join_all,
+    http::Status,
+    serde::json::{serde_json::json, Value},
+};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::{config, db, prelude::*, util};
+
+/// A node of the cluster, as listed in the `[nodes]` section of the config file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub url: String,
+}
+
+/// name of this node in the cluster
+pub fn node_name() -> String {
+    config::get().cluster.node.clone()
+}
+
+/// nodes of the cluster, this one included
+pub fn members() -> Vec<Member> {
+    config::get()
+        .nodes
+        .iter()
+        .map(|(name, url)| Member {
+            name: name.clone(),
+            url: url.trim_end_matches('/').to_owned(),
+        })
+        .collect()
+}
+
+/// number of nodes holding each document of a database, unless set when it was created
+pub fn default_replicas() -> usize {
+    config::get().cluster.n
+}
+
+/// number of shards databases are split into
+pub fn shard_count() -> u32 {
+    config::get().cluster.shards
+}
+
+/// Check whether this node shares its databases with other nodes.
+/// A node missing from its own membership list keeps all of its data.
+pub fn is_clustered(members: &[Member]) -> bool {
+    let node = node_name();
+    members.len() > 1 && members.iter().any(|member| member.name == node)
+}
+
+/// position of a key on the hash ring
+fn hash(key: &str) -> u32 {
+    let digest = md5::compute(key);
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// Ranges of document ID hashes held by each shard, splitting the hash space evenly
+fn shard_ranges(shards: u32) -> Vec<(u32, u32)> {
+    let start = |shard: u64| (shard << 32).div_ceil(shards as u64);
+    (0..shards as u64)
+        .map(|shard| (start(shard) as u32, (start(shard + 1) - 1) as u32))
+        .collect()
+}
+
+/// name of the range of a shard, as in `00000000-1fffffff`
+fn range_name((start, end): (u32, u32)) -> String {
+    format!("{:08x}-{:08x}", start, end)
+}
+
+/// Place a shard on the nodes following it on a ring of virtual nodes, so that adding or
+/// removing a node only moves the shards next to it. The first node is the one writes go to.
+fn shard_replicas(ring: &BTreeMap<u32, &Member>, key: &str, n: usize) -> Vec<Member> {
+    let mut replicas: Vec<Member> = Vec::with_capacity(n);
+    for (_, member) in ring.range(hash(key)..).chain(ring.iter()) {
+        if replicas.len() == n {
+            break;
+        }
+        if !replicas.contains(member) {
+            replicas.push((*member).clone());
+        }
+    }
+
+    replicas
+}
+
+/// virtual nodes of the members on the hash ring
+fn ring(members: &[Member]) -> BTreeMap<u32, &Member> {
+    members
+        .iter()
+        .flat_map(|member| {
+            (0..CLUSTER_VNODES)
+                .map(move |vnode| (hash(&format!("{}-{}", member.name, vnode)), member))
+        })
+        .collect()
+}
+
+/// key placing a shard of a database on the ring
+fn shard_key(application_did: &Did, db_name: &str, range: (u32, u32)) -> String {
+    format!("{}/{}/{}", application_did.0, db_name, range_name(range))
+}
+
+/// Return the nodes holding each shard of a database, by hash range
+pub fn placement(application_did: &Did, db_name: &str, n: usize) -> BTreeMap<String, Vec<String>> {
+    let members = members();
+    if !is_clustered(&members) {
+        let node = match node_name() {
+            node if node.is_empty() => "local".to_owned(),
+            node => node,
+        };
+        return BTreeMap::from([(range_name((0, u32::MAX)), vec![node])]);
+    }
+
+    let ring = ring(&members);
+    shard_ranges(shard_count())
+        .into_iter()
+        .map(|range| {
+            let replicas = shard_replicas(&ring, &shard_key(application_did, db_name, range), n);
+            (
+                range_name(range),
+                replicas.into_iter().map(|member| member.name).collect(),
+            )
+        })
+        .collect()
+}
+
+/// number of nodes holding each document of a database
+pub fn replica_count(config: &DbConfig, application_did: &Did, db_name: &str) -> usize {
+    db::database_replicas(config, application_did, db_name)
+        .ok()
+        .flatten()
+        .unwrap_or_else(default_replicas)
+}
+
+/// Check whether the nodes reached hold a replica of every shard of a database
+pub fn covers(application_did: &Did, db_name: &str, n: usize, reached: &[String]) -> bool {
+    placement(application_did, db_name, n)
+        .values()
+        .all(|nodes| nodes.iter().any(|node| reached.contains(node)))
+}
+
+/// Return the nodes holding a document, or nothing outside a cluster
+pub fn replicas(application_did: &Did, db_name: &str, doc_id: &str, n: usize) -> Vec<Member> {
+    let members = members();
+    if !is_clustered(&members) {
+        return Vec::new();
+    }
+
+    let shards = shard_count();
+    let shard = ((hash(doc_id) as u64 * shards as u64) >> 32) as usize;
+    match shard_ranges(shards).get(shard) {
+        Some(range) => shard_replicas(
+            &ring(&members),
+            &shard_key(application_did, db_name, *range),
+            n,
+        ),
+        None => Vec::new(),
+    }
+}
+
+/// Send a request to a node of the cluster on behalf of a client, with its credentials
+async fn send(
+    member: &Member,
+    request: &ClusterRequest,
+    method: Method,
+    uri: &str,
+    body: Option<Vec<u8>>,
+) -> Result<(Status, Value), reqwest::Error> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(REPLICATION_REQUEST_TIMEOUT))
+        .build()?;
+
+    let mut builder = client
+        .request(method, format!("{}{}", member.url, uri))
+        .header(FORWARDED_HEADER, node_name());
+    for (name, value) in &request.headers {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = body {
+        builder = builder.body(body);
+    }
+
+    let response = builder.send().await?;
+    let status = Status::from_code(response.status().as_u16()).unwrap_or(Status::BadGateway);
+    let body = response.json::<Value>().await.unwrap_or(Value::Null);
+
+    Ok((status, body))
+}
+
+/// response given for a node that could not be reached
+fn unreachable(member: &Member) -> (Status, Value) {
+    (
+        Status::BadGateway,
+        json!({
+            "error": format!("Could not reach node `{}`", member.name)
+        }),
+    )
+}
+
+/// Send a request to several nodes at once. Their responses are received as they come,
+/// and requests still running when the receiver is dropped carry on.
+fn send_all(
+    members: &[Member],
+    request: &ClusterRequest,
+    method: Method,
+    uri: &str,
+    body: Option<Vec<u8>>,
+) -> mpsc::Receiver<(Member, (Status, Value))> {
+    let (sender, receiver) = mpsc::channel(members.len().max(1));
+    for member in members {
+        let (sender, member, request, method, uri, body) = (
+            sender.clone(),
+            member.clone(),
+            request.clone(),
+            method.clone(),
+            uri.to_owned(),
+            body.clone(),
+        );
+        tokio::spawn(async move {
+            let response = send(&member, &request, method, &uri, body)
+                .await
+                .unwrap_or_else(|_| unreachable(&member));
+            let _ = sender.send((member, response)).await;
+        });
+    }
+
+    receiver
+}
+
+/// revisions leading to a document read with `revs=true`, newest first
+fn revision_history(document: &Value) -> Vec<String> {
+    let start = document["_revisions"]["start"].as_u64().unwrap_or_default();
+    document["_revisions"]["ids"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter_map(|(index, id)| {
+            Some(format!(
+                "{}-{}",
+                start.checked_sub(index as u64)?,
+                id.as_str()?
+            ))
+        })
+        .collect()
+}
+
+/// The revision replicas agree on, among those read with `revs=true` that no other one replied
+/// descends from: one that is not deleted if there is one, then the one with the highest
+/// generation, then the one with the highest hash, as each replica picks its winning revision
+fn latest_revision<'a>(documents: &[&'a Value]) -> Option<&'a Value> {
+    let histories = documents
+        .iter()
+        .map(|document| revision_history(document))
+        .collect::<Vec<_>>();
+    documents
+        .iter()
+        .filter(|document| {
+            let rev = document["_rev"].as_str().unwrap_or_default();
+            !histories.iter().any(|history| {
+                history.first().map(String::as_str) != Some(rev)
+                    && history.iter().any(|known| known == rev)
+            })
+        })
+        .max_by_key(|document| {
+            (
+                document["_deleted"] != true,
+                document["_rev"].as_str().and_then(util::parse_rev),
+            )
+        })
+        .copied()
+}
+
+/// Read a document from its replicas, answering with the latest revision once `r` of them
+/// replied. Replicas that do not have the document count as replies, and deleted revisions are
+/// compared like the others. The replicas that replied with an older revision are then sent the
+/// latest one.
+/// Reads of given revisions are answered by the first replica holding them.
+pub async fn read(replicas: &[Member], request: &ClusterRequest, r: usize) -> (Status, Value) {
+    let (path, query) = request
+        .uri
+        .split_once('?')
+        .unwrap_or((request.uri.as_str(), ""));
+    let params = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .collect::<Vec<_>>();
+    let given = params
+        .iter()
+        .any(|param| param.starts_with("rev=") || param.starts_with("open_revs="));
+    let revs = params.contains(&"revs=true");
+
+    // revisions are compared with their history, which deleted ones are read with too
+    let uri = if given {
+        request.uri.clone()
+    } else {
+        let params = params
+            .iter()
+            .filter(|param| !param.starts_with("revs="))
+            .chain([&"revs=true"])
+            .copied()
+            .collect::<Vec<_>>();
+        format!("{}?{}", path, params.join("&"))
+    };
+    let mut responses = send_all(replicas, request, Method::GET, &uri, None);
+
+    let mut replies = Vec::new();
+    while let Some((member, response)) = responses.recv().await {
+        if response.0 == Status::Ok || response.0 == Status::NotFound {
+            replies.push((member, response));
+            if replies.len() >= r {
+                break;
+            }
+        }
+    }
+
+    let documents = replies
+        .iter()
+        .filter(|(_, (status, _))| *status == Status::Ok)
+        .map(|(_, (_, document))| document)
+        .collect::<Vec<_>>();
+    let latest = if given {
+        documents.first().copied()
+    } else {
+        latest_revision(&documents)
+    };
+    let Some(latest) = latest.cloned() else {
+        return match replies.into_iter().next() {
+            Some((_, response)) => response,
+            None => (
+                Status::ServiceUnavailable,
+                json!({
+                    "error": "No replica of the document could be reached"
+                }),
+            ),
+        };
+    };
+    if given {
+        return (Status::Ok, latest);
+    }
+
+    // read repair, done with the credentials of the reader, which must be able to write
+    let stale = replies
+        .iter()
+        .filter(|(_, (_, document))| document["_rev"] != latest["_rev"])
+        .map(|(member, _)| member.clone())
+        .collect::<Vec<_>>();
+    if !stale.is_empty() {
+        let db_path = path.rsplit_once('/').map_or("", |(db_path, _)| db_path);
+        let bulk_docs = json!({ "docs": [latest], "new_edits": false }).to_string();
+        send_all(
+            &stale,
+            request,
+            Method::POST,
+            &format!("{}/_bulk_docs", db_path),
+            Some(bulk_docs.into_bytes()),
+        );
+    }
+
+    if latest["_deleted"] == true {
+        return (
+            Status::NotFound,
+            json!({
+                "error": "The document does not exist"
+            }),
+        );
+    }
+    let mut document = latest;
+    if let (false, Value::Object(fields)) = (revs, &mut document) {
+        for field in ["_id", "_did", "_revisions"] {
+            fields.remove(field);
+        }
+    }
+    (Status::Ok, document)
+}
+
+/// Write a document on its first reachable replica, then copy the revision written to the other
+/// replicas. The write is acknowledged once `w` replicas hold it, and accepted otherwise.
+pub async fn write(
+    replicas: &[Member],
+    request: &ClusterRequest,
+    method: Method,
+    body: Option<Vec<u8>>,
+    w: usize,
+) -> (Status, Value) {
+    let mut written = None;
+    for (index, member) in replicas.iter().enumerate() {
+        let response = match send(member, request, method.clone(), &request.uri, body.clone()).await
+        {
+            // only a replica that could not be reached makes the write go to the next one, since
+            // the others may have received it
+            Err(error) if error.is_connect() => continue,
+            Err(_) => unreachable(member),
+            Ok(response) => response,
+        };
+
+        written = Some((index, response));
+        break;
+    }
+    let (primary, (status, mut result)) = match written {
+        Some(written) if written.1 .0.class().is_success() => written,
+        Some((_, response)) => return response,
+        None => {
+            return (
+                Status::ServiceUnavailable,
+                json!({
+                    "error": "No replica of the document could be reached"
+                }),
+            )
+        }
+    };
+
+    // the other replicas get the revision written, with its history, as replication would copy it
+    let rev = result["rev"].as_str().unwrap_or_default().to_owned();
+    let path = request.uri.split('?').next().unwrap_or_default();
+    let document = send(
+        &replicas[primary],
+        request,
+        Method::GET,
+        &format!("{}?rev={}&revs=true", path, rev),
+        None,
+    )
+    .await;
+
+    let mut acks = 1;
+    let db_path = path.rsplit_once('/').map_or("", |(db_path, _)| db_path);
+    if let Ok((Status { code: 200 }, document)) = document {
+        let others = replicas
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != primary)
+            .map(|(_, member)| member.clone())
+            .collect::<Vec<_>>();
+        let bulk_docs = json!({ "docs": [document], "new_edits": false }).to_string();
+        let mut responses = send_all(
+            &others,
+            request,
+            Method::POST,
+            &format!("{}/_bulk_docs", db_path),
+            Some(bulk_docs.into_bytes()),
+        );
+
+        while acks < w {
+            match responses.recv().await {
+                Some((_, (Status { code: 201 }, results))) if results[0].get("error").is_none() => {
+                    acks += 1
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+
+    if acks >= w {
+        (status, result)
+    } else {
+        result["quorum"] = json!({ "w": w, "acks": acks });
+        (Status::Accepted, result)
+    }
+}
+
+/// Ask the other nodes of the cluster for their part of a request over a whole database,
+/// returning the replies of those that answered with `200 OK`.
+/// Nothing is asked outside a cluster, or when another node coordinates the request.
+pub async fn scatter(
+    request: &ClusterRequest,
+    method: Method,
+    body: Option<Vec<u8>>,
+) -> Option<Vec<(String, Value)>> {
+    let members = members();
+    if request.forwarded || !is_clustered(&members) {
+        return None;
+    }
+
+    let node = node_name();
+    let others = members
+        .into_iter()
+        .filter(|member| member.name != node)
+        .collect::<Vec<_>>();
+    let replies = others.iter().map(|member| async {
+        match send(member, request, method.clone(), &request.uri, body.clone()).await {
+            Ok((Status { code: 200 }, reply)) => Some((member.name.clone(), reply)),
+            _ => None,
+        }
+    });
+
+    Some(join_all(replies).await.into_iter().flatten().collect())
+}
+
+/// Keep the latest revision of each document gathered from several nodes
+pub fn latest_revisions(docs: impl IntoIterator<Item = Value>) -> Vec<Value> {
+    let mut latest = BTreeMap::<String, Value>::new();
+    for doc in docs {
+        let doc_id = doc["id"]
+            .as_str()
+            .or(doc["_id"].as_str())
+            .unwrap_or_default();
+        let newer = latest.get(doc_id).is_none_or(|known| {
+            doc["_rev"].as_str().and_then(util::parse_rev)
+                > known["_rev"].as_str().and_then(util::parse_rev)
+        });
+        if newer {
+            latest.insert(doc_id.to_owned(), doc);
+        }
+    }
+
+    latest.into_values().collect()
+}
+
+/// Split replicated documents by the nodes holding them, keeping the position of each document.
+/// Everything stays on this node outside a cluster, or when another node coordinates the request.
+pub fn route_documents(
+    request: &ClusterRequest,
+    application_did: &Did,
+    db_name: &str,
+    n: usize,
+    docs: &[Value],
+) -> BTreeMap<String, Vec<usize>> {
+    let node = node_name();
+    if request.forwarded || !is_clustered(&members()) {
+        return BTreeMap::from([(node, (0..docs.len()).collect())]);
+    }
+
+    let mut routes = BTreeMap::<String, Vec<usize>>::new();
+    for (index, doc) in docs.iter().enumerate() {
+        let doc_id = doc["_id"].as_str().unwrap_or_default();
+        for member in replicas(application_did, db_name, doc_id, n) {
+            routes.entry(member.name).or_default().push(index);
+        }
+    }
+
+    routes
+}
+
+/// Write replicated documents on other nodes of the cluster, each with the documents it holds.
+/// The results of each node are returned in the order of its documents, or nothing for the
+/// nodes that could not write them.
+pub async fn bulk_docs(
+    request: &ClusterRequest,
+    db_name: &str,
+    batches: Vec<(String, Vec<Value>)>,
+) -> Vec<(String, Option<Vec<Value>>)> {
+    let members = members();
+    let writes = batches.into_iter().map(|(name, docs)| {
+        let member = members.iter().find(|member| member.name == name).cloned();
+        async move {
+            let body = json!({ "docs": docs, "new_edits": false }).to_string();
+            let results = match member {
+                Some(member) => match send(
+                    &member,
+                    request,
+                    Method::POST,
+                    &format!("/{}/_bulk_docs", db_name),
+                    Some(body.into_bytes()),
+                )
+                .await
+                {
+                    Ok((Status { code: 201 }, Value::Array(results))) => Some(results),
+                    _ => None,
+                },
+                None => None,
+            };
+            (name, results)
+        }
+    });
+
+    join_all(writes).await
+}
+
+/// Repeat a request on the other nodes of the cluster, returning the nodes where it failed
+pub async fn broadcast(request: &ClusterRequest, method: Method) -> Vec<String> {
+    let members = members();
+    if request.forwarded || !is_clustered(&members) {
+        return Vec::new();
+    }
+
+    let node = node_name();
+    let mut failed = Vec::new();
+    for member in members.iter().filter(|member| member.name != node) {
+        match send(member, request, method.clone(), &request.uri, None).await {
+            // the database may already be created or deleted there
+            Ok((status, _))
+                if status.class().is_success()
+                    || status == Status::Conflict
+                    || status == Status::NotFound => {}
+            _ => failed.push(member.name.clone()),
+        }
+    }
+
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<Member> {
+        (0..count)
+            .map(|index| Member {
+                name: format!("n{}", index),
+                url: format!("http://n{}", index),
+            })
+            .collect()
+    }
+
+    /// nodes holding each of many shards, by shard key
+    fn shard_placement(members: &[Member], n: usize) -> Vec<Vec<String>> {
+        let ring = ring(members);
+        (0..1000)
+            .map(|shard| {
+                shard_replicas(&ring, &format!("did:sam:apps:app/db/{}", shard), n)
+                    .into_iter()
+                    .map(|member| member.name)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shard_ranges_split_the_hash_space() {
+        for shards in [1, 3, 8, 16, 1000] {
+            let ranges = shard_ranges(shards);
+            assert_eq!(ranges.len(), shards as usize);
+            assert_eq!(ranges.first().unwrap().0, 0);
+            assert_eq!(ranges.last().unwrap().1, u32::MAX);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].1 as u64 + 1, pair[1].0 as u64);
+            }
+
+            let sizes = ranges.iter().map(|(start, end)| end - start);
+            assert!(sizes.clone().max().unwrap() - sizes.min().unwrap() <= 1);
+        }
+    }
+
+    #[test]
+    fn documents_fall_in_the_range_of_their_shard() {
+        let shards = 8;
+        let ranges = shard_ranges(shards);
+        for doc_id in ["a", "b", "user-1", "0378f893-e48d-4b69-b821-7a3c2ea7b4b1"] {
+            let shard = ((hash(doc_id) as u64 * shards as u64) >> 32) as usize;
+            let (start, end) = ranges[shard];
+            assert!((start..=end).contains(&hash(doc_id)));
+        }
+    }
+
+    #[test]
+    fn shards_are_placed_on_distinct_nodes_the_same_way_everywhere() {
+        let members = nodes(5);
+        let placement = shard_placement(&members, 3);
+        assert_eq!(placement, shard_placement(&members, 3));
+
+        let mut reversed = members.clone();
+        reversed.reverse();
+        assert_eq!(placement, shard_placement(&reversed, 3));
+
+        for replicas in &placement {
+            assert_eq!(replicas.len(), 3);
+            let distinct = replicas.iter().collect::<std::collections::BTreeSet<_>>();
+            assert_eq!(distinct.len(), 3);
+        }
+
+        // asking for more replicas than nodes places the shard on every node
+        let ring = ring(&members[..2]);
+        assert_eq!(shard_replicas(&ring, "key", 3).len(), 2);
+    }
+
+    #[test]
+    fn joining_node_only_takes_shards_over() {
+        let before = shard_placement(&nodes(4), 3);
+        let after = shard_placement(&nodes(5), 3);
+
+        let mut moved = 0;
+        for (old, new) in before.iter().zip(&after) {
+            if old != new {
+                moved += 1;
+                // the shard moves onto the new node, the others keep their order
+                assert!(new.contains(&"n4".to_owned()));
+                let kept = new.iter().filter(|node| *node != "n4").collect::<Vec<_>>();
+                assert!(kept.iter().all(|node| old.contains(node)));
+            }
+            if old[0] != new[0] {
+                assert_eq!(new[0], "n4");
+            }
+        }
+
+        // a fifth node holds about 3/5 of the shards, none of them moving otherwise
+        assert!(moved > 400 && moved < 800, "{} shards moved", moved);
+        let primaries = before
+            .iter()
+            .zip(&after)
+            .filter(|(old, new)| old[0] != new[0]);
+        assert!(primaries.count() < 300);
+    }
+
+    #[test]
+    fn latest_revision_of_each_document_is_kept() {
+        let docs = latest_revisions([
+            json!({ "id": "a", "_rev": "1-aaa" }),
+            json!({ "id": "b", "_rev": "2-bbb" }),
+            json!({ "id": "a", "_rev": "3-ccc" }),
+            json!({ "id": "b", "_rev": "1-ddd" }),
+        ]);
+
+        assert_eq!(
+            docs,
+            vec![
+                json!({ "id": "a", "_rev": "3-ccc" }),
+                json!({ "id": "b", "_rev": "2-bbb" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn deleted_revision_wins_over_the_revisions_it_descends_from() {
+        let lagging =
+            json!({ "_rev": "2-bbb", "_revisions": { "start": 2, "ids": ["bbb", "aaa"] } });
+        let deleted = json!({
+            "_rev": "3-ccc",
+            "_deleted": true,
+            "_revisions": { "start": 3, "ids": ["ccc", "bbb", "aaa"] }
+        });
+        assert_eq!(latest_revision(&[&lagging, &deleted]), Some(&deleted));
+        assert_eq!(latest_revision(&[&deleted, &lagging]), Some(&deleted));
+
+        // a deleted revision on another branch loses to a live one
+        let branch =
+            json!({ "_rev": "2-zzz", "_revisions": { "start": 2, "ids": ["zzz", "aaa"] } });
+        assert_eq!(latest_revision(&[&deleted, &branch]), Some(&branch));
+        assert_eq!(latest_revision(&[]), None);
+    }
+}
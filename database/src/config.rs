@@ -0,0 +1,747 @@
+/// Copyright (c) Algorealm, Inc.
+use figment::{
+    providers::{Env, Serialized},
+    Figment,
+};
+use ini::Ini;
+use reqwest::Url;
+use rocket::serde::json::{
+    serde_json::{self, json},
+    Value,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::PathBuf,
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::{logging, prelude::*, tasks, util};
+
+/// levels of the messages the database prints, from the fewest to the most
+pub const LOG_LEVELS: [&str; 4] = ["error", "warn", "info", "debug"];
+
+/// keys whose values are hidden when the config is read through the API
+const SECRET_KEYS: [&str; 3] = ["mnemonic", "auth_secret", "secret"];
+
+/// what secrets are replaced with when the config is read through the API
+const REDACTED: &str = "********";
+
+/// Settings only read when the node starts, as sections or `section.key`.
+/// The node keeps the values it started with until it is restarted.
+const RESTART_KEYS: [&str; 11] = [
+    "data",
+    "encryption",
+    "cluster",
+    "nodes",
+    "contract.mnemonic",
+    "discovery.enabled",
+    "discovery.mdns",
+    "discovery.url",
+    "p2p.enabled",
+    "p2p.listen",
+    "tracing",
+];
+
+/// Values of the config file by section and key, as text
+pub type Sections = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Settings of the database, read from the config file and the environment
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub data: DataConfig,
+    pub encryption: EncryptionConfig,
+    pub session: SessionConfig,
+    pub cluster: ClusterConfig,
+    pub discovery: DiscoveryConfig,
+    pub p2p: P2pConfig,
+    pub replication: ReplicationConfig,
+    pub contract: ContractConfig,
+    pub log: LogConfig,
+    pub tracing: TracingConfig,
+    /// administrators of the node, by name, with their Argon2 password hash
+    pub admins: BTreeMap<String, String>,
+    /// peers the databases of applications are replicated with, by name
+    pub peers: BTreeMap<String, String>,
+    /// nodes of the cluster, by name
+    pub nodes: BTreeMap<String, String>,
+    /// DIDs already found on the chain
+    pub identifiers: BTreeMap<String, String>,
+    /// every value read, including those of sections without settings of their own
+    #[serde(skip)]
+    pub sections: Sections,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DataConfig {
+    /// directory holding the databases, ending with `/`
+    pub path: String,
+    pub log: String,
+    /// time between two flushes of the databases to disk, in milliseconds
+    pub flush_interval: u64,
+    /// size of the page cache of each database, in bytes
+    pub cache_capacity: u64,
+    pub version: String,
+    pub keystore: String,
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        DataConfig {
+            path: ".data/".to_owned(),
+            log: "samdb.log".to_owned(),
+            flush_interval: 1000,
+            cache_capacity: 1024 * 1024 * 1024,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            keystore: DEFAULT_KEYSTORE_PATH.to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// lifetime of access tokens, in seconds
+    pub ttl: u64,
+    /// lifetime of refresh tokens, in seconds
+    pub refresh_ttl: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl: SESSION_TOKEN_TTL,
+            refresh_ttl: SESSION_REFRESH_TTL,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// name of this node in `[nodes]`
+    pub node: String,
+    pub shards: u32,
+    /// number of nodes holding each document of a database, unless set when it was created
+    pub n: usize,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            node: String::new(),
+            shards: CLUSTER_SHARDS,
+            n: CLUSTER_REPLICAS,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub enabled: bool,
+    pub mdns: bool,
+    /// URL the other nodes reach this node at
+    pub url: String,
+    /// comma-separated URLs of nodes to gossip with
+    pub seeds: String,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            enabled: false,
+            mdns: true,
+            url: String::new(),
+            seeds: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct P2pConfig {
+    pub enabled: bool,
+    /// comma-separated multiaddresses to listen on
+    pub listen: String,
+    /// comma-separated multiaddresses of the nodes to reach, ending with their peer ID
+    pub peers: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// let replications reach loopback, link-local and private addresses
+    pub allow_private_hosts: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ContractConfig {
+    /// URL of the bridge to the chain
+    pub url: String,
+    /// time between two checks of the DIDs written to the chain, in seconds
+    pub did_check_interval: u64,
+}
+
+impl Default for ContractConfig {
+    fn default() -> Self {
+        ContractConfig {
+            url: DEFAULT_CHAIN_URL.to_owned(),
+            did_check_interval: DID_CLEANUP_SLEEP_TIME,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LogConfig {
+    /// one of `LOG_LEVELS`
+    pub level: String,
+    /// `text`, or `json` for a JSON object per line
+    pub format: String,
+    /// size the log file is rotated at, in bytes, or 0 to never rotate it by size
+    pub max_size: u64,
+    /// rotate the log file every day
+    pub daily: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+            format: "text".to_owned(),
+            max_size: LOG_MAX_SIZE,
+            daily: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TracingConfig {
+    /// export traces to an OpenTelemetry collector
+    pub enabled: bool,
+    /// URL traces are sent to with OTLP over HTTP
+    pub endpoint: String,
+    /// name the node is known by in traces
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            enabled: false,
+            endpoint: DEFAULT_OTLP_ENDPOINT.to_owned(),
+            service_name: "samaritan-db".to_owned(),
+        }
+    }
+}
+
+/// Changes applied by a reload of the config file
+#[derive(Serialize, Debug, Default)]
+pub struct Reload {
+    /// keys whose new value is in use
+    pub applied: Vec<String>,
+    /// keys changed since the node started, whose new value is only used after a restart
+    pub restart_required: Vec<String>,
+}
+
+/// settings in use
+static CURRENT: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+/// settings the node started with
+static BOOT: OnceLock<Arc<Config>> = OnceLock::new();
+
+/// path of the config file, set when it is first loaded
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Find the path of the config file, given with `--config` or in `SAMDB_CONFIG`, and return the
+/// other arguments
+pub fn path_from_args(args: &[String]) -> (PathBuf, Vec<String>) {
+    let mut path = env::var(CONFIG_PATH_ENV)
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| CONFIG_FILE_PATH.to_owned());
+
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--config") {
+            Some("") => {
+                if let Some(value) = args.next() {
+                    path = value.clone();
+                }
+            }
+            Some(value) if value.starts_with('=') => path = value[1..].to_owned(),
+            _ => rest.push(arg.clone()),
+        }
+    }
+
+    (PathBuf::from(path), rest)
+}
+
+/// path of the config file
+pub fn path() -> PathBuf {
+    PATH.get()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(CONFIG_FILE_PATH))
+}
+
+/// Return the settings in use
+pub fn get() -> Arc<Config> {
+    CURRENT
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+/// Load the config file at a path, which is then used by every read and write of the config
+pub fn load(path: PathBuf) -> Result<Arc<Config>, String> {
+    let _ = PATH.set(path);
+    let config = Arc::new(read()?);
+    let _ = BOOT.set(config.clone());
+    *CURRENT.write().unwrap_or_else(|e| e.into_inner()) = Some(config.clone());
+
+    Ok(config)
+}
+
+/// Read the config file again, keeping the settings in use if it is invalid.
+/// Settings only read at startup keep the value the node started with.
+pub fn reload() -> Result<Reload, String> {
+    let Some(boot) = BOOT.get() else {
+        return load(path()).map(|_| Reload::default());
+    };
+
+    let (config, restart_required) = read()?.pin(boot);
+    let config = Arc::new(config);
+    let previous = CURRENT
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .replace(config.clone())
+        .unwrap_or_default();
+
+    let (previous, current) = (previous.flatten(), config.flatten());
+    let mut applied = previous
+        .keys()
+        .chain(current.keys())
+        .filter(|key| previous.get(*key) != current.get(*key))
+        .cloned()
+        .collect::<Vec<_>>();
+    applied.sort();
+    applied.dedup();
+
+    Ok(Reload {
+        applied,
+        restart_required,
+    })
+}
+
+/// value of a key as written in the config file, before the environment overrides it
+pub fn file_value(section: &str, key: &str) -> Option<String> {
+    Ini::load_from_file(path())
+        .ok()?
+        .get_from(Some(section), key)
+        .map(str::to_owned)
+}
+
+/// Hide the secrets of a value: secret keys, administrator passwords and the passwords in the
+/// URLs of peers
+pub fn redact(section: &str, key: &str, value: &str) -> String {
+    if value.is_empty() {
+        return String::new();
+    }
+
+    if SECRET_KEYS.contains(&key) || section == "admins" {
+        return REDACTED.to_owned();
+    }
+
+    match Url::parse(value) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some(REDACTED));
+            url.to_string()
+        }
+        _ => value.to_owned(),
+    }
+}
+
+/// values in use, by section and key, with their secrets hidden
+pub fn redacted_sections() -> Sections {
+    get()
+        .sections
+        .iter()
+        .map(|(section, values)| {
+            let values = values
+                .iter()
+                .map(|(key, value)| (key.clone(), redact(section, key, value)))
+                .collect();
+            (section.clone(), values)
+        })
+        .collect()
+}
+
+/// Reload the config file whenever it changes
+pub async fn watch() {
+    let modified = || {
+        fs::metadata(path())
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+
+    let _task = tasks::start("config_watcher", json!({ "path": path() }));
+    let mut last_modified = modified();
+    let mut reported: Vec<String> = Vec::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs(CONFIG_WATCH_INTERVAL)).await;
+
+        let current = modified();
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current.or(Some(SystemTime::UNIX_EPOCH));
+
+        match reload() {
+            Ok(reload) => {
+                if !reload.applied.is_empty() {
+                    logging::info(format!(
+                        "Applied the changes of {}",
+                        reload.applied.join(", ")
+                    ));
+                }
+                if reload.restart_required != reported {
+                    if !reload.restart_required.is_empty() {
+                        logging::warn(format!(
+                            "Restart the node to apply the changes of {}",
+                            reload.restart_required.join(", ")
+                        ));
+                    }
+                    reported = reload.restart_required;
+                }
+            }
+            Err(e) => logging::error(format!("{}, the previous config is kept.", e)),
+        }
+    }
+}
+
+/// Replace the administrator passwords written in plaintext in the config file with their hash
+fn hash_admin_passwords() {
+    let path = path();
+    let Ok(mut ini) = Ini::load_from_file(&path) else {
+        return;
+    };
+
+    let plaintext = ini
+        .section(Some("admins"))
+        .map(|admins| {
+            admins
+                .iter()
+                .filter(|(_, password)| !password.is_empty() && !password.starts_with("$argon2"))
+                .map(|(name, password)| (name.to_owned(), password.to_owned()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if plaintext.is_empty() {
+        return;
+    }
+
+    for (name, password) in plaintext {
+        if let Some(hash) = util::hash_secret(&password) {
+            ini.set_to(Some("admins"), name, hash);
+        }
+    }
+    if ini.write_to_file(&path).is_err() {
+        println!(
+            "Could not hash the administrator passwords of {}",
+            path.display()
+        );
+    }
+}
+
+/// values of the config file, as text
+fn file_sections() -> Result<Sections, String> {
+    let path = path();
+    let ini = Ini::load_from_file(&path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+
+    Ok(ini
+        .iter()
+        .filter_map(|(section, properties)| {
+            Some((
+                section?.to_owned(),
+                properties
+                    .iter()
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect(),
+            ))
+        })
+        .collect())
+}
+
+/// Values set in `SAMDB_<SECTION>__<KEY>` environment variables, as text like those of the
+/// config file. Other `SAMDB_` variables, like the keystore passphrase, are left out.
+fn env_sections() -> Sections {
+    let mut sections = Sections::new();
+    for (key, value) in Env::prefixed("SAMDB_")
+        .filter(|key| key.as_str().contains("__"))
+        .split("__")
+        .iter()
+    {
+        let key = key.as_str().to_lowercase();
+        if let Some((section, key)) = key.split_once('.') {
+            sections
+                .entry(section.to_owned())
+                .or_default()
+                .insert(key.to_owned(), value);
+        }
+    }
+
+    sections
+}
+
+/// Read the settings, from the config file overridden by the environment
+fn read() -> Result<Config, String> {
+    hash_admin_passwords();
+    parse(file_sections()?)
+}
+
+/// Check the settings the config file would hold with a key set to a value, without writing it.
+/// Returns the keys changed since the node started whose new value is only used after a restart.
+pub fn check(section: &str, key: &str, value: &str) -> Result<Vec<String>, String> {
+    let mut sections = file_sections()?;
+    sections
+        .entry(section.to_owned())
+        .or_default()
+        .insert(key.to_owned(), value.to_owned());
+
+    let config = parse(sections)?;
+    Ok(BOOT
+        .get()
+        .map(|boot| config.pin(boot).1)
+        .unwrap_or_default())
+}
+
+/// Settings from values of the config file, overridden by the environment
+fn parse(mut sections: Sections) -> Result<Config, String> {
+    let overrides = env_sections();
+    for (section, values) in overrides.clone() {
+        sections.entry(section).or_default().extend(values);
+    }
+
+    // errors name the key and where it was set
+    let invalid = |key: String, error: String| {
+        let source = match key.split_once('.') {
+            Some((section, name))
+                if overrides
+                    .get(section)
+                    .is_some_and(|values| values.contains_key(name)) =>
+            {
+                format!("SAMDB_{}__{}", section, name).to_uppercase()
+            }
+            _ => path().display().to_string(),
+        };
+        format!("Invalid value of `{}` in {}: {}", key, source, error)
+    };
+
+    // keys left empty take their default value
+    let set = sections
+        .iter()
+        .map(|(section, values)| {
+            let values = values
+                .iter()
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<BTreeMap<_, _>>();
+            (section.clone(), values)
+        })
+        .collect::<Sections>();
+
+    let mut config: Config = Figment::from(Serialized::defaults(Config::default()))
+        .merge(Serialized::globals(set))
+        .extract_lossy()
+        .map_err(|e: figment::Error| invalid(e.path.join("."), e.kind.to_string()))?;
+
+    config.validate().map_err(|(key, e)| invalid(key, e))?;
+    config.sections = sections;
+
+    Ok(config)
+}
+
+impl Config {
+    /// Every setting as `section.key`, with its value
+    fn flatten(&self) -> BTreeMap<String, Value> {
+        let mut flat = BTreeMap::new();
+        if let Ok(Value::Object(sections)) = serde_json::to_value(self) {
+            for (section, values) in sections {
+                for (key, value) in values.as_object().into_iter().flatten() {
+                    flat.insert(format!("{}.{}", section, key), value.clone());
+                }
+            }
+        }
+
+        // values of the sections without settings of their own, as text
+        for (section, values) in &self.sections {
+            for (key, value) in values {
+                flat.entry(format!("{}.{}", section, key))
+                    .or_insert_with(|| Value::String(value.clone()));
+            }
+        }
+
+        flat
+    }
+
+    /// Keep the values the node started with for the settings only read at startup, returning
+    /// those that changed since
+    fn pin(mut self, boot: &Config) -> (Config, Vec<String>) {
+        let (booted, current) = (boot.flatten(), self.flatten());
+        let mut changed = booted
+            .keys()
+            .chain(current.keys())
+            .filter(|key| {
+                RESTART_KEYS.iter().any(|restart| {
+                    key.as_str() == *restart
+                        || key
+                            .strip_prefix(restart)
+                            .is_some_and(|rest| rest.starts_with('.'))
+                })
+            })
+            .filter(|key| booted.get(*key) != current.get(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        changed.sort();
+        changed.dedup();
+        if changed.is_empty() {
+            return (self, changed);
+        }
+
+        let (Ok(mut pinned), Ok(started)) =
+            (serde_json::to_value(&self), serde_json::to_value(boot))
+        else {
+            return (self, changed);
+        };
+        for key in &changed {
+            let Some((section, name)) = key.split_once('.') else {
+                continue;
+            };
+
+            match started.get(section).and_then(|values| values.get(name)) {
+                Some(value) => pinned[section][name] = value.clone(),
+                None if pinned.get(section).is_some_and(Value::is_object) => {
+                    if let Some(values) = pinned[section].as_object_mut() {
+                        values.remove(name);
+                    }
+                }
+                None => {}
+            }
+            match boot
+                .sections
+                .get(section)
+                .and_then(|values| values.get(name))
+            {
+                Some(value) => {
+                    self.sections
+                        .entry(section.to_owned())
+                        .or_default()
+                        .insert(name.to_owned(), value.clone());
+                }
+                None => {
+                    if let Some(values) = self.sections.get_mut(section) {
+                        values.remove(name);
+                    }
+                }
+            }
+        }
+
+        match serde_json::from_value::<Config>(pinned) {
+            Ok(mut config) => {
+                config.sections = self.sections;
+                (config, changed)
+            }
+            Err(_) => (self, changed),
+        }
+    }
+
+    /// Check the ranges of the settings and the format of URLs, returning the invalid key
+    fn validate(&self) -> Result<(), (String, String)> {
+        let positive = [
+            ("data.flush_interval", self.data.flush_interval),
+            ("data.cache_capacity", self.data.cache_capacity),
+            ("session.ttl", self.session.ttl),
+            ("session.refresh_ttl", self.session.refresh_ttl),
+            ("cluster.shards", self.cluster.shards as u64),
+            ("cluster.n", self.cluster.n as u64),
+            (
+                "contract.did_check_interval",
+                self.contract.did_check_interval,
+            ),
+        ];
+        for (key, value) in positive {
+            if value == 0 {
+                return Err((key.to_owned(), "it must be greater than 0".to_owned()));
+            }
+        }
+
+        if !LOG_LEVELS.contains(&self.log.level.as_str()) {
+            return Err((
+                "log.level".to_owned(),
+                format!("it must be one of {}", LOG_LEVELS.join(", ")),
+            ));
+        }
+
+        if !["text", "json"].contains(&self.log.format.as_str()) {
+            return Err((
+                "log.format".to_owned(),
+                "it must be `text` or `json`".to_owned(),
+            ));
+        }
+
+        if !self.data.path.ends_with('/') {
+            return Err((
+                "data.path".to_owned(),
+                "it must be a directory ending with `/`".to_owned(),
+            ));
+        }
+
+        let urls = self
+            .nodes
+            .iter()
+            .map(|(name, url)| (format!("nodes.{}", name), url.as_str()))
+            .chain(
+                self.discovery
+                    .seeds
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(|url| ("discovery.seeds".to_owned(), url)),
+            )
+            .chain(
+                Some(self.discovery.url.as_str())
+                    .filter(|url| !url.is_empty())
+                    .map(|url| ("discovery.url".to_owned(), url)),
+            )
+            .chain([("contract.url".to_owned(), self.contract.url.as_str())])
+            .chain(
+                Some(self.tracing.endpoint.as_str())
+                    .filter(|_| self.tracing.enabled)
+                    .map(|url| ("tracing.endpoint".to_owned(), url)),
+            );
+        for (key, url) in urls {
+            match Url::parse(url) {
+                Ok(url) if ["http", "https"].contains(&url.scheme()) => {}
+                _ => return Err((key, format!("`{}` is not an HTTP URL", url))),
+            }
+        }
+
+        Ok(())
+    }
+}
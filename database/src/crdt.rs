@@ -0,0 +1,495 @@
+/// Copyright (c) Algorealm, Inc.
+use rocket::serde::json::{serde_json::Map, Value};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{prelude::CRDT_TOMBSTONE_TTL, util};
+
+/// A hybrid logical clock reading, made unique by the node that took it.
+/// It reads `<milliseconds>-<counter>-<node>`, padded so that readings compare in time order.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hlc(String);
+
+impl Hlc {
+    fn new(time: u64, counter: u64) -> Self {
+        Hlc(format!("{:013}-{:06}-{}", time, counter, node_id()))
+    }
+
+    /// physical time and logical counter of the reading
+    fn parts(&self) -> Option<(u64, u64)> {
+        let mut parts = self.0.splitn(3, '-');
+        Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+    }
+}
+
+/// latest physical time and logical counter the clock of this node has read
+static CLOCK: Mutex<(u64, u64)> = Mutex::new((0, 0));
+
+/// identifier of this node in its clock readings
+fn node_id() -> &'static str {
+    static NODE_ID: OnceLock<String> = OnceLock::new();
+    NODE_ID.get_or_init(|| util::generate_uuid().simple().to_string()[..12].to_owned())
+}
+
+/// physical time of this node, in milliseconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// read the clock, which never goes back even if the system time does
+pub fn tick() -> Hlc {
+    let now = now();
+
+    let mut clock = CLOCK.lock().unwrap_or_else(|e| e.into_inner());
+    *clock = if now > clock.0 {
+        (now, 0)
+    } else {
+        (clock.0, clock.1 + 1)
+    };
+
+    Hlc::new(clock.0, clock.1)
+}
+
+/// move the clock past a reading received from another node
+pub fn observe(reading: &Hlc) {
+    if let Some(parts) = reading.parts() {
+        let mut clock = CLOCK.lock().unwrap_or_else(|e| e.into_inner());
+        if parts > *clock {
+            *clock = parts;
+        }
+    }
+}
+
+/// Clocks of a JSON value, mirroring its structure.
+/// The clock of each node is the latest write made within it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Node {
+    /// last-writer-wins register, holding a scalar or a value replaced as a whole
+    Register { clock: Hlc },
+    /// object whose fields are merged one by one
+    Map {
+        clock: Hlc,
+        fields: BTreeMap<String, Node>,
+    },
+    /// observed-remove set holding an array, with the tag of each element in order
+    /// and the tags of the elements removed.
+    /// Removed tags older than `CRDT_TOMBSTONE_TTL` are forgotten, and the elements tagged up to
+    /// `floor` are only kept while they are in `tags`.
+    Set {
+        clock: Hlc,
+        tags: Vec<Hlc>,
+        removed: BTreeSet<Hlc>,
+        #[serde(default)]
+        floor: Hlc,
+    },
+    /// removed field or deleted document
+    Removed { clock: Hlc },
+}
+
+impl Node {
+    pub fn clock(&self) -> &Hlc {
+        match self {
+            Node::Register { clock }
+            | Node::Map { clock, .. }
+            | Node::Set { clock, .. }
+            | Node::Removed { clock } => clock,
+        }
+    }
+
+    pub fn is_removed(&self) -> bool {
+        matches!(self, Node::Removed { .. })
+    }
+}
+
+/// Forget the removed tags older than `CRDT_TOMBSTONE_TTL`, raising the floor of the set to them
+fn compact(removed: &mut BTreeSet<Hlc>, floor: &mut Hlc) {
+    // readings of the same millisecond but of any counter and node are after the horizon
+    let horizon = Hlc(format!("{:013}", now().saturating_sub(CRDT_TOMBSTONE_TTL)));
+    let expired = removed.split_off(&horizon);
+    let expired = std::mem::replace(removed, expired);
+    if let Some(latest) = expired.last() {
+        *floor = floor.clone().max(latest.clone());
+    }
+}
+
+/// Encrypted fields are replaced as a whole, like scalars
+fn is_map(value: &Value) -> bool {
+    value.is_object() && value.get("_encrypted").is_none()
+}
+
+/// Record a local write of a value over its previous value and clocks.
+/// Only the parts of the value that changed get a new clock. Array elements keep the order
+/// they were added in, so that every replica orders them the same way.
+pub fn update(old: Option<(&Value, &Node)>, new: &Value) -> (Value, Node) {
+    match (new, old) {
+        (
+            Value::Object(fields),
+            Some((
+                Value::Object(old_fields),
+                Node::Map {
+                    clock,
+                    fields: old_nodes,
+                },
+            )),
+        ) if is_map(new) => {
+            let mut values = Map::new();
+            let mut nodes = BTreeMap::new();
+            let mut clock = clock.clone();
+            for (key, value) in fields {
+                let old = old_fields.get(key).zip(old_nodes.get(key));
+                let (value, node) = update(old, value);
+                clock = clock.max(node.clock().clone());
+                values.insert(key.clone(), value);
+                nodes.insert(key.clone(), node);
+            }
+
+            // fields missing from the new value are removed
+            for (key, node) in old_nodes {
+                if !fields.contains_key(key) {
+                    let node = match node {
+                        Node::Removed { .. } => node.clone(),
+                        _ => Node::Removed { clock: tick() },
+                    };
+                    clock = clock.max(node.clock().clone());
+                    nodes.insert(key.clone(), node);
+                }
+            }
+
+            (
+                Value::Object(values),
+                Node::Map {
+                    clock,
+                    fields: nodes,
+                },
+            )
+        }
+        (Value::Object(fields), _) if is_map(new) => {
+            let mut values = Map::new();
+            let mut nodes = BTreeMap::new();
+            for (key, value) in fields {
+                let (value, node) = update(None, value);
+                values.insert(key.clone(), value);
+                nodes.insert(key.clone(), node);
+            }
+
+            (
+                Value::Object(values),
+                Node::Map {
+                    clock: tick(),
+                    fields: nodes,
+                },
+            )
+        }
+        (
+            Value::Array(items),
+            Some((
+                Value::Array(old_items),
+                Node::Set {
+                    clock,
+                    tags,
+                    removed,
+                    floor,
+                },
+            )),
+        ) => {
+            // elements still in the array keep their tag, the others are removed
+            let mut remaining = items.iter().map(Some).collect::<Vec<_>>();
+            let mut elements = Vec::new();
+            let mut removed = removed.clone();
+            let mut floor = floor.clone();
+            for (tag, value) in tags.iter().zip(old_items) {
+                match remaining.iter_mut().find(|item| **item == Some(value)) {
+                    Some(item) => {
+                        *item = None;
+                        elements.push((tag.clone(), value.clone()));
+                    }
+                    None => {
+                        removed.insert(tag.clone());
+                    }
+                }
+            }
+
+            let changed = elements.len() < tags.len() || remaining.iter().any(Option::is_some);
+            elements.extend(
+                remaining
+                    .into_iter()
+                    .flatten()
+                    .map(|value| (tick(), value.clone())),
+            );
+            let (tags, values): (Vec<_>, Vec<_>) = elements.into_iter().unzip();
+            compact(&mut removed, &mut floor);
+
+            (
+                Value::Array(values),
+                Node::Set {
+                    clock: if changed { tick() } else { clock.clone() },
+                    tags,
+                    removed,
+                    floor,
+                },
+            )
+        }
+        (Value::Array(items), _) => (
+            new.clone(),
+            Node::Set {
+                tags: items.iter().map(|_| tick()).collect(),
+                clock: tick(),
+                removed: BTreeSet::new(),
+                floor: Hlc::default(),
+            },
+        ),
+        (value, Some((old_value, node @ Node::Register { .. }))) if value == old_value => {
+            (value.clone(), node.clone())
+        }
+        (value, _) => (value.clone(), Node::Register { clock: tick() }),
+    }
+}
+
+/// Merge two replicas of a value given with their clocks. Fields and array elements are merged one
+/// by one, and otherwise the latest write wins, the greater value breaking ties.
+/// The values of removed nodes are ignored.
+pub fn merge(a: (&Value, &Node), b: (&Value, &Node)) -> (Value, Node) {
+    match (a, b) {
+        (
+            (
+                Value::Object(a_values),
+                Node::Map {
+                    clock: a_clock,
+                    fields: a_nodes,
+                },
+            ),
+            (
+                Value::Object(b_values),
+                Node::Map {
+                    clock: b_clock,
+                    fields: b_nodes,
+                },
+            ),
+        ) => {
+            let mut values = Map::new();
+            let mut nodes = BTreeMap::new();
+            for key in a_nodes.keys().chain(b_nodes.keys()) {
+                if nodes.contains_key(key) {
+                    continue;
+                }
+
+                let a = a_nodes
+                    .get(key)
+                    .map(|node| (a_values.get(key).unwrap_or(&Value::Null), node));
+                let b = b_nodes
+                    .get(key)
+                    .map(|node| (b_values.get(key).unwrap_or(&Value::Null), node));
+                let (value, node) = match (a, b) {
+                    (Some(a), Some(b)) => merge(a, b),
+                    (Some((value, node)), None) | (None, Some((value, node))) => {
+                        (value.clone(), node.clone())
+                    }
+                    (None, None) => continue,
+                };
+
+                if !node.is_removed() {
+                    values.insert(key.clone(), value);
+                }
+                nodes.insert(key.clone(), node);
+            }
+
+            (
+                Value::Object(values),
+                Node::Map {
+                    clock: a_clock.max(b_clock).clone(),
+                    fields: nodes,
+                },
+            )
+        }
+        (
+            (
+                Value::Array(a_items),
+                Node::Set {
+                    clock: a_clock,
+                    tags: a_tags,
+                    removed: a_removed,
+                    floor: a_floor,
+                },
+            ),
+            (
+                Value::Array(b_items),
+                Node::Set {
+                    clock: b_clock,
+                    tags: b_tags,
+                    removed: b_removed,
+                    floor: b_floor,
+                },
+            ),
+        ) => {
+            let floor = a_floor.max(b_floor).clone();
+            let mut removed = a_removed.union(b_removed).cloned().collect::<BTreeSet<_>>();
+            // a replica has seen every element tagged up to its floor, and removed those it lacks
+            let kept = |tag: &Hlc, other_tags: &[Hlc], other_floor: &Hlc| {
+                !removed.contains(tag) && (tag > other_floor || other_tags.contains(tag))
+            };
+            let elements = a_tags
+                .iter()
+                .zip(a_items)
+                .filter(|(tag, _)| kept(tag, b_tags, b_floor))
+                .chain(
+                    b_tags
+                        .iter()
+                        .zip(b_items)
+                        .filter(|(tag, _)| kept(tag, a_tags, a_floor)),
+                )
+                .collect::<BTreeMap<_, _>>();
+            removed.retain(|tag| *tag > floor);
+
+            (
+                Value::Array(elements.values().map(|value| (*value).clone()).collect()),
+                Node::Set {
+                    clock: a_clock.max(b_clock).clone(),
+                    tags: elements.into_keys().cloned().collect(),
+                    removed,
+                    floor,
+                },
+            )
+        }
+        ((a_value, a_node), (b_value, b_node)) => {
+            if (b_node.clock(), b_value.to_string()) > (a_node.clock(), a_value.to_string()) {
+                (b_value.clone(), b_node.clone())
+            } else {
+                (a_value.clone(), a_node.clone())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json::serde_json::json;
+
+    /// replicas of a document edited concurrently from a common ancestor
+    fn replicas() -> Vec<(Value, Node)> {
+        let base = update(None, &json!({ "a": 1, "list": [1, 2], "m": { "x": 1 } }));
+        let first = update(
+            Some((&base.0, &base.1)),
+            &json!({ "a": 2, "list": [1, 2, 3], "m": { "x": 1 } }),
+        );
+        let second = update(
+            Some((&base.0, &base.1)),
+            &json!({ "list": [2], "m": { "x": 1, "y": 2 } }),
+        );
+        let third = update(
+            Some((&first.0, &first.1)),
+            &json!({ "a": 3, "list": [3, 1], "m": "replaced" }),
+        );
+
+        vec![base, first, second, third]
+    }
+
+    fn merged(a: &(Value, Node), b: &(Value, Node)) -> (Value, Node) {
+        merge((&a.0, &a.1), (&b.0, &b.1))
+    }
+
+    #[test]
+    fn merge_is_commutative() {
+        let replicas = replicas();
+        for a in &replicas {
+            for b in &replicas {
+                assert_eq!(merged(a, b), merged(b, a));
+            }
+        }
+    }
+
+    #[test]
+    fn merge_is_associative() {
+        let replicas = replicas();
+        for a in &replicas {
+            for b in &replicas {
+                for c in &replicas {
+                    assert_eq!(merged(&merged(a, b), c), merged(a, &merged(b, c)),);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        for a in &replicas() {
+            assert_eq!(merged(a, a), *a);
+            let twice = merged(a, &merged(a, a));
+            assert_eq!(twice, *a);
+        }
+    }
+
+    #[test]
+    fn concurrent_adds_and_removes_are_kept() {
+        let replicas = replicas();
+        let (value, _) = merged(&replicas[1], &replicas[2]);
+
+        // the first replica added 3, the second removed 1 and the field `a`
+        assert_eq!(value["list"], json!([2, 3]));
+        assert!(value.get("a").is_none());
+        assert_eq!(value["m"], json!({ "x": 1, "y": 2 }));
+    }
+
+    #[test]
+    fn remove_only_affects_observed_elements() {
+        let base = update(None, &json!({ "list": [1] }));
+        let removed = update(Some((&base.0, &base.1)), &json!({ "list": [] }));
+        // the element is added again on another replica, with a new tag
+        let added = update(Some((&base.0, &base.1)), &json!({ "list": [1, 1] }));
+
+        let (value, _) = merged(&removed, &added);
+        assert_eq!(value["list"], json!([1]));
+    }
+
+    #[test]
+    fn concurrent_adds_of_equal_values_are_both_kept() {
+        let base = update(None, &json!({ "list": [] }));
+        let first = update(Some((&base.0, &base.1)), &json!({ "list": ["x"] }));
+        let second = update(Some((&base.0, &base.1)), &json!({ "list": ["x"] }));
+
+        let (value, _) = merged(&first, &second);
+        assert_eq!(value["list"], json!(["x", "x"]));
+    }
+
+    #[test]
+    fn expired_removals_are_forgotten() {
+        let old = |millis: u64| Hlc(format!("{:013}-000000-remote", millis));
+        let stale = (
+            json!(["gone", "kept"]),
+            Node::Set {
+                clock: old(2),
+                tags: vec![old(1), old(2)],
+                removed: BTreeSet::new(),
+                floor: Hlc::default(),
+            },
+        );
+        let current = (
+            json!(["kept"]),
+            Node::Set {
+                clock: old(3),
+                tags: vec![old(2)],
+                removed: BTreeSet::from([old(1)]),
+                floor: Hlc::default(),
+            },
+        );
+
+        let (value, node) = update(Some((&current.0, &current.1)), &json!(["kept", "new"]));
+        let Node::Set { removed, floor, .. } = &node else {
+            panic!("arrays are sets");
+        };
+        assert!(removed.is_empty());
+        assert_eq!(*floor, old(1));
+
+        // a replica that still holds the removed element does not bring it back
+        let (merged, _) = merged(&(value, node), &stale);
+        assert_eq!(merged, json!(["kept", "new"]));
+    }
+}
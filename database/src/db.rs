@@ -1,71 +1,176 @@
 /// Copyright (c) Algorealm, Inc.
-use std::{collections::VecDeque, fs};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs,
+};
 
-use crate::{prelude::*, util};
+use crate::{crdt, discovery, encryption, journal, logging, metrics, prelude::*, util};
 use rocket::serde::json::{
-    serde_json::{from_str, json},
+    serde_json::{self, from_slice, from_str, json, Map},
     Value,
 };
 
 use async_std::sync::Mutex;
-use std::sync::Arc;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree,
+};
+use std::sync::{Arc, Mutex as StdMutex};
+use tracing::{info_span, instrument, Instrument};
 
-/// check if a database exists
-pub fn database_exists(db_name: &str) -> bool {
-    let data_path = util::read_config("data", "path");
-    let db_path = format!("{}{}", data_path, db_name);
+/// handle to the root database.
+/// It is kept open because sled locks its files and every authenticated request reads it.
+static ROOT_DB: StdMutex<Option<sled::Db>> = StdMutex::new(None);
 
-    // since databases are capsulated in directories
-    util::is_directory_within_parent(&db_path, &data_path)
+/// open the root database, which holds the metadata of all databases and applications
+fn open_root_db(config: &DbConfig) -> DatabaseResult<sled::Db> {
+    let mut root_db = ROOT_DB.lock().map_err(|_| DatabaseError::OtherError)?;
+    if let Some(db) = root_db.as_ref() {
+        return Ok(db.clone());
+    }
+
+    let cfg = sled::Config::default()
+        .path(format!("{}.dbs", config.path))
+        .cache_capacity(config.cache_capacity)
+        .flush_every_ms(Some(config.flush_interval));
+
+    let db = cfg.open()?;
+    *root_db = Some(db.clone());
+
+    Ok(db)
 }
 
-/// create a database
-pub fn create_database(config: &DbConfig, name: &str) -> Result<(), DatabaseError> {
+/// handles to the databases opened so far, by path.
+/// They are kept open so that requests and replications can use a database concurrently.
+static DATABASES: StdMutex<BTreeMap<String, sled::Db>> = StdMutex::new(BTreeMap::new());
+
+/// open a database, reusing its handle if it is already open
+#[instrument(skip_all, fields(db_name = name))]
+pub fn open_database(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+) -> DatabaseResult<sled::Db> {
+    let path = database_path(config, application_did, name);
+    let mut databases = DATABASES.lock().map_err(|_| DatabaseError::OtherError)?;
+    metrics::database_cache(databases.contains_key(&path));
+    if let Some(db) = databases.get(&path) {
+        return Ok(db.clone());
+    }
+
     let cfg = sled::Config::default()
-        .path(format!("{}{}", config.path, name))
+        .path(&path)
         .cache_capacity(config.cache_capacity)
         .flush_every_ms(Some(config.flush_interval));
 
+    let db = cfg.open()?;
+    backfill_changes(&db)?;
+    databases.insert(path, db.clone());
+
+    Ok(db)
+}
+
+/// number of databases open, including the root database
+pub fn open_database_count() -> usize {
+    let root = ROOT_DB
+        .lock()
+        .map(|root_db| root_db.iter().count())
+        .unwrap_or_default();
+    root + DATABASES
+        .lock()
+        .map(|databases| databases.len())
+        .unwrap_or_default()
+}
+
+/// size on disk of the root database and of the databases opened so far, by path
+pub fn disk_usage(config: &DbConfig) -> DatabaseResult<Vec<(String, u64)>> {
+    let mut usage = vec![(
+        format!("{}.dbs", config.path),
+        open_root_db(config)?.size_on_disk()?,
+    )];
+    let databases = DATABASES.lock().map_err(|_| DatabaseError::OtherError)?;
+    for (path, db) in databases.iter() {
+        usage.push((path.clone(), db.size_on_disk()?));
+    }
+
+    Ok(usage)
+}
+
+/// path of a database within the namespace of the application that owns it
+fn database_path(config: &DbConfig, application_did: &Did, name: &str) -> String {
+    format!("{}{}/{}", config.path, application_did.address(), name)
+}
+
+/// key of a database in the root database
+fn database_key(application_did: &Did, name: &str) -> String {
+    format!("{}/{}", application_did.address(), name)
+}
+
+/// check if a database exists
+#[instrument(skip_all, fields(db_name = db_name))]
+pub fn database_exists(config: &DbConfig, application_did: &Did, db_name: &str) -> bool {
+    let db_path = database_path(config, application_did, db_name);
+
+    // since databases are capsulated in directories
+    util::is_directory_within_parent(&db_path, &config.path)
+}
+
+/// create a database
+#[instrument(skip_all, fields(db_name = name))]
+pub fn create_database(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+) -> Result<(), DatabaseError> {
     // create database
-    let _ = cfg.open()?;
+    open_database(config, application_did, name)?;
 
     // record metadata in the .dbs database
-    let cfg = cfg.path(format!("{}.dbs", config.path));
-    let root_db = cfg.open()?;
+    let root_db = open_root_db(config)?;
 
     let db_meta = json!({
         "id": util::generate_uuid().to_string(),
-        "application_did": util::read_config("auth", "application_did"),
+        "application_did": application_did.0,
         "created_at": util::get_unix_epoch_time(),
     })
     .to_string();
 
     // insert into root db
-    root_db.insert(name.as_bytes(), db_meta.as_bytes())?;
+    root_db.insert(
+        database_key(application_did, name).as_bytes(),
+        db_meta.as_bytes(),
+    )?;
 
     Ok(())
 }
 
 /// delete a database
 /// This majorly entails removing the db directory and clearing its metadata
-pub fn delete_database(config: &DbConfig, name: &str) -> Result<(), DatabaseError> {
+#[instrument(skip_all, fields(db_name = name))]
+pub fn delete_database(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+) -> Result<(), DatabaseError> {
     // get metadata entry
-    let cfg = sled::Config::default()
-        .path(format!("{}.dbs", config.path))
-        .cache_capacity(config.cache_capacity)
-        .flush_every_ms(Some(config.flush_interval));
-
-    let root_db = cfg.open()?;
+    let root_db = open_root_db(config)?;
 
     // remove metadata
-    match root_db.remove(name.as_bytes())? {
+    match root_db.remove(database_key(application_did, name).as_bytes())? {
         Some(_) => {
-            // finally, remove directory
-            let data_path = util::read_config("data", "path");
-            let db_path = format!("{}{}", data_path, name);
+            // close the database before its files are removed
+            let path = database_path(config, application_did, name);
+            DATABASES
+                .lock()
+                .map_err(|_| DatabaseError::OtherError)?
+                .remove(&path);
 
+            // finally, remove directory
             // since databases are capsulated in directories
-            fs::remove_dir_all(db_path)?;
+            fs::remove_dir_all(path)?;
         }
         None => {
             return Err(DatabaseError::OtherError);
@@ -75,25 +180,1313 @@ pub fn delete_database(config: &DbConfig, name: &str) -> Result<(), DatabaseErro
     Ok(())
 }
 
-/// Get a list of all the databases
-pub fn all_dbs(config: &DbConfig) -> DatabaseResult<Vec<String>> {
+/// Get a list of all the databases owned by an application
+#[instrument(skip_all)]
+pub fn all_dbs(config: &DbConfig, application_did: &Did) -> DatabaseResult<Vec<String>> {
     // get metadata entry
-    let cfg = sled::Config::default()
-        .path(format!("{}.dbs", config.path))
-        .cache_capacity(config.cache_capacity)
-        .flush_every_ms(Some(config.flush_interval));
-
-    let root_db = cfg.open()?;
+    let root_db = open_root_db(config)?;
+    let prefix = database_key(application_did, "");
 
     Ok(root_db
-        .iter()
+        .scan_prefix(prefix.as_bytes())
         .filter_map(Result::ok)
-        .map(|(key, _)| String::from_utf8_lossy(&key).to_string())
+        .map(|(key, _)| String::from_utf8_lossy(&key[prefix.len()..]).to_string())
         .collect::<Vec<String>>())
 }
 
+/// Fetch the registry entry of an application, if it has been initialized
+#[instrument(skip_all)]
+pub fn fetch_application(
+    config: &DbConfig,
+    application_did: &Did,
+) -> DatabaseResult<Option<Value>> {
+    let apps = open_root_db(config)?.open_tree("apps")?;
+
+    match apps.get(application_did.0.as_bytes())? {
+        Some(entry) => Ok(Some(from_slice::<Value>(&entry)?)),
+        None => Ok(None),
+    }
+}
+
+/// Record an application and its credentials in the registry.
+/// The auth secret used for Basic authentication is only stored as an Argon2 hash.
+#[instrument(skip_all)]
+pub fn register_application(
+    config: &DbConfig,
+    application_did: &Did,
+    secret_hash: &str,
+    auth_secret: &str,
+) -> DatabaseResult<()> {
+    let apps = open_root_db(config)?.open_tree("apps")?;
+
+    let entry = json!({
+        "secret": secret_hash,
+        "auth_hash": util::hash_secret(auth_secret).ok_or(DatabaseError::OtherError)?,
+        "created_at": util::get_unix_epoch_time(),
+    })
+    .to_string();
+
+    apps.insert(application_did.0.as_bytes(), entry.as_bytes())?;
+
+    Ok(())
+}
+
+/// Apply a change to the registry entry of an application
+fn update_application(
+    config: &DbConfig,
+    application_did: &Did,
+    update: impl FnOnce(&mut Value),
+) -> DatabaseResult<()> {
+    let apps = open_root_db(config)?.open_tree("apps")?;
+
+    let mut entry =
+        fetch_application(config, application_did)?.ok_or(DatabaseError::MissingDocument)?;
+    update(&mut entry);
+
+    apps.insert(application_did.0.as_bytes(), entry.to_string().as_bytes())?;
+
+    Ok(())
+}
+
+/// Replace the hash of the secret an application was initialized with
+#[instrument(skip_all)]
+pub fn update_application_secret(
+    config: &DbConfig,
+    application_did: &Did,
+    secret_hash: &str,
+) -> DatabaseResult<()> {
+    update_application(config, application_did, |entry| {
+        entry["secret"] = secret_hash.into();
+    })
+}
+
+/// Replace the auth secret of an application.
+/// The previous secret remains valid for `grace` seconds, or is invalidated at once if it is zero.
+#[instrument(skip_all)]
+pub fn rotate_auth_secret(
+    config: &DbConfig,
+    application_did: &Did,
+    auth_secret: &str,
+    grace: u64,
+) -> DatabaseResult<()> {
+    let auth_hash = util::hash_secret(auth_secret).ok_or(DatabaseError::OtherError)?;
+    forget_verified_secrets(application_did);
+
+    update_application(config, application_did, |entry| {
+        let now = util::get_unix_epoch_time();
+        let previous_hash = entry["auth_hash"].take();
+        if let Some(fields) = entry.as_object_mut() {
+            fields.remove("previous_auth_hash");
+            fields.remove("previous_expires_at");
+        }
+
+        if grace > 0 {
+            entry["previous_auth_hash"] = previous_hash;
+            entry["previous_expires_at"] = (now + grace).into();
+        }
+
+        entry["auth_hash"] = auth_hash.into();
+        entry["rotated_at"] = now.into();
+    })
+}
+
+/// auth secrets checked recently, by their HMAC, with the application they belong to and when
+/// the check expires. Argon2 is too slow to run on every request.
+static VERIFIED_SECRETS: StdMutex<BTreeMap<Vec<u8>, (Did, u64)>> = StdMutex::new(BTreeMap::new());
+
+/// HMAC of an auth secret of an application, so that the secrets checked are not kept in memory
+fn secret_digest(
+    config: &DbConfig,
+    application_did: &Did,
+    auth_secret: &str,
+) -> DatabaseResult<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&session_signing_key(config)?)
+        .map_err(|_| DatabaseError::OtherError)?;
+    mac.update(application_did.0.as_bytes());
+    mac.update(b"\0");
+    mac.update(auth_secret.as_bytes());
+
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// forget the auth secrets of an application checked so far, once they may have changed
+fn forget_verified_secrets(application_did: &Did) {
+    if let Ok(mut verified) = VERIFIED_SECRETS.lock() {
+        verified.retain(|_, (did, _)| did != application_did);
+    }
+}
+
+/// Check an auth secret against the current secret of an application,
+/// and against the previous one while its grace period lasts.
+/// Successful checks are remembered for `AUTH_CACHE_TTL` seconds.
+#[instrument(skip_all)]
+pub fn verify_auth_secret(
+    config: &DbConfig,
+    application_did: &Did,
+    auth_secret: &str,
+) -> DatabaseResult<bool> {
+    let digest = secret_digest(config, application_did, auth_secret)?;
+    let now = util::get_unix_epoch_time();
+    let mut verified = VERIFIED_SECRETS
+        .lock()
+        .map_err(|_| DatabaseError::OtherError)?;
+    if verified
+        .get(&digest)
+        .is_some_and(|(_, expires_at)| *expires_at > now)
+    {
+        return Ok(true);
+    }
+    drop(verified);
+
+    if !check_auth_secret(config, application_did, auth_secret)? {
+        return Ok(false);
+    }
+
+    verified = VERIFIED_SECRETS
+        .lock()
+        .map_err(|_| DatabaseError::OtherError)?;
+    verified.retain(|_, (_, expires_at)| *expires_at > now);
+    verified.insert(digest, (application_did.clone(), now + AUTH_CACHE_TTL));
+
+    Ok(true)
+}
+
+/// Check an auth secret against the hashes of the secrets of an application
+fn check_auth_secret(
+    config: &DbConfig,
+    application_did: &Did,
+    auth_secret: &str,
+) -> DatabaseResult<bool> {
+    let app = match fetch_application(config, application_did)? {
+        Some(app) => app,
+        None => return Ok(false),
+    };
+
+    if util::verify_secret(auth_secret, app["auth_hash"].as_str().unwrap_or_default()) {
+        return Ok(true);
+    }
+
+    let in_grace = app["previous_expires_at"]
+        .as_u64()
+        .is_some_and(|expires_at| expires_at > util::get_unix_epoch_time());
+
+    Ok(in_grace
+        && util::verify_secret(
+            auth_secret,
+            app["previous_auth_hash"].as_str().unwrap_or_default(),
+        ))
+}
+
+/// Check the credential a node found through discovery replicates the databases of an
+/// application with, derived from the key the application shares with its nodes
+#[instrument(skip_all)]
+pub fn verify_peer_credential(
+    config: &DbConfig,
+    application_did: &Did,
+    credential: &str,
+) -> DatabaseResult<bool> {
+    let app = match fetch_application(config, application_did)? {
+        Some(app) => app,
+        None => return Ok(false),
+    };
+
+    match app["discovery_key"].as_str() {
+        Some(key) => Ok(discovery::verify_peer_credential(
+            key,
+            &node_id(config)?,
+            credential,
+        )),
+        None => Ok(false),
+    }
+}
+
+/// Set or remove the key shared by the nodes of an application that sync with the nodes they discover
+#[instrument(skip_all)]
+pub fn set_discovery_key(
+    config: &DbConfig,
+    application_did: &Did,
+    key: Option<&str>,
+) -> DatabaseResult<()> {
+    update_application(config, application_did, |entry| match key {
+        Some(key) => entry["discovery_key"] = key.into(),
+        None => {
+            if let Some(fields) = entry.as_object_mut() {
+                fields.remove("discovery_key");
+            }
+        }
+    })
+}
+
+/// Return the applications that sync with the nodes they discover, with their shared key
+#[instrument(skip_all)]
+pub fn discovery_keys(config: &DbConfig) -> DatabaseResult<Vec<(Did, String)>> {
+    let apps = open_root_db(config)?.open_tree("apps")?;
+
+    Ok(apps
+        .iter()
+        .filter_map(Result::ok)
+        .filter_map(|(did, entry)| {
+            let entry = from_slice::<Value>(&entry).ok()?;
+            Some((
+                Did(String::from_utf8(did.to_vec()).ok()?),
+                entry["discovery_key"].as_str()?.to_owned(),
+            ))
+        })
+        .collect())
+}
+
+/// Store the Basic credentials of the application on a peer, as `<username>:<password>`.
+/// They are sealed with the master key when encryption at rest is enabled.
+#[instrument(skip_all)]
+pub fn store_peer_credentials(
+    config: &DbConfig,
+    name: &str,
+    credentials: &str,
+) -> DatabaseResult<()> {
+    let peers = open_root_db(config)?.open_tree("peer_credentials")?;
+
+    let entry = match &config.master_key {
+        Some(master_key) => json!({
+            "sealed": STANDARD.encode(encryption::encrypt(master_key, credentials.as_bytes())?)
+        }),
+        None => json!({ "credentials": credentials }),
+    };
+    peers.insert(name, serde_json::to_vec(&entry)?)?;
+
+    Ok(())
+}
+
+/// Return the Basic credentials of the application on a peer, if they are stored
+#[instrument(skip_all)]
+pub fn peer_credentials(config: &DbConfig, name: &str) -> DatabaseResult<Option<String>> {
+    let peers = open_root_db(config)?.open_tree("peer_credentials")?;
+    let Some(entry) = peers.get(name)? else {
+        return Ok(None);
+    };
+
+    let entry = from_slice::<Value>(&entry)?;
+    if let Some(credentials) = entry["credentials"].as_str() {
+        return Ok(Some(credentials.to_owned()));
+    }
+
+    let master_key = config
+        .master_key
+        .as_ref()
+        .ok_or(DatabaseError::OtherError)?;
+    let sealed = STANDARD
+        .decode(entry["sealed"].as_str().unwrap_or_default())
+        .map_err(|_| DatabaseError::OtherError)?;
+    let credentials = encryption::decrypt(master_key, &sealed)?;
+
+    Ok(String::from_utf8(credentials).ok())
+}
+
+/// Forget the credentials of the application on a peer
+#[instrument(skip_all)]
+pub fn remove_peer_credentials(config: &DbConfig, name: &str) -> DatabaseResult<()> {
+    open_root_db(config)?
+        .open_tree("peer_credentials")?
+        .remove(name)?;
+
+    Ok(())
+}
+
+/// Return the ID this node is known by to the nodes that discover it, generated on first use
+#[instrument(skip_all)]
+pub fn node_id(config: &DbConfig) -> DatabaseResult<String> {
+    let node = open_root_db(config)?.open_tree("node")?;
+
+    if let Some(id) = node.get("id")? {
+        return Ok(String::from_utf8(id.to_vec()).unwrap_or_default());
+    }
+
+    let id = util::generate_uuid().simple().to_string()[..16].to_owned();
+    // another request may have generated the ID in the meantime
+    match node.compare_and_swap("id", None as Option<&[u8]>, Some(id.as_bytes()))? {
+        Ok(_) => Ok(id),
+        Err(existing) => Ok(existing
+            .current
+            .map(|id| String::from_utf8(id.to_vec()).unwrap_or_default())
+            .unwrap_or_default()),
+    }
+}
+
+/// Return the number of applications initialized in the database
+#[instrument(skip_all)]
+pub fn application_count(config: &DbConfig) -> DatabaseResult<usize> {
+    Ok(open_root_db(config)?.open_tree("apps")?.len())
+}
+
+/// Store a new API key, keeping only the hash of its secret
+#[instrument(skip_all)]
+pub fn create_api_key(config: &DbConfig, api_key: &ApiKey, secret: &str) -> DatabaseResult<()> {
+    let keys = open_root_db(config)?.open_tree("api_keys")?;
+
+    let entry = json!({
+        "key": api_key,
+        "hash": util::hash_api_secret(secret),
+    })
+    .to_string();
+
+    keys.insert(api_key.id.as_bytes(), entry.as_bytes())?;
+
+    Ok(())
+}
+
+/// Fetch an API key and the hash of its secret
+#[instrument(skip_all)]
+pub fn fetch_api_key(config: &DbConfig, id: &str) -> DatabaseResult<Option<(ApiKey, String)>> {
+    let keys = open_root_db(config)?.open_tree("api_keys")?;
+
+    match keys.get(id.as_bytes())? {
+        Some(entry) => {
+            let mut entry = from_slice::<Value>(&entry)?;
+            let hash = entry["hash"].as_str().unwrap_or_default().to_owned();
+            Ok(Some((serde_json::from_value(entry["key"].take())?, hash)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// List the API keys issued by an application
+#[instrument(skip_all)]
+pub fn list_api_keys(config: &DbConfig, application_did: &Did) -> DatabaseResult<Vec<ApiKey>> {
+    let keys = open_root_db(config)?.open_tree("api_keys")?;
+
+    let mut api_keys = Vec::new();
+    for (_, entry) in keys.iter().filter_map(Result::ok) {
+        let api_key: ApiKey = serde_json::from_value(from_slice::<Value>(&entry)?["key"].take())?;
+        if api_key.application_did == application_did.0 {
+            api_keys.push(api_key);
+        }
+    }
+
+    Ok(api_keys)
+}
+
+/// Revoke an API key issued by an application
+#[instrument(skip_all)]
+pub fn revoke_api_key(config: &DbConfig, application_did: &Did, id: &str) -> DatabaseResult<()> {
+    match fetch_api_key(config, id)? {
+        Some((api_key, _)) if api_key.application_did == application_did.0 => {
+            open_root_db(config)?
+                .open_tree("api_keys")?
+                .remove(id.as_bytes())?;
+            Ok(())
+        }
+        _ => Err(DatabaseError::MissingDocument),
+    }
+}
+
+/// Return the key session tokens are signed with, generating it on first use
+#[instrument(skip_all)]
+pub fn session_signing_key(config: &DbConfig) -> DatabaseResult<Vec<u8>> {
+    let sessions = open_root_db(config)?.open_tree("sessions")?;
+
+    if let Some(key) = sessions.get("signing_key")? {
+        return Ok(key.to_vec());
+    }
+
+    let key = rand::random::<[u8; 32]>();
+    // another request may have generated the key in the meantime
+    match sessions.compare_and_swap("signing_key", None as Option<&[u8]>, Some(&key[..]))? {
+        Ok(_) => Ok(key.to_vec()),
+        Err(existing) => Ok(existing.current.map(|key| key.to_vec()).unwrap_or_default()),
+    }
+}
+
+/// Add a session token to the revocation list.
+/// Entries are dropped once the tokens they refer to have expired.
+#[instrument(skip_all)]
+pub fn revoke_session(config: &DbConfig, jti: &str, expires_at: u64) -> DatabaseResult<()> {
+    let revoked = open_root_db(config)?.open_tree("revoked_sessions")?;
+
+    let now = util::get_unix_epoch_time();
+    for (key, exp) in revoked.iter().filter_map(Result::ok) {
+        if from_slice::<u64>(&exp).unwrap_or_default() <= now {
+            revoked.remove(key)?;
+        }
+    }
+
+    revoked.insert(jti.as_bytes(), expires_at.to_string().as_bytes())?;
+
+    Ok(())
+}
+
+/// Check whether a session token has been revoked
+#[instrument(skip_all)]
+pub fn is_session_revoked(config: &DbConfig, jti: &str) -> DatabaseResult<bool> {
+    Ok(open_root_db(config)?
+        .open_tree("revoked_sessions")?
+        .contains_key(jti.as_bytes())?)
+}
+
+/// key of an audit entry in the root database: the DID of the user, then the sequence number of
+/// the entry, so that the entries of a user are listed in the order they were recorded
+fn audit_key(did: &str, seq: u64) -> Vec<u8> {
+    [did.as_bytes(), b"\0", &seq.to_be_bytes()].concat()
+}
+
+/// Record an access by an application to a document of a user.
+/// The user is the owner of the document, read from its metadata when it is not given.
+/// Entries are only ever added.
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn audit(
+    config: &DbConfig,
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    operation: &str,
+    owner: Option<&Did>,
+) -> DatabaseResult<()> {
+    let owner = match owner {
+        Some(owner) => owner.0.clone(),
+        None => document_meta(&open_database(config, application_did, db_name)?, doc_id)?["_did"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+    };
+    if owner.is_empty() {
+        return Ok(());
+    }
+
+    let root_db = open_root_db(config)?;
+    let seq = root_db.generate_id()?;
+    let entry = json!({
+        "seq": seq,
+        "at": util::get_unix_epoch_time(),
+        "operation": operation,
+        "application_did": application_did.0,
+        "did": owner,
+        "db_name": db_name,
+        "doc_id": doc_id,
+    });
+    root_db
+        .open_tree("audit")?
+        .insert(audit_key(&owner, seq), serde_json::to_vec(&entry)?)?;
+
+    Ok(())
+}
+
+/// List the accesses to the documents of a user, oldest first, by an application or by all of them.
+/// Only the entries recorded after the sequence number `since` are listed if it is given.
+#[instrument(skip_all)]
+pub fn audit_entries(
+    config: &DbConfig,
+    did: &str,
+    application_did: Option<&Did>,
+    since: Option<u64>,
+    limit: Option<usize>,
+) -> DatabaseResult<Vec<Value>> {
+    let audit = open_root_db(config)?.open_tree("audit")?;
+    let start = audit_key(did, since.map_or(0, |since| since.saturating_add(1)));
+    let end = audit_key(did, u64::MAX);
+
+    let mut entries = Vec::new();
+    for (_, entry) in audit.range(start..=end).filter_map(Result::ok) {
+        if limit.is_some_and(|limit| entries.len() >= limit) {
+            break;
+        }
+        let entry = from_slice::<Value>(&entry)?;
+        if application_did
+            .is_none_or(|application_did| entry["application_did"] == application_did.0.as_str())
+        {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// key of a data key in the root database
+fn data_key_id(application_did: &Did, subject: &str) -> String {
+    format!("{}/{}", application_did.address(), subject)
+}
+
+/// Fetch the wrapped data key of a subject (a DID or a database) within an application
+#[instrument(skip_all)]
+pub fn fetch_data_key(
+    config: &DbConfig,
+    application_did: &Did,
+    subject: &str,
+) -> DatabaseResult<Option<Vec<u8>>> {
+    let keys = open_root_db(config)?.open_tree("data_keys")?;
+
+    Ok(keys
+        .get(data_key_id(application_did, subject).as_bytes())?
+        .map(|key| key.to_vec()))
+}
+
+/// Store the wrapped data key of a subject, unless another write stored one first.
+/// The key that ends up stored is returned.
+#[instrument(skip_all)]
+pub fn store_data_key(
+    config: &DbConfig,
+    application_did: &Did,
+    subject: &str,
+    wrapped: &[u8],
+) -> DatabaseResult<Vec<u8>> {
+    let keys = open_root_db(config)?.open_tree("data_keys")?;
+
+    match keys.compare_and_swap(
+        data_key_id(application_did, subject).as_bytes(),
+        None as Option<&[u8]>,
+        Some(wrapped),
+    )? {
+        Ok(_) => Ok(wrapped.to_vec()),
+        Err(existing) => Ok(existing.current.map(|key| key.to_vec()).unwrap_or_default()),
+    }
+}
+
+/// Destroy the data key of a subject, which makes the data encrypted with it unrecoverable
+#[instrument(skip_all)]
+pub fn remove_data_key(
+    config: &DbConfig,
+    application_did: &Did,
+    subject: &str,
+) -> DatabaseResult<bool> {
+    let keys = open_root_db(config)?.open_tree("data_keys")?;
+    let removed = keys.remove(data_key_id(application_did, subject).as_bytes())?;
+    keys.flush()?;
+
+    Ok(removed.is_some())
+}
+
+/// Fetch the field encryption policy of a database, if it has one
+#[instrument(skip_all, fields(db_name = name))]
+pub fn encryption_policy(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+) -> DatabaseResult<Option<EncryptionPolicy>> {
+    let root_db = open_root_db(config)?;
+
+    match root_db.get(database_key(application_did, name).as_bytes())? {
+        Some(meta) => {
+            let mut meta = from_slice::<Value>(&meta)?;
+            match meta["encryption"].take() {
+                Value::Null => Ok(None),
+                policy => Ok(Some(serde_json::from_value(policy)?)),
+            }
+        }
+        None => Err(DatabaseError::OtherError),
+    }
+}
+
+/// Record the field encryption policy of a database
+#[instrument(skip_all, fields(db_name = name))]
+pub fn set_encryption_policy(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+    policy: &EncryptionPolicy,
+) -> DatabaseResult<()> {
+    let root_db = open_root_db(config)?;
+    let key = database_key(application_did, name);
+
+    let mut meta = from_slice::<Value>(
+        &root_db
+            .get(key.as_bytes())?
+            .ok_or(DatabaseError::OtherError)?,
+    )?;
+    meta["encryption"] = serde_json::to_value(policy)?;
+
+    root_db.insert(key.as_bytes(), meta.to_string().as_bytes())?;
+
+    Ok(())
+}
+
+/// return how concurrent writes to the documents of a database are reconciled
+#[instrument(skip_all, fields(db_name = name))]
+pub fn database_mode(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+) -> DatabaseResult<DatabaseMode> {
+    let root_db = open_root_db(config)?;
+
+    match root_db.get(database_key(application_did, name).as_bytes())? {
+        Some(meta) => {
+            let mut meta = from_slice::<Value>(&meta)?;
+            match meta["mode"].take() {
+                Value::Null => Ok(DatabaseMode::default()),
+                mode => Ok(serde_json::from_value(mode)?),
+            }
+        }
+        None => Err(DatabaseError::OtherError),
+    }
+}
+
+/// Record how concurrent writes to the documents of a database are reconciled
+#[instrument(skip_all, fields(db_name = name))]
+pub fn set_database_mode(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+    mode: DatabaseMode,
+) -> DatabaseResult<()> {
+    let root_db = open_root_db(config)?;
+    let key = database_key(application_did, name);
+
+    let mut meta = from_slice::<Value>(
+        &root_db
+            .get(key.as_bytes())?
+            .ok_or(DatabaseError::OtherError)?,
+    )?;
+    meta["mode"] = serde_json::to_value(mode)?;
+
+    root_db.insert(key.as_bytes(), meta.to_string().as_bytes())?;
+
+    Ok(())
+}
+
+/// return the number of cluster nodes holding each document of a database, if it was set
+#[instrument(skip_all, fields(db_name = name))]
+pub fn database_replicas(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+) -> DatabaseResult<Option<usize>> {
+    let root_db = open_root_db(config)?;
+
+    match root_db.get(database_key(application_did, name).as_bytes())? {
+        Some(meta) => Ok(from_slice::<Value>(&meta)?["n"]
+            .as_u64()
+            .map(|n| n as usize)),
+        None => Err(DatabaseError::OtherError),
+    }
+}
+
+/// Record the number of cluster nodes holding each document of a database
+#[instrument(skip_all, fields(db_name = name))]
+pub fn set_database_replicas(
+    config: &DbConfig,
+    application_did: &Did,
+    name: &str,
+    n: usize,
+) -> DatabaseResult<()> {
+    let root_db = open_root_db(config)?;
+    let key = database_key(application_did, name);
+
+    let mut meta = from_slice::<Value>(
+        &root_db
+            .get(key.as_bytes())?
+            .ok_or(DatabaseError::OtherError)?,
+    )?;
+    meta["n"] = n.into();
+
+    root_db.insert(key.as_bytes(), meta.to_string().as_bytes())?;
+
+    Ok(())
+}
+
+/// Move the state of a database that served a single application into the application namespaces.
+/// The application recorded in the config file is imported into the registry, and its credentials
+/// removed from the file. Databases created before namespacing are moved into the namespace of the
+/// application that created them.
+#[instrument(skip_all)]
+pub fn migrate_single_application(config: &DbConfig) -> DatabaseResult<()> {
+    let application_did = Did(util::read_config("auth", "application_did").to_string());
+    if DbConfig::is_valid_did(&application_did.0, false) {
+        if fetch_application(config, &application_did)?.is_none() {
+            register_application(
+                config,
+                &application_did,
+                &util::read_config("auth", "secret"),
+                &util::read_config("auth", "auth_secret"),
+            )?;
+        }
+
+        if !util::remove_config_section("auth") {
+            return Err(DatabaseError::IoError);
+        }
+    }
+
+    let root_db = open_root_db(config)?;
+    for (key, meta) in root_db.iter().filter_map(Result::ok) {
+        let name = String::from_utf8_lossy(&key).to_string();
+        // namespaced databases are keyed by `<address>/<name>`
+        if name.contains('/') {
+            continue;
+        }
+
+        let owner = Did(from_slice::<Value>(&meta)?["application_did"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned());
+        if !DbConfig::is_valid_did(&owner.0, false) {
+            continue;
+        }
+
+        // a database whose directory is missing keeps its metadata, and is opened empty
+        let old_path = format!("{}{}", config.path, name);
+        if fs::metadata(&old_path).is_ok() {
+            fs::create_dir_all(format!("{}{}", config.path, owner.address()))?;
+            fs::rename(old_path, database_path(config, &owner, &name))?;
+        } else {
+            logging::log(
+                logging::Level::Warn,
+                "Database has no directory to move into its application namespace",
+                json!({ "db_name": name, "path": old_path }),
+            );
+        }
+
+        root_db.insert(database_key(&owner, &name).as_bytes(), meta)?;
+        root_db.remove(key)?;
+    }
+
+    Ok(())
+}
+
+/// leaves of the revision tree of a document, each with the revisions leading to it
+fn revision_tree(doc_meta: &Value) -> Vec<RevisionLeaf> {
+    if let Ok(leaves) = serde_json::from_value(doc_meta["_leaves"].clone()) {
+        return leaves;
+    }
+
+    // documents written before conflicts were kept have a single branch
+    let path = match doc_meta["_revisions"].as_array() {
+        Some(revisions) => revisions
+            .iter()
+            .filter_map(|rev| rev.as_str().map(str::to_owned))
+            .collect::<Vec<_>>(),
+        // documents written before revisions were recorded only know their current one
+        None => doc_meta["_rev"]
+            .as_str()
+            .map(|rev| vec![rev.to_owned()])
+            .unwrap_or_default(),
+    };
+
+    match path.first() {
+        Some(rev) => vec![RevisionLeaf {
+            rev: rev.clone(),
+            path,
+            deleted: doc_meta["_deleted"] == true,
+        }],
+        None => Vec::new(),
+    }
+}
+
+/// The revision every replica picks as the current one: a revision that is not deleted if there
+/// is one, then the one with the highest generation, then the one with the highest hash
+fn winning_leaf(leaves: &[RevisionLeaf]) -> Option<&RevisionLeaf> {
+    leaves.iter().max_by(|a, b| {
+        (!a.deleted, util::parse_rev(&a.rev)).cmp(&(!b.deleted, util::parse_rev(&b.rev)))
+    })
+}
+
+/// key of the body of a conflicting revision of a document
+fn conflict_key(doc_id: &str, rev: &str) -> Vec<u8> {
+    [doc_id.as_bytes(), &[0], rev.as_bytes()].concat()
+}
+
+/// body of a revision of a document that is a leaf of its revision tree
+fn leaf_body(
+    db: &sled::Db,
+    doc_id: &str,
+    doc_meta: &Value,
+    rev: &str,
+) -> DatabaseResult<Option<sled::IVec>> {
+    if doc_meta["_rev"] == rev {
+        Ok(db.get(doc_id.as_bytes())?)
+    } else {
+        Ok(db.open_tree("conflicts")?.get(conflict_key(doc_id, rev))?)
+    }
+}
+
+/// error of a transaction, aborted with a database error or failed to reach the storage
+fn transaction_error(error: TransactionError<DatabaseError>) -> DatabaseError {
+    match error {
+        TransactionError::Abort(error) => error,
+        TransactionError::Storage(error) => error.into(),
+    }
+}
+
+/// abort a transaction on an error that is not one of the storage
+fn abort_on<E: Into<DatabaseError>>(error: E) -> ConflictableTransactionError<DatabaseError> {
+    ConflictableTransactionError::Abort(error.into())
+}
+
+/// Drop the oldest deleted branches of a revision tree beyond `TOMBSTONE_LIMIT`.
+/// Tombstones are kept so that replicas do not bring deleted branches back, the winning
+/// revision always is.
+fn prune_tombstones(leaves: &mut Vec<RevisionLeaf>) {
+    let winner = winning_leaf(leaves).map(|leaf| leaf.rev.clone());
+    let mut tombstones = leaves
+        .iter()
+        .filter(|leaf| leaf.deleted && Some(&leaf.rev) != winner.as_ref())
+        .map(|leaf| (util::parse_rev(&leaf.rev), leaf.rev.clone()))
+        .collect::<Vec<_>>();
+    if tombstones.len() <= TOMBSTONE_LIMIT {
+        return;
+    }
+
+    tombstones.sort();
+    let pruned = tombstones.len() - TOMBSTONE_LIMIT;
+    let pruned = tombstones
+        .into_iter()
+        .take(pruned)
+        .map(|(_, rev)| rev)
+        .collect::<Vec<_>>();
+    leaves.retain(|leaf| !pruned.contains(&leaf.rev));
+}
+
+/// save the revision tree of a document and record its winning revision in the changes feed
+fn record_revision(
+    docs: &TransactionalTree,
+    changes: &TransactionalTree,
+    doc_id: &str,
+    doc_meta: &mut Value,
+    mut leaves: Vec<RevisionLeaf>,
+) -> ConflictableTransactionResult<(), DatabaseError> {
+    for leaf in leaves.iter_mut() {
+        leaf.path.truncate(REVS_LIMIT);
+    }
+    prune_tombstones(&mut leaves);
+
+    // a document only appears once in the feed, at its latest change
+    if let Some(seq) = doc_meta["_seq"].as_u64() {
+        changes.remove(&seq.to_be_bytes())?;
+    }
+
+    let (rev, deleted) = winning_leaf(&leaves)
+        .map(|leaf| (leaf.rev.clone(), leaf.deleted))
+        .unwrap_or_default();
+
+    // sequence numbers start at 1, so that changes since 0 include all of them
+    let seq = docs.generate_id()? + 1;
+    changes.insert(
+        &seq.to_be_bytes(),
+        json!({
+            "id": doc_id,
+            "rev": rev,
+            "deleted": deleted,
+            "leaves": leaves.iter().map(|leaf| &leaf.rev).collect::<Vec<_>>()
+        })
+        .to_string()
+        .as_bytes(),
+    )?;
+
+    if let Value::Object(fields) = doc_meta {
+        // replaced by the revision tree
+        fields.remove("_revisions");
+    }
+    doc_meta["_rev"] = rev.into();
+    doc_meta["_leaves"] = serde_json::to_value(leaves).map_err(abort_on)?;
+    doc_meta["_seq"] = seq.into();
+    doc_meta["_deleted"] = deleted.into();
+
+    docs.insert(
+        format!("{}_meta", doc_id).as_bytes(),
+        doc_meta.to_string().as_bytes(),
+    )?;
+
+    Ok(())
+}
+
+/// Save a new revision of a document given the leaves of its revision tree.
+/// The body of the winning revision is kept beside the other documents, and the bodies of the
+/// other leaves that are not deleted in the conflicts tree.
+/// The bodies are moved, the revision tree saved and the write appended to the journal of the
+/// database in one transaction, which fails with a conflict if the document was written since
+/// its metadata was read.
+fn save_revision(
+    db: &sled::Db,
+    db_name: &str,
+    doc_id: &str,
+    doc_meta: &mut Value,
+    leaves: Vec<RevisionLeaf>,
+    rev: &str,
+    body: Option<Vec<u8>>,
+) -> DatabaseResult<()> {
+    let body_hash = body.as_deref().map(journal::sha256);
+    let conflicts = db.open_tree("conflicts")?;
+    let changes = db.open_tree("changes")?;
+    let journal = db.open_tree("journal")?;
+    let journal_head = db.open_tree("journal_head")?;
+    let meta_key = format!("{}_meta", doc_id);
+    let winner = winning_leaf(&leaves)
+        .map(|leaf| leaf.rev.clone())
+        .unwrap_or_default();
+
+    let saved = (&**db, &conflicts, &changes, &journal, &journal_head)
+        .transaction(|(docs, conflicts, changes, entries, heads)| {
+            let stored = match docs.get(meta_key.as_bytes())? {
+                Some(meta) => from_slice::<Value>(&meta).map_err(abort_on)?,
+                None => Value::Null,
+            };
+            if stored["_seq"] != doc_meta["_seq"] {
+                return Err(abort_on(DatabaseError::DocumentUpdateConflict));
+            }
+
+            // gather the bodies of the leaves, to put each of them in its place
+            let mut bodies = HashMap::new();
+            if let (Some(current), Some(body)) =
+                (stored["_rev"].as_str(), docs.remove(doc_id.as_bytes())?)
+            {
+                bodies.insert(current.to_owned(), body);
+            }
+            for leaf in revision_tree(&stored) {
+                if let Some(body) = conflicts.remove(conflict_key(doc_id, &leaf.rev))? {
+                    bodies.insert(leaf.rev, body);
+                }
+            }
+            if let Some(body) = &body {
+                bodies.insert(rev.to_owned(), body.as_slice().into());
+            }
+
+            for leaf in leaves.iter().filter(|leaf| !leaf.deleted) {
+                if let Some(body) = bodies.remove(&leaf.rev) {
+                    if leaf.rev == winner {
+                        docs.insert(doc_id.as_bytes(), body)?;
+                    } else {
+                        conflicts.insert(conflict_key(doc_id, &leaf.rev), body)?;
+                    }
+                }
+            }
+
+            let mut saved = doc_meta.clone();
+            record_revision(docs, changes, doc_id, &mut saved, leaves.clone())?;
+            append_journal(
+                (entries, heads),
+                json!({
+                    "at": util::get_unix_epoch_time(),
+                    "op": if body_hash.is_some() { "write" } else { "delete" },
+                    "db_name": db_name,
+                    "doc_id": doc_id,
+                    "rev": rev,
+                    "did": saved["_did"],
+                    "body_hash": body_hash,
+                }),
+                saved["_seq"].as_u64().unwrap_or_default(),
+            )?;
+            Ok(saved)
+        })
+        .map_err(transaction_error)?;
+
+    *doc_meta = saved;
+    Ok(())
+}
+
+/// key of the last entry of a journal in the `journal_head` tree
+const JOURNAL_HEAD: &[u8] = b"head";
+/// key of the first sequence number of the changes feed a journal covers in the `journal_head` tree
+const JOURNAL_SINCE: &[u8] = b"since";
+
+/// Append a write to the journal of a database, linked to the previous entry by its hash.
+/// The last entry is kept apart, so that the append is part of the transaction saving the write.
+fn append_journal(
+    (entries, heads): (&TransactionalTree, &TransactionalTree),
+    mut entry: Value,
+    change_seq: u64,
+) -> ConflictableTransactionResult<(), DatabaseError> {
+    let (seq, prev) = match heads.get(JOURNAL_HEAD)? {
+        Some(head) => {
+            let head = from_slice::<Value>(&head).map_err(abort_on)?;
+            (
+                head["seq"].as_u64().unwrap_or_default() + 1,
+                head["hash"].clone(),
+            )
+        }
+        None => {
+            // documents last written before the journal began are not covered by it
+            heads.insert(JOURNAL_SINCE, &change_seq.to_be_bytes())?;
+            (0, journal::GENESIS.into())
+        }
+    };
+
+    entry["seq"] = seq.into();
+    entry["prev"] = prev;
+    entry["hash"] = journal::entry_hash(&entry).into();
+    let entry = serde_json::to_vec(&entry).map_err(abort_on)?;
+    entries.insert(&seq.to_be_bytes(), entry.as_slice())?;
+    heads.insert(JOURNAL_HEAD, entry)?;
+
+    Ok(())
+}
+
+/// Sign the head of the journal of every open database written since its last checkpoint,
+/// returning the number of checkpoints made
+#[instrument(skip_all)]
+pub fn checkpoint_journals(config: &DbConfig, signer: &journal::Signer) -> DatabaseResult<usize> {
+    // the databases are released before signing, so that they can be opened meanwhile
+    let databases = DATABASES
+        .lock()
+        .map_err(|_| DatabaseError::OtherError)?
+        .iter()
+        .map(|(path, db)| (path.clone(), db.clone()))
+        .collect::<Vec<_>>();
+
+    let mut made = 0;
+    for (path, db) in databases {
+        let Some((key, head)) = db.open_tree("journal")?.last()? else {
+            continue;
+        };
+        let seq = seq_from_key(&key);
+        let checkpoints = db.open_tree("journal_checkpoints")?;
+        if checkpoints
+            .last()?
+            .is_some_and(|(last, _)| seq_from_key(&last) >= seq)
+        {
+            continue;
+        }
+
+        // the key of the database within the root database, which names it across applications
+        let database = path.strip_prefix(&config.path).unwrap_or(&path);
+        let hash = from_slice::<Value>(&head)?["hash"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        let checkpoint = json!({
+            "seq": seq,
+            "hash": hash,
+            "at": util::get_unix_epoch_time(),
+            "signer": signer.address(),
+            "signature": signer.sign(&journal::checkpoint_message(database, seq, &hash)),
+        });
+        checkpoints.insert(seq.to_be_bytes(), serde_json::to_vec(&checkpoint)?)?;
+        made += 1;
+    }
+
+    Ok(made)
+}
+
+/// what breaks the link of a journal entry to the previous one, if anything
+fn journal_fault(entry: &Value, seq: u64, prev: &Value) -> Option<&'static str> {
+    if entry["seq"] != seq {
+        Some("sequence number altered")
+    } else if &entry["prev"] != prev {
+        Some("not linked to the previous entry")
+    } else if entry["hash"] != journal::entry_hash(entry) {
+        Some("entry altered")
+    } else {
+        None
+    }
+}
+
+/// what is wrong with the checkpoint of a journal entry, if anything.
+/// Checkpoints must be signed by the account of the node.
+fn checkpoint_fault(
+    database: &str,
+    entry: &Value,
+    checkpoint: &Value,
+    signer: Option<&str>,
+) -> Option<&'static str> {
+    let address = checkpoint["signer"].as_str().unwrap_or_default();
+    let message = journal::checkpoint_message(
+        database,
+        entry["seq"].as_u64().unwrap_or_default(),
+        entry["hash"].as_str().unwrap_or_default(),
+    );
+
+    if checkpoint["hash"] != entry["hash"] {
+        Some("checkpoint does not match the entry")
+    } else if signer != Some(address) {
+        Some("checkpoint signed by another account")
+    } else if !journal::verify(
+        address,
+        &message,
+        checkpoint["signature"].as_str().unwrap_or_default(),
+    ) {
+        Some("checkpoint signature invalid")
+    } else {
+        None
+    }
+}
+
+/// Recompute the hashes of the journal of a database, check its checkpoints and the documents
+/// it covers, and report the first entry whose link is broken
+#[instrument(skip_all, fields(db_name = db_name))]
+pub fn verify_journal(
+    config: &DbConfig,
+    application_did: &Did,
+    db_name: &str,
+) -> DatabaseResult<Value> {
+    let db = open_database(config, application_did, db_name)?;
+    let signer = journal::Signer::from_mnemonic(&config.mnemonic).map(|signer| signer.address());
+
+    check_journal(
+        &db,
+        db_name,
+        &database_key(application_did, db_name),
+        signer.as_deref(),
+    )
+}
+
+/// Check the journal of a database, named `database` in its checkpoints, which must be signed by
+/// the account `signer`. The winning revision of every document written since the journal
+/// began must be journaled, with the hash of the body stored.
+fn check_journal(
+    db: &sled::Db,
+    db_name: &str,
+    database: &str,
+    signer: Option<&str>,
+) -> DatabaseResult<Value> {
+    let mut checkpoints = BTreeMap::new();
+    for (key, checkpoint) in db
+        .open_tree("journal_checkpoints")?
+        .iter()
+        .filter_map(Result::ok)
+    {
+        checkpoints.insert(seq_from_key(&key), from_slice::<Value>(&checkpoint)?);
+    }
+    let checkpointed = checkpoints.keys().next_back().copied();
+
+    // the revisions journaled for each document, with the entry and body hash of each
+    let mut journaled = HashMap::<String, HashMap<String, (u64, Value)>>::new();
+    let mut prev = Value::from(journal::GENESIS);
+    let mut verified = 0;
+    let mut broken = None;
+    for (key, entry) in db.open_tree("journal")?.iter().filter_map(Result::ok) {
+        let seq = seq_from_key(&key);
+        if seq != verified {
+            broken = Some(json!({ "seq": verified, "reason": "entry missing" }));
+            break;
+        }
+        let Ok(entry) = from_slice::<Value>(&entry) else {
+            broken = Some(json!({ "seq": seq, "reason": "entry unreadable" }));
+            break;
+        };
+        let fault = journal_fault(&entry, seq, &prev).or_else(|| {
+            checkpoints
+                .remove(&seq)
+                .and_then(|checkpoint| checkpoint_fault(database, &entry, &checkpoint, signer))
+        });
+        if let Some(reason) = fault {
+            broken = Some(json!({ "seq": seq, "reason": reason }));
+            break;
+        }
+
+        prev = entry["hash"].clone();
+        verified += 1;
+        if let (Some(doc_id), Some(rev)) = (entry["doc_id"].as_str(), entry["rev"].as_str()) {
+            journaled
+                .entry(doc_id.to_owned())
+                .or_default()
+                .insert(rev.to_owned(), (seq, entry["body_hash"].clone()));
+        }
+    }
+
+    // checkpoints past the end of the journal cover entries that were removed
+    if broken.is_none() && !checkpoints.is_empty() {
+        broken = Some(json!({ "seq": verified, "reason": "entry missing" }));
+    }
+
+    if broken.is_none() {
+        let since = db
+            .open_tree("journal_head")?
+            .get(JOURNAL_SINCE)?
+            .map(|since| seq_from_key(&since));
+
+        let mut faults = Vec::new();
+        for (key, doc_meta) in db.iter().filter_map(Result::ok) {
+            let Some(doc_id) = key.strip_suffix(b"_meta") else {
+                continue;
+            };
+            let doc_id = String::from_utf8_lossy(doc_id).into_owned();
+            let doc_meta = from_slice::<Value>(&doc_meta)?;
+            let revisions = journaled.get(&doc_id);
+
+            // documents last written before the journal began are not covered by it
+            let covered = since
+                .is_some_and(|since| doc_meta["_seq"].as_u64().is_some_and(|seq| seq >= since));
+            if !covered {
+                continue;
+            }
+
+            let rev = doc_meta["_rev"].as_str().unwrap_or_default();
+            match revisions.and_then(|revisions| revisions.get(rev)) {
+                None => faults.push((None, doc_id, "winning revision not journaled")),
+                Some((seq, body_hash)) => {
+                    // only the body of the winning revision is kept beside the document
+                    let body = db.get(doc_id.as_bytes())?;
+                    if body.map(|body| journal::sha256(&body)).as_deref() != body_hash.as_str() {
+                        faults.push((Some(*seq), doc_id, "document altered since the entry"));
+                    }
+                }
+            }
+        }
+
+        broken = faults
+            .into_iter()
+            .min_by_key(|(seq, doc_id, _)| (seq.is_none(), *seq, doc_id.clone()))
+            .map(|(seq, doc_id, reason)| json!({ "seq": seq, "reason": reason, "doc_id": doc_id }));
+    }
+
+    Ok(json!({
+        "ok": broken.is_none(),
+        "db_name": db_name,
+        "verified": verified,
+        "head": if verified > 0 { prev } else { Value::Null },
+        "checkpointed": checkpointed,
+        "signer": signer,
+        "broken": broken,
+    }))
+}
+
+/// record the documents of a database written before changes were recorded in its changes feed
+fn backfill_changes(db: &sled::Db) -> DatabaseResult<()> {
+    if !db.open_tree("changes")?.is_empty() {
+        return Ok(());
+    }
+
+    let changes = db.open_tree("changes")?;
+    for (key, meta) in db.iter().filter_map(Result::ok) {
+        if let Some(doc_id) = key.strip_suffix(b"_meta") {
+            let doc_id = String::from_utf8_lossy(doc_id);
+            let doc_meta = from_slice::<Value>(&meta)?;
+            let leaves = revision_tree(&doc_meta);
+            (&**db, &changes)
+                .transaction(|(docs, changes)| {
+                    let mut doc_meta = doc_meta.clone();
+                    record_revision(docs, changes, &doc_id, &mut doc_meta, leaves.clone())
+                })
+                .map_err(transaction_error)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// add a written document to the queue of DIDs to validate
+async fn queue_did_validation(
+    did_queue: &Arc<Mutex<VecDeque<DbEntry>>>,
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    did: &Did,
+) {
+    let mut guard = did_queue.lock().instrument(info_span!("did_queue")).await;
+    // check that the did is not already on the queue, before pushing
+    if guard.iter().all(|e| &e.did != did) {
+        guard.push_back(DbEntry {
+            did: did.clone(),
+            application_did: application_did.clone(),
+            db_name: db_name.to_owned(),
+            doc_id: doc_id.to_owned(),
+        });
+    }
+}
+
+/// Record a local write to a document of a database in CRDT mode over its previous revision,
+/// returning the document to store and its clocks
+fn crdt_update(doc_meta: &Value, old: Option<Value>, new: Value) -> DatabaseResult<(Value, Value)> {
+    let fields =
+        |document: Value| util::remove_field(util::remove_field(document, "_rev").0, "id").0;
+    let clocks = serde_json::from_value::<crdt::Node>(doc_meta["_crdt"].clone()).ok();
+    let old = old.map(fields);
+
+    let id = new["id"].clone();
+    let (mut document, clocks) = crdt::update(old.as_ref().zip(clocks.as_ref()), &fields(new));
+    document["id"] = id;
+
+    Ok((document, serde_json::to_value(clocks)?))
+}
+
 /// write to database
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
 pub async fn update_document(
+    application_did: &Did,
     db_name: &str,
     doc_id: &str,
     did: Did,
@@ -104,12 +1497,24 @@ pub async fn update_document(
     // first parse the data wrapper
     let mut db_entry: Value = data_wrapper.data;
 
-    let cfg = sled::Config::default()
-        .path(format!("{}{}", config.path, db_name))
-        .cache_capacity(config.cache_capacity)
-        .flush_every_ms(Some(config.flush_interval));
+    let db = open_database(config, application_did, db_name)?;
 
-    let db = cfg.open()?;
+    // in CRDT mode, writes apply to the current revision and concurrent ones are merged,
+    // so that they never conflict
+    let crdt = database_mode(config, application_did, db_name)? == DatabaseMode::Crdt;
+    if crdt {
+        let current = match db.get(doc_id.as_bytes())? {
+            Some(_) => match db.get(format!("{}_meta", doc_id).as_bytes())? {
+                Some(doc_meta) => Some(from_slice::<Value>(&doc_meta)?["_rev"].clone()),
+                None => None,
+            },
+            None => None,
+        };
+        db_entry = util::remove_field(db_entry, "_rev").0;
+        if let Some(rev) = current {
+            db_entry["_rev"] = rev;
+        }
+    }
 
     // clone did for the queue
     let did_1 = did.clone();
@@ -120,35 +1525,43 @@ pub async fn update_document(
     // _rev signifies an update
     let rev = db_entry["_rev"].clone();
     if rev != Value::Null {
-        let rev = rev.to_string();
-
-        // get the document entry and its metadata
-        let doc = db
-            .get(doc_id.as_bytes())?
-            .ok_or(DatabaseError::OtherError)?;
+        let rev = rev.as_str().unwrap_or_default();
 
+        // get the document metadata and its revision tree
         let doc_meta = db
-            .get(meta_id.as_bytes())?
-            .ok_or(DatabaseError::OtherError)?;
-
-        let doc = from_str::<Value>(
-            &String::from_utf8(doc.to_vec())
-                .ok()
-                .ok_or(DatabaseError::OtherError)?,
-        )?;
+            .get(meta_id.as_bytes())?
+            .ok_or(DatabaseError::MissingDocument)?;
+
         let mut doc_meta = from_str::<Value>(
             &String::from_utf8(doc_meta.to_vec())
                 .ok()
                 .ok_or(DatabaseError::OtherError)?,
         )?;
+        let mut leaves = revision_tree(&doc_meta);
+        if leaves.iter().all(|leaf| leaf.deleted) {
+            return Err(DatabaseError::MissingDocument);
+        }
 
-        // extract _rev_id and compare
-        if rev == doc["_rev"].to_string() {
+        // any revision that is not deleted can be updated, so conflicts can be resolved by
+        // updating the winning one and deleting the others
+        if let Some(leaf) = leaves
+            .iter()
+            .position(|leaf| !leaf.deleted && leaf.rev == rev)
+        {
             // check for did correlation
             if Value::String(did.0) == doc_meta["_did"] {
+                let doc = leaf_body(&db, doc_id, &doc_meta, rev)?
+                    .ok_or(DatabaseError::DocumentRevisionNotFound)?;
+                let doc = encryption::open_document(config, application_did, &doc)?;
+
                 // update data
-                let new_entry = util::merge_json_values(doc, db_entry);
+                let new_entry = util::merge_json_values(doc.clone(), db_entry);
                 let (mut new_entry, current_rev) = util::remove_field(new_entry, "_rev");
+                if crdt {
+                    let (document, clocks) = crdt_update(&doc_meta, Some(doc), new_entry)?;
+                    new_entry = document;
+                    doc_meta["_crdt"] = clocks;
+                }
                 if let Some(_rev) = current_rev {
                     // get new rev
                     let _rev = _rev.as_str().unwrap_or_default();
@@ -168,27 +1581,38 @@ pub async fn update_document(
                     new_entry["_rev"] = new_rev.clone().into();
 
                     // save new document
-                    db.insert(doc_id.as_bytes(), new_entry.to_string().as_bytes())?;
+                    encryption::encrypt_fields(
+                        config,
+                        application_did,
+                        db_name,
+                        &did_1,
+                        &mut new_entry,
+                    )?;
+                    let body =
+                        encryption::seal_document(config, application_did, &did_1, &new_entry)?;
+
+                    // extend the branch of the updated revision
+                    leaves[leaf] = RevisionLeaf {
+                        rev: new_rev.clone(),
+                        path: [vec![new_rev.clone()], leaves[leaf].path.clone()].concat(),
+                        deleted: false,
+                    };
 
                     // update document metadata
-                    doc_meta["_rev"] = new_rev.clone().into();
                     doc_meta["updated_at"] = util::get_unix_epoch_time().into();
-
-                    // save metadata
-                    db.insert(meta_id.as_bytes(), doc_meta.to_string().as_bytes())?;
+                    save_revision(
+                        &db,
+                        db_name,
+                        doc_id,
+                        &mut doc_meta,
+                        leaves,
+                        &new_rev,
+                        Some(body),
+                    )?;
 
                     // push to db_entry queue for DID validation
-                    let mut guard = did_queue.lock().await;
-                    // check that the did is not already on the queue, before pushing
-                    if guard.iter().all(|e| e.did != did_1) {
-                        guard.push_back(DbEntry {
-                            did: did_1.clone(),
-                            db_name: db_name.to_owned(),
-                            doc_id: doc_id.to_owned(),
-                        });
-
-                        println!("--- {:#?}", guard);
-                    }
+                    queue_did_validation(did_queue, application_did, db_name, doc_id, &did_1).await;
+                    metrics::document_operation(application_did, db_name, "write");
 
                     // return response
                     return Ok(json!({
@@ -212,40 +1636,84 @@ pub async fn update_document(
     } else {
         // first check that truly, the document doesn't exist
         if let None = db.get(doc_id.as_bytes())? {
+            // a deleted document is recreated on top of its history, so that replicas
+            // holding the deletion accept the new revision
+            let (seq, mut leaves) = match db.get(meta_id.as_bytes())? {
+                Some(doc_meta) => {
+                    let doc_meta = from_slice::<Value>(&doc_meta)?;
+                    (doc_meta["_seq"].clone(), revision_tree(&doc_meta))
+                }
+                None => (Value::Null, Vec::new()),
+            };
+            let deleted = winning_leaf(&leaves).cloned();
+            let generation = deleted
+                .as_ref()
+                .and_then(|leaf| util::parse_rev(&leaf.rev))
+                .map_or(0, |(generation, _)| generation);
+
             // create new document entry in the database
             // update id
             db_entry["id"] = doc_id.to_owned().into();
+            let clocks = if crdt {
+                let (document, clocks) = crdt_update(&Value::Null, None, db_entry)?;
+                db_entry = document;
+                Some(clocks)
+            } else {
+                None
+            };
             // update rev
-            let rev = util::generate_rev(1, &db_entry.to_string());
+            let rev = util::generate_rev(generation + 1, &db_entry.to_string());
             db_entry["_rev"] = rev.clone().into();
 
             // save entry
-            db.insert(doc_id.as_bytes(), db_entry.to_string().as_bytes())?;
+            encryption::encrypt_fields(config, application_did, db_name, &did, &mut db_entry)?;
+            let body = encryption::seal_document(config, application_did, &did, &db_entry)?;
+
+            let leaf = RevisionLeaf {
+                rev: rev.clone(),
+                path: [
+                    vec![rev.clone()],
+                    deleted
+                        .as_ref()
+                        .map(|leaf| leaf.path.clone())
+                        .unwrap_or_default(),
+                ]
+                .concat(),
+                deleted: false,
+            };
+            match deleted {
+                Some(deleted) => leaves.retain(|leaf| leaf.rev != deleted.rev),
+                None => leaves.clear(),
+            }
+            leaves.push(leaf);
 
             // save the document metadata too
-            let metadata = json!({
+            let mut metadata = json!({
                 // accessible by default, except changed in contract
                 "_accessible": true,
                 "_did": did.0,
-                "_rev": rev.clone(),
+                "_seq": seq,
                 "created_at": util::get_unix_epoch_time(),
                 "updated_at": util::get_unix_epoch_time(),
-            })
-            .to_string();
+            });
+            if let Some(clocks) = clocks {
+                metadata["_crdt"] = clocks;
+            }
 
             // save in same database
-            db.insert(meta_id.as_bytes(), metadata.to_string().as_bytes())?;
+            save_revision(
+                &db,
+                db_name,
+                doc_id,
+                &mut metadata,
+                leaves,
+                &rev,
+                Some(body),
+            )?;
 
             // push to db_entry queue for DID validation
-            let mut guard = did_queue.lock().await;
-            // check that the did is not already on the queue, before pushing
-            if guard.iter().any(|e| e.did != did_1) {
-                guard.push_back(DbEntry {
-                    did: did_1.clone(),
-                    db_name: db_name.to_owned(),
-                    doc_id: doc_id.to_owned(),
-                });
-            }
+            queue_did_validation(did_queue, application_did, db_name, doc_id, &did_1).await;
+            metrics::document_operation(application_did, db_name, "write");
 
             // return response
             return Ok(json!({
@@ -260,42 +1728,1035 @@ pub async fn update_document(
 }
 
 /// read from database
-pub fn fetch_document(db_name: &str, doc_id: &str, config: &DbConfig) -> DatabaseResult<Value> {
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn fetch_document(
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    config: &DbConfig,
+) -> DatabaseResult<Value> {
     // open database
-    let cfg = sled::Config::default()
-        .path(format!("{}{}", config.path, db_name))
-        .cache_capacity(config.cache_capacity)
-        .flush_every_ms(Some(config.flush_interval));
-
-    let db = cfg.open()?;
+    let db = open_database(config, application_did, db_name)?;
 
     let document = db
         .get(doc_id.as_bytes())?
         .ok_or(DatabaseError::MissingDocument)?;
+    metrics::document_operation(application_did, db_name, "read");
 
-    let doc_string = String::from_utf8(document.to_vec())
-        .ok()
-        .ok_or(DatabaseError::OtherError)?;
+    encryption::open_document(config, application_did, &document)
+}
+
+/// return the stored documents whose fields equal those of the selector
+#[instrument(skip_all, fields(db_name = db_name))]
+pub fn find_documents(
+    application_did: &Did,
+    db_name: &str,
+    selector: &Map<String, Value>,
+    config: &DbConfig,
+) -> DatabaseResult<Vec<Value>> {
+    // open database
+    let db = open_database(config, application_did, db_name)?;
+
+    let mut documents = Vec::new();
+    for (key, document) in db.iter().filter_map(Result::ok) {
+        // metadata entries live beside the documents
+        if key.ends_with(b"_meta") {
+            continue;
+        }
+
+        // documents whose data key was destroyed cannot be read, nor matched
+        let document = match encryption::open_document(config, application_did, &document) {
+            Ok(document) => document,
+            Err(DatabaseError::DataKeyShredded) => continue,
+            Err(e) => return Err(e),
+        };
+        if selector
+            .iter()
+            .all(|(path, value)| document.pointer(&util::json_pointer(path)) == Some(value))
+        {
+            documents.push(document);
+        }
+    }
 
-    Ok(json!(from_str::<Value>(&doc_string)?))
+    Ok(documents)
 }
 
-/// delete document
-pub fn delete_document(db_name: &str, doc_id: &str, config: &DbConfig) -> DatabaseResult<()> {
+/// delete a revision of a document, the winning one unless another one is given.
+/// A tombstone holding its history is kept, so that the deletion can be replicated, and its
+/// revision is returned.
+/// Deleting the winning revision of a document in conflict makes another revision win.
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn delete_document(
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    rev: Option<&str>,
+    config: &DbConfig,
+) -> DatabaseResult<String> {
     // open database
-    let cfg = sled::Config::default()
-        .path(format!("{}{}", config.path, db_name))
-        .cache_capacity(config.cache_capacity)
-        .flush_every_ms(Some(config.flush_interval));
+    let db = open_database(config, application_did, db_name)?;
 
-    let db = cfg.open()?;
+    let mut doc_meta = from_slice::<Value>(
+        &db.get(format!("{}_meta", doc_id).as_bytes())?
+            .ok_or(DatabaseError::MissingDocument)?,
+    )?;
+    let mut leaves = revision_tree(&doc_meta);
+    let rev = match rev {
+        Some(rev) => rev.to_owned(),
+        None => match winning_leaf(&leaves) {
+            Some(leaf) if !leaf.deleted => leaf.rev.clone(),
+            _ => return Err(DatabaseError::MissingDocument),
+        },
+    };
+    let leaf = leaves
+        .iter()
+        .position(|leaf| !leaf.deleted && leaf.rev == rev)
+        .ok_or(DatabaseError::DocumentUpdateConflict)?;
 
-    db.remove(doc_id.as_bytes())?
-        .ok_or(DatabaseError::OtherError)?;
+    let generation = util::parse_rev(&rev)
+        .ok_or(DatabaseError::RevisionIdParseError)?
+        .0;
+    let tombstone = util::generate_rev(generation + 1, &format!("{}_deleted", rev));
 
-    // delete metadata
-    db.remove(format!("{}_meta", doc_id).as_bytes())?
-        .ok_or(DatabaseError::OtherError)?;
+    // in CRDT mode, the deletion is merged like any other write
+    if doc_meta.get("_crdt").is_some() && doc_meta["_rev"] == rev.as_str() {
+        doc_meta["_crdt"] = serde_json::to_value(crdt::Node::Removed {
+            clock: crdt::tick(),
+        })?;
+    }
+    leaves[leaf] = RevisionLeaf {
+        rev: tombstone.clone(),
+        path: [vec![tombstone.clone()], leaves[leaf].path.clone()].concat(),
+        deleted: true,
+    };
+
+    // update metadata
+    doc_meta["updated_at"] = util::get_unix_epoch_time().into();
+    save_revision(
+        &db,
+        db_name,
+        doc_id,
+        &mut doc_meta,
+        leaves,
+        &tombstone,
+        None,
+    )?;
+    metrics::document_operation(application_did, db_name, "delete");
+
+    Ok(tombstone)
+}
+
+/// return the name, document count and latest sequence number of a database
+#[instrument(skip_all, fields(db_name = db_name))]
+pub fn database_info(
+    application_did: &Did,
+    db_name: &str,
+    config: &DbConfig,
+) -> DatabaseResult<Value> {
+    let db = open_database(config, application_did, db_name)?;
+    let doc_count = db
+        .iter()
+        .keys()
+        .filter_map(Result::ok)
+        .filter(|key| !key.ends_with(b"_meta"))
+        .count();
+
+    Ok(json!({
+        "db_name": db_name,
+        "doc_count": doc_count,
+        "update_seq": last_seq(&db)?
+    }))
+}
+
+/// sequence number of the latest change made to a database
+fn last_seq(db: &sled::Db) -> DatabaseResult<u64> {
+    Ok(db
+        .open_tree("changes")?
+        .last()?
+        .map(|(seq, _)| seq_from_key(&seq))
+        .unwrap_or_default())
+}
+
+/// decode the sequence number keying a change
+fn seq_from_key(key: &[u8]) -> u64 {
+    u64::from_be_bytes(key.try_into().unwrap_or_default())
+}
+
+/// Return the documents changed after the sequence number `since`, oldest change first.
+/// With `all_docs`, every leaf revision of the documents is listed instead of the winning one.
+#[instrument(skip_all, fields(db_name = db_name))]
+pub fn changes(
+    application_did: &Did,
+    db_name: &str,
+    since: u64,
+    limit: Option<usize>,
+    all_docs: bool,
+    config: &DbConfig,
+) -> DatabaseResult<Value> {
+    let db = open_database(config, application_did, db_name)?;
+
+    let mut results = Vec::new();
+    let mut last_seq = since;
+    let mut changes = db
+        .open_tree("changes")?
+        .range((since + 1).to_be_bytes()..)
+        .filter_map(Result::ok);
+    for (seq, change) in changes.by_ref().take(limit.unwrap_or(usize::MAX)) {
+        let change = from_slice::<Value>(&change)?;
+        last_seq = seq_from_key(&seq);
+
+        let revs = match change["leaves"].as_array() {
+            Some(leaves) if all_docs => leaves.clone(),
+            _ => vec![change["rev"].clone()],
+        };
+        let mut result = json!({
+            "seq": last_seq,
+            "id": change["id"],
+            "changes": revs.into_iter().map(|rev| json!({ "rev": rev })).collect::<Vec<_>>()
+        });
+        if change["deleted"] == true {
+            result["deleted"] = true.into();
+        }
+        results.push(result);
+    }
+
+    Ok(json!({
+        "results": results,
+        "last_seq": last_seq,
+        // changes left after the limit
+        "pending": changes.count()
+    }))
+}
+
+/// return the revisions of each document that the database does not have
+#[instrument(skip_all, fields(db_name = db_name))]
+pub fn revs_diff(
+    application_did: &Did,
+    db_name: &str,
+    revs: &Map<String, Value>,
+    config: &DbConfig,
+) -> DatabaseResult<Value> {
+    let db = open_database(config, application_did, db_name)?;
+
+    let mut diff = Map::new();
+    for (doc_id, revs) in revs {
+        let leaves = match db.get(format!("{}_meta", doc_id).as_bytes())? {
+            Some(doc_meta) => revision_tree(&from_slice::<Value>(&doc_meta)?),
+            None => Vec::new(),
+        };
+
+        let missing = revs
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .filter(|rev| {
+                !leaves
+                    .iter()
+                    .any(|leaf| leaf.path.iter().any(|known| known == rev))
+            })
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            diff.insert(doc_id.to_owned(), json!({ "missing": missing }));
+        }
+    }
+
+    Ok(Value::Object(diff))
+}
+
+/// read the metadata of a document
+fn document_meta(db: &sled::Db, doc_id: &str) -> DatabaseResult<Value> {
+    Ok(from_slice::<Value>(
+        &db.get(format!("{}_meta", doc_id).as_bytes())?
+            .ok_or(DatabaseError::MissingDocument)?,
+    )?)
+}
+
+/// DID of the user owning a document, `None` if the document does not exist
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn document_owner(
+    config: &DbConfig,
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+) -> DatabaseResult<Option<Did>> {
+    match document_meta(&open_database(config, application_did, db_name)?, doc_id) {
+        Ok(doc_meta) => Ok(Some(Did(doc_meta["_did"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned()))),
+        Err(DatabaseError::MissingDocument) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// a leaf revision of a document as replication exchanges it, with the document ID, owner and
+/// revision history. Deleted revisions are tombstones.
+fn replicated_revision(
+    config: &DbConfig,
+    application_did: &Did,
+    db: &sled::Db,
+    doc_id: &str,
+    doc_meta: &Value,
+    leaf: &RevisionLeaf,
+) -> DatabaseResult<Value> {
+    let mut document = if leaf.deleted {
+        json!({ "_rev": leaf.rev, "_deleted": true })
+    } else {
+        let body = leaf_body(db, doc_id, doc_meta, &leaf.rev)?
+            .ok_or(DatabaseError::DocumentRevisionNotFound)?;
+        encryption::open_document(config, application_did, &body)?
+    };
+
+    let start = util::parse_rev(&leaf.rev).map_or(0, |(generation, _)| generation);
+    let ids = leaf
+        .path
+        .iter()
+        .filter_map(|rev| util::parse_rev(rev).map(|(_, hash)| hash))
+        .collect::<Vec<_>>();
+
+    document["_id"] = doc_id.into();
+    document["_did"] = doc_meta["_did"].clone();
+    document["_revisions"] = json!({ "start": start, "ids": ids });
+    if let (Some(clocks), true) = (doc_meta.get("_crdt"), doc_meta["_rev"] == leaf.rev.as_str()) {
+        document["_crdt"] = clocks.clone();
+    }
+
+    Ok(document)
+}
+
+/// read a leaf revision of a document, the winning one unless another one is given,
+/// with its revision history and owner
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn fetch_document_revs(
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    rev: Option<&str>,
+    config: &DbConfig,
+) -> DatabaseResult<Value> {
+    let db = open_database(config, application_did, db_name)?;
+    let doc_meta = document_meta(&db, doc_id)?;
+    let leaves = revision_tree(&doc_meta);
+
+    let leaf = match rev {
+        Some(rev) => leaves.iter().find(|leaf| leaf.rev == rev),
+        None => winning_leaf(&leaves),
+    }
+    .ok_or(DatabaseError::MissingDocument)?;
+
+    replicated_revision(config, application_did, &db, doc_id, &doc_meta, leaf)
+}
+
+/// read every leaf revision of a document, with their revision history and owner
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn fetch_open_revs(
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    config: &DbConfig,
+) -> DatabaseResult<Vec<Value>> {
+    let db = open_database(config, application_did, db_name)?;
+    let doc_meta = document_meta(&db, doc_id)?;
+
+    revision_tree(&doc_meta)
+        .iter()
+        .map(|leaf| replicated_revision(config, application_did, &db, doc_id, &doc_meta, leaf))
+        .collect()
+}
+
+/// revisions of a document in conflict with its winning revision
+fn conflicting_revs(doc_meta: &Value) -> Vec<String> {
+    revision_tree(doc_meta)
+        .into_iter()
+        .filter(|leaf| !leaf.deleted && doc_meta["_rev"] != leaf.rev.as_str())
+        .map(|leaf| leaf.rev)
+        .collect()
+}
+
+/// return the revisions of a document in conflict with its winning revision
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn conflicts(
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    config: &DbConfig,
+) -> DatabaseResult<Vec<String>> {
+    let db = open_database(config, application_did, db_name)?;
+
+    Ok(conflicting_revs(&document_meta(&db, doc_id)?))
+}
+
+/// list the documents of a database with conflicting revisions
+#[instrument(skip_all, fields(db_name = db_name))]
+pub fn documents_with_conflicts(
+    application_did: &Did,
+    db_name: &str,
+    config: &DbConfig,
+) -> DatabaseResult<Vec<Value>> {
+    let db = open_database(config, application_did, db_name)?;
+
+    let mut documents = Vec::new();
+    for (key, doc_meta) in db.iter().filter_map(Result::ok) {
+        if let Some(doc_id) = key.strip_suffix(b"_meta") {
+            let doc_meta = from_slice::<Value>(&doc_meta)?;
+            let conflicts = conflicting_revs(&doc_meta);
+            if !conflicts.is_empty() {
+                documents.push(json!({
+                    "id": String::from_utf8_lossy(doc_id),
+                    "rev": doc_meta["_rev"],
+                    "_conflicts": conflicts
+                }));
+            }
+        }
+    }
+
+    Ok(documents)
+}
+
+/// Write a revision of a document produced by another replica, keeping its revision.
+/// A revision descending from a leaf of the revision tree extends its branch, others start
+/// a new branch in conflict with the existing ones. In CRDT mode, they are merged with the
+/// current revision instead.
+#[instrument(skip_all, fields(db_name = db_name))]
+pub async fn write_replicated_document(
+    application_did: &Did,
+    db_name: &str,
+    mut document: Value,
+    config: &DbConfig,
+    did_queue: &Arc<Mutex<VecDeque<DbEntry>>>,
+) -> DatabaseResult<Value> {
+    let db = open_database(config, application_did, db_name)?;
+
+    let doc_id = document["_id"]
+        .as_str()
+        .ok_or(DatabaseError::MissingDocument)?
+        .to_owned();
+    let did = Did(document["_did"].as_str().unwrap_or_default().to_owned());
+    let deleted = document["_deleted"] == true;
+
+    // expand the revision history into full revisions, newest first
+    let start = document["_revisions"]["start"]
+        .as_u64()
+        .ok_or(DatabaseError::RevisionIdParseError)?;
+    let revisions = document["_revisions"]["ids"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .zip((1..=start).rev())
+        .map(|(hash, generation)| format!("{}-{}", generation, hash))
+        .collect::<Vec<_>>();
+    let rev = revisions
+        .first()
+        .cloned()
+        .ok_or(DatabaseError::RevisionIdParseError)?;
+
+    let (mut doc_meta, mut leaves) = match db.get(format!("{}_meta", doc_id).as_bytes())? {
+        Some(doc_meta) => {
+            let doc_meta = from_slice::<Value>(&doc_meta)?;
+            let leaves = revision_tree(&doc_meta);
+
+            // the revision is already here
+            if leaves.iter().any(|leaf| leaf.path.contains(&rev)) {
+                return Ok(json!({ "id": doc_id, "rev": rev }));
+            }
+
+            if doc_meta["_did"] != Value::String(did.0.clone()) {
+                return Err(DatabaseError::UserDidConflict);
+            }
+            (doc_meta, leaves)
+        }
+        None => {
+            if !DbConfig::is_valid_did(&did.0, true) {
+                return Err(DatabaseError::UserDidConflict);
+            }
+
+            let metadata = json!({
+                "_accessible": true,
+                "_did": did.0,
+                "created_at": util::get_unix_epoch_time(),
+            });
+            (metadata, Vec::new())
+        }
+    };
+    doc_meta["updated_at"] = util::get_unix_epoch_time().into();
+
+    // in CRDT mode, documents come with their clocks
+    let clocks = match document.get("_crdt") {
+        Some(clocks) if database_mode(config, application_did, db_name)? == DatabaseMode::Crdt => {
+            serde_json::from_value::<crdt::Node>(clocks.clone()).ok()
+        }
+        _ => None,
+    };
+    if let Value::Object(fields) = &mut document {
+        for field in [
+            "_id",
+            "_did",
+            "_revisions",
+            "_deleted",
+            "_conflicts",
+            "_crdt",
+            "_rev",
+        ] {
+            fields.remove(field);
+        }
+    }
+    document["id"] = doc_id.clone().into();
+
+    let local_clocks = serde_json::from_value::<crdt::Node>(doc_meta["_crdt"].clone()).ok();
+    let (leaf, document) = match (clocks, local_clocks) {
+        // concurrent edits are merged into a single revision, which every replica derives alike
+        (Some(clocks), Some(local_clocks)) => {
+            crdt::observe(clocks.clock());
+
+            let winner = winning_leaf(&leaves)
+                .cloned()
+                .ok_or(DatabaseError::MissingDocument)?;
+            let local = match db.get(doc_id.as_bytes())? {
+                Some(body) => {
+                    util::remove_field(
+                        encryption::open_document(config, application_did, &body)?,
+                        "_rev",
+                    )
+                    .0
+                }
+                None => Value::Null,
+            };
+            let (mut merged, merged_clocks) =
+                crdt::merge((&local, &local_clocks), (&document, &clocks));
+            leaves.clear();
+
+            if merged_clocks == clocks {
+                doc_meta["_crdt"] = serde_json::to_value(clocks)?;
+                (
+                    RevisionLeaf {
+                        rev: rev.clone(),
+                        path: revisions,
+                        deleted,
+                    },
+                    document,
+                )
+            } else {
+                let generation = util::parse_rev(&winner.rev)
+                    .map_or(0, |(generation, _)| generation)
+                    .max(start)
+                    + 1;
+                let merged_rev =
+                    util::generate_rev(generation, &serde_json::to_string(&merged_clocks)?);
+                merged["id"] = doc_id.clone().into();
+                doc_meta["_crdt"] = serde_json::to_value(&merged_clocks)?;
+                (
+                    RevisionLeaf {
+                        rev: merged_rev.clone(),
+                        path: [vec![merged_rev], winner.path].concat(),
+                        deleted: merged_clocks.is_removed(),
+                    },
+                    merged,
+                )
+            }
+        }
+        (clocks, _) => {
+            // the clocks only describe the winning revision
+            if let Some(clocks) = clocks {
+                if winning_leaf(&leaves).is_none_or(|winner| {
+                    util::parse_rev(&rev) > util::parse_rev(&winner.rev) || winner.deleted
+                }) {
+                    doc_meta["_crdt"] = serde_json::to_value(clocks)?;
+                }
+            }
+            (
+                RevisionLeaf {
+                    rev: rev.clone(),
+                    path: revisions,
+                    deleted,
+                },
+                document,
+            )
+        }
+    };
+
+    let leaf_rev = leaf.rev.clone();
+    let deleted = leaf.deleted;
+    match leaves
+        .iter()
+        .position(|known| leaf.path.contains(&known.rev))
+    {
+        Some(extended) => leaves[extended] = leaf,
+        None => leaves.push(leaf),
+    }
+
+    let body = if deleted {
+        None
+    } else {
+        let mut document = document;
+        document["_rev"] = leaf_rev.clone().into();
+
+        encryption::encrypt_fields(config, application_did, db_name, &did, &mut document)?;
+        Some(encryption::seal_document(
+            config,
+            application_did,
+            &did,
+            &document,
+        )?)
+    };
+
+    save_revision(
+        &db,
+        db_name,
+        &doc_id,
+        &mut doc_meta,
+        leaves,
+        &leaf_rev,
+        body,
+    )?;
+    metrics::document_operation(
+        application_did,
+        db_name,
+        if deleted { "delete" } else { "write" },
+    );
+
+    // replicated documents are validated like local writes
+    if !deleted {
+        queue_did_validation(did_queue, application_did, db_name, &doc_id, &did).await;
+    }
+
+    Ok(json!({ "id": doc_id, "rev": rev }))
+}
+
+/// read a local document, which holds replication state and is never replicated
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn fetch_local_document(
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    config: &DbConfig,
+) -> DatabaseResult<Value> {
+    let db = open_database(config, application_did, db_name)?;
+    let document = db
+        .open_tree("local")?
+        .get(doc_id.as_bytes())?
+        .ok_or(DatabaseError::MissingDocument)?;
+
+    Ok(from_slice::<Value>(&document)?)
+}
+
+/// write a local document
+#[instrument(skip_all, fields(db_name = db_name, doc_id = doc_id))]
+pub fn write_local_document(
+    application_did: &Did,
+    db_name: &str,
+    doc_id: &str,
+    document: &Value,
+    config: &DbConfig,
+) -> DatabaseResult<()> {
+    let db = open_database(config, application_did, db_name)?;
+    db.open_tree("local")?
+        .insert(doc_id.as_bytes(), document.to_string().as_bytes())?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(rev: &str, deleted: bool) -> RevisionLeaf {
+        RevisionLeaf {
+            rev: rev.to_owned(),
+            path: vec![rev.to_owned()],
+            deleted,
+        }
+    }
+
+    fn stored_meta(db: &sled::Db, doc_id: &str) -> Value {
+        from_slice(&db.get(format!("{}_meta", doc_id)).unwrap().unwrap()).unwrap()
+    }
+
+    fn temporary_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn winner_does_not_depend_on_leaf_order() {
+        let leaves = [
+            leaf("2-aaa", false),
+            leaf("2-bbb", false),
+            leaf("3-ccc", true),
+            leaf("1-zzz", false),
+        ];
+        for rotation in 0..leaves.len() {
+            let mut shuffled = leaves.to_vec();
+            shuffled.rotate_left(rotation);
+            assert_eq!(winning_leaf(&shuffled).unwrap().rev, "2-bbb");
+            shuffled.reverse();
+            assert_eq!(winning_leaf(&shuffled).unwrap().rev, "2-bbb");
+        }
+    }
+
+    #[test]
+    fn deleted_leaf_only_wins_when_all_are_deleted() {
+        let leaves = [leaf("3-aaa", true), leaf("5-bbb", true)];
+        assert_eq!(winning_leaf(&leaves).unwrap().rev, "5-bbb");
+        assert!(winning_leaf(&[]).is_none());
+    }
+
+    #[test]
+    fn conflicting_writes_keep_both_bodies() {
+        let db = temporary_db();
+        let mut meta = json!({ "_did": "did:sam:root:user" });
+        save_revision(
+            &db,
+            "db",
+            "doc",
+            &mut meta,
+            vec![leaf("1-aaa", false)],
+            "1-aaa",
+            Some(b"a".to_vec()),
+        )
+        .unwrap();
+
+        let mut meta = stored_meta(&db, "doc");
+        let leaves = vec![leaf("1-aaa", false), leaf("1-bbb", false)];
+        save_revision(
+            &db,
+            "db",
+            "doc",
+            &mut meta,
+            leaves,
+            "1-bbb",
+            Some(b"b".to_vec()),
+        )
+        .unwrap();
+
+        let meta = stored_meta(&db, "doc");
+        assert_eq!(meta["_rev"], "1-bbb");
+        assert_eq!(meta["_leaves"].as_array().unwrap().len(), 2);
+        assert_eq!(db.get("doc").unwrap().unwrap(), b"b");
+        let conflicts = db.open_tree("conflicts").unwrap();
+        assert_eq!(
+            conflicts
+                .get(conflict_key("doc", "1-aaa"))
+                .unwrap()
+                .unwrap(),
+            b"a"
+        );
+        assert_eq!(conflicts.len(), 1);
+    }
+
+    #[test]
+    fn deleting_the_winner_promotes_the_conflict() {
+        let db = temporary_db();
+        let mut meta = json!({ "_did": "did:sam:root:user" });
+        save_revision(
+            &db,
+            "db",
+            "doc",
+            &mut meta,
+            vec![leaf("1-aaa", false)],
+            "1-aaa",
+            Some(b"a".to_vec()),
+        )
+        .unwrap();
+        let mut meta = stored_meta(&db, "doc");
+        let leaves = vec![leaf("1-aaa", false), leaf("1-bbb", false)];
+        save_revision(
+            &db,
+            "db",
+            "doc",
+            &mut meta,
+            leaves,
+            "1-bbb",
+            Some(b"b".to_vec()),
+        )
+        .unwrap();
+
+        let mut meta = stored_meta(&db, "doc");
+        let leaves = vec![leaf("1-aaa", false), leaf("2-ccc", true)];
+        save_revision(&db, "db", "doc", &mut meta, leaves, "2-ccc", None).unwrap();
+
+        let meta = stored_meta(&db, "doc");
+        assert_eq!(meta["_rev"], "1-aaa");
+        assert_eq!(meta["_deleted"], false);
+        assert_eq!(db.get("doc").unwrap().unwrap(), b"a");
+        assert!(db.open_tree("conflicts").unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_from_stale_metadata_is_refused() {
+        let db = temporary_db();
+        let mut meta = json!({ "_did": "did:sam:root:user" });
+        save_revision(
+            &db,
+            "db",
+            "doc",
+            &mut meta,
+            vec![leaf("1-aaa", false)],
+            "1-aaa",
+            Some(b"a".to_vec()),
+        )
+        .unwrap();
+
+        let stale = stored_meta(&db, "doc");
+        let mut meta = stale.clone();
+        save_revision(
+            &db,
+            "db",
+            "doc",
+            &mut meta,
+            vec![leaf("2-bbb", false)],
+            "2-bbb",
+            Some(b"b".to_vec()),
+        )
+        .unwrap();
+
+        let mut meta = stale;
+        let result = save_revision(
+            &db,
+            "db",
+            "doc",
+            &mut meta,
+            vec![leaf("2-ccc", false)],
+            "2-ccc",
+            Some(b"c".to_vec()),
+        );
+        assert!(matches!(result, Err(DatabaseError::DocumentUpdateConflict)));
+        assert_eq!(db.get("doc").unwrap().unwrap(), b"b");
+        // the refused write is not journaled either
+        assert_eq!(db.open_tree("journal").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn oldest_tombstones_are_pruned() {
+        let mut leaves = (1..=TOMBSTONE_LIMIT + 3)
+            .map(|generation| leaf(&format!("{}-aaa", generation), true))
+            .chain([leaf("1-bbb", false)])
+            .collect::<Vec<_>>();
+        prune_tombstones(&mut leaves);
+
+        assert_eq!(leaves.len(), TOMBSTONE_LIMIT + 1);
+        assert!(leaves.iter().any(|leaf| leaf.rev == "1-bbb"));
+        assert!(!leaves
+            .iter()
+            .any(|leaf| ["1-aaa", "2-aaa", "3-aaa"].contains(&leaf.rev.as_str())));
+
+        // the winning tombstone of a deleted document is never pruned
+        let mut leaves = (1..=TOMBSTONE_LIMIT + 3)
+            .map(|generation| leaf(&format!("{}-aaa", generation), true))
+            .collect::<Vec<_>>();
+        prune_tombstones(&mut leaves);
+        assert!(leaves
+            .iter()
+            .any(|leaf| leaf.rev == format!("{}-aaa", TOMBSTONE_LIMIT + 3)));
+        assert_eq!(leaves.len(), TOMBSTONE_LIMIT + 1);
+    }
+
+    /// write a revision of a document over its current one
+    fn write(db: &sled::Db, doc_id: &str, rev: &str, body: &[u8]) {
+        let mut meta = db.get(format!("{}_meta", doc_id)).unwrap().map_or_else(
+            || json!({ "_did": "did:sam:root:user" }),
+            |meta| from_slice(&meta).unwrap(),
+        );
+        save_revision(
+            db,
+            "db",
+            doc_id,
+            &mut meta,
+            vec![leaf(rev, false)],
+            rev,
+            Some(body.to_vec()),
+        )
+        .unwrap();
+    }
+
+    /// a database whose journal holds three writes of two documents
+    fn journaled_db() -> sled::Db {
+        let db = temporary_db();
+        write(&db, "a", "1-aaa", b"a1");
+        write(&db, "b", "1-bbb", b"b1");
+        write(&db, "a", "2-ccc", b"a2");
+        db
+    }
+
+    fn entry(db: &sled::Db, seq: u64) -> Value {
+        from_slice(
+            &db.open_tree("journal")
+                .unwrap()
+                .get(seq.to_be_bytes())
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn put_entry(db: &sled::Db, seq: u64, entry: &Value) {
+        db.open_tree("journal")
+            .unwrap()
+            .insert(seq.to_be_bytes(), serde_json::to_vec(entry).unwrap())
+            .unwrap();
+    }
+
+    fn broken(db: &sled::Db) -> Value {
+        check_journal(db, "db", "app/db", None).unwrap()["broken"].clone()
+    }
+
+    #[test]
+    fn journal_links_every_write() {
+        let db = journaled_db();
+        let report = check_journal(&db, "db", "app/db", None).unwrap();
+        assert_eq!(report["ok"], true);
+        assert_eq!(report["verified"], 3);
+        assert_eq!(report["head"], entry(&db, 2)["hash"]);
+
+        assert_eq!(entry(&db, 0)["prev"], journal::GENESIS);
+        assert_eq!(entry(&db, 1)["prev"], entry(&db, 0)["hash"]);
+        assert_eq!(entry(&db, 2)["rev"], "2-ccc");
+        assert_eq!(entry(&db, 2)["body_hash"], journal::sha256(b"a2"));
+        let head = db.open_tree("journal_head").unwrap().get(JOURNAL_HEAD);
+        assert_eq!(
+            from_slice::<Value>(&head.unwrap().unwrap()).unwrap(),
+            entry(&db, 2)
+        );
+    }
+
+    #[test]
+    fn broken_links_are_reported() {
+        let db = journaled_db();
+        let mut altered = entry(&db, 1);
+        altered["rev"] = "1-zzz".into();
+        put_entry(&db, 1, &altered);
+        assert_eq!(broken(&db), json!({ "seq": 1, "reason": "entry altered" }));
+
+        let db = journaled_db();
+        let mut renumbered = entry(&db, 1);
+        renumbered["seq"] = 5.into();
+        renumbered["hash"] = journal::entry_hash(&renumbered).into();
+        put_entry(&db, 1, &renumbered);
+        assert_eq!(
+            broken(&db),
+            json!({ "seq": 1, "reason": "sequence number altered" })
+        );
+
+        let db = journaled_db();
+        let mut relinked = entry(&db, 1);
+        relinked["prev"] = journal::GENESIS.into();
+        relinked["hash"] = journal::entry_hash(&relinked).into();
+        put_entry(&db, 1, &relinked);
+        assert_eq!(
+            broken(&db),
+            json!({ "seq": 1, "reason": "not linked to the previous entry" })
+        );
+
+        let db = journaled_db();
+        db.open_tree("journal")
+            .unwrap()
+            .remove(1u64.to_be_bytes())
+            .unwrap();
+        assert_eq!(broken(&db), json!({ "seq": 1, "reason": "entry missing" }));
+
+        let db = journaled_db();
+        db.open_tree("journal")
+            .unwrap()
+            .insert(1u64.to_be_bytes(), b"not json".to_vec())
+            .unwrap();
+        assert_eq!(
+            broken(&db),
+            json!({ "seq": 1, "reason": "entry unreadable" })
+        );
+
+        // dropping the last entries is seen through the checkpoints
+        let db = journaled_db();
+        let checkpoint = json!({ "seq": 2, "hash": entry(&db, 2)["hash"] });
+        db.open_tree("journal_checkpoints")
+            .unwrap()
+            .insert(2u64.to_be_bytes(), serde_json::to_vec(&checkpoint).unwrap())
+            .unwrap();
+        db.open_tree("journal")
+            .unwrap()
+            .remove(2u64.to_be_bytes())
+            .unwrap();
+        assert_eq!(broken(&db), json!({ "seq": 2, "reason": "entry missing" }));
+    }
+
+    #[test]
+    fn documents_must_match_their_journaled_revision() {
+        let db = journaled_db();
+        db.insert("b", b"b2".to_vec()).unwrap();
+        assert_eq!(
+            broken(&db),
+            json!({ "seq": 1, "reason": "document altered since the entry", "doc_id": "b" })
+        );
+
+        let db = journaled_db();
+        let mut meta = stored_meta(&db, "a");
+        meta["_rev"] = "3-zzz".into();
+        db.insert("a_meta", meta.to_string().as_bytes()).unwrap();
+        assert_eq!(
+            broken(&db),
+            json!({ "seq": null, "reason": "winning revision not journaled", "doc_id": "a" })
+        );
+
+        // documents last written before the journal began are not covered
+        let db = temporary_db();
+        db.insert(
+            "old_meta",
+            json!({ "_rev": "1-old", "_seq": 0 }).to_string().as_bytes(),
+        )
+        .unwrap();
+        db.insert("old", b"old".to_vec()).unwrap();
+        write(&db, "a", "1-aaa", b"a1");
+        assert_eq!(broken(&db), Value::Null);
+    }
+
+    #[test]
+    fn checkpoints_are_signed_by_the_account_of_the_node() {
+        let signer = journal::Signer::from_mnemonic(
+            "bottom drive obey lake curtain smoke basket hold race lonely fit walk",
+        )
+        .unwrap();
+        let db = journaled_db();
+        let head = entry(&db, 2);
+        let checkpoint = json!({
+            "seq": 2,
+            "hash": head["hash"],
+            "signer": signer.address(),
+            "signature": signer.sign(&journal::checkpoint_message(
+                "app/db",
+                2,
+                head["hash"].as_str().unwrap()
+            )),
+        });
+        let address = signer.address();
+        assert_eq!(
+            checkpoint_fault("app/db", &head, &checkpoint, Some(&address)),
+            None
+        );
+
+        assert_eq!(
+            checkpoint_fault(
+                "app/db",
+                &head,
+                &checkpoint,
+                Some("5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty")
+            ),
+            Some("checkpoint signed by another account")
+        );
+        assert_eq!(
+            checkpoint_fault("app/other", &head, &checkpoint, Some(&address)),
+            Some("checkpoint signature invalid")
+        );
+        assert_eq!(
+            checkpoint_fault("app/db", &entry(&db, 1), &checkpoint, Some(&address)),
+            Some("checkpoint does not match the entry")
+        );
+
+        db.open_tree("journal_checkpoints")
+            .unwrap()
+            .insert(2u64.to_be_bytes(), serde_json::to_vec(&checkpoint).unwrap())
+            .unwrap();
+        let report = check_journal(&db, "db", "app/db", Some(&address)).unwrap();
+        assert_eq!(report["ok"], true);
+        assert_eq!(report["checkpointed"], 2);
+    }
+}
@@ -0,0 +1,414 @@
+/// Copyright (c) Algorealm, Inc.
+use async_std::sync::Mutex;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use reqwest::{Client, Url};
+use rocket::futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{config, db, logging, prelude::*, replicator::Peer, util};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A node as it advertises itself to the other nodes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Node {
+    pub id: String,
+    /// URL the other nodes reach the node at
+    pub url: String,
+    /// `Server` header of the node, with its version
+    pub server: String,
+    /// applications syncing with the nodes they discover, by DID, with the proof that the node
+    /// holds the key shared by the nodes of the application
+    pub apps: BTreeMap<String, String>,
+    /// when the node was last heard of, directly or through another node
+    pub seen_at: u64,
+}
+
+/// Gossip exchanged by two nodes, holding the sender and the nodes it knows
+#[derive(Serialize, Deserialize)]
+pub struct Gossip {
+    pub node: Node,
+    pub nodes: Vec<Node>,
+}
+
+/// Nodes found on the network, and the advertisement of this node once it is listening
+#[derive(Clone, Default)]
+pub struct Discovery {
+    nodes: Arc<Mutex<HashMap<String, Node>>>,
+    this: Arc<Mutex<Option<Node>>>,
+}
+
+/// Check whether this node advertises itself and looks for other nodes
+pub fn is_enabled() -> bool {
+    config::get().discovery.enabled
+}
+
+/// nodes gossiped with besides those already known, from the `seeds` of the config file
+fn seeds() -> Vec<String> {
+    config::get()
+        .discovery
+        .seeds
+        .split(',')
+        .map(|url| url.trim().trim_end_matches('/').to_owned())
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+/// URL the other nodes reach this node at, by default the address and port it listens on
+pub fn advertised_url(config: &rocket::Config) -> String {
+    match config::get().discovery.url.as_str() {
+        url if !url.is_empty() => url.trim_end_matches('/').to_owned(),
+        _ => {
+            let address = match config.address {
+                address if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                address => address,
+            };
+            format!("http://{}", SocketAddr::new(address, config.port))
+        }
+    }
+}
+
+/// keyed hash of a message with the key shared by the nodes of an application
+fn mac(key: &str, message: &str) -> Option<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).ok()?;
+    mac.update(message.as_bytes());
+    Some(mac)
+}
+
+fn sign(key: &str, message: &str) -> String {
+    mac(key, message)
+        .map(|mac| URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+        .unwrap_or_default()
+}
+
+fn verify(key: &str, message: &str, signature: &str) -> bool {
+    match (mac(key, message), URL_SAFE_NO_PAD.decode(signature)) {
+        (Some(mac), Ok(signature)) => mac.verify_slice(&signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// Proof that a node holds the key of an application.
+/// It is bound to the URL of the node, so that another node cannot pass itself off as it.
+fn proof(key: &str, id: &str, url: &str) -> String {
+    sign(key, &format!("node:{}:{}", id, url))
+}
+
+/// check that a node holds the key of an application
+fn holds_key(node: &Node, application_did: &Did, key: &str) -> bool {
+    node.apps
+        .get(&application_did.0)
+        .is_some_and(|proof| verify(key, &format!("node:{}:{}", node.id, node.url), proof))
+}
+
+/// Password a node found through discovery replicates the databases of an application with.
+/// It only opens the replication routes of that node, so the key itself never leaves the nodes
+/// of the application.
+pub fn peer_credential(key: &str, id: &str) -> String {
+    sign(key, &format!("peer:{}", id))
+}
+
+/// check the password of an application on this node, given by a node found through discovery
+pub fn verify_peer_credential(key: &str, id: &str, credential: &str) -> bool {
+    verify(key, &format!("peer:{}", id), credential)
+}
+
+/// node advertised by an mDNS service
+fn from_service(info: &ServiceInfo) -> Option<Node> {
+    let properties = info.get_properties();
+    Some(Node {
+        id: properties.get("id")?.val_str().to_owned(),
+        url: properties.get("url")?.val_str().to_owned(),
+        server: properties
+            .get("server")
+            .map(|server| server.val_str().to_owned())
+            .unwrap_or_default(),
+        apps: properties
+            .iter()
+            .filter(|property| property.key().starts_with("did:"))
+            .map(|property| (property.key().to_owned(), property.val_str().to_owned()))
+            .collect(),
+        seen_at: util::get_unix_epoch_time(),
+    })
+}
+
+/// Advertise this node with mDNS, replacing its previous advertisement
+fn advertise(daemon: &ServiceDaemon, node: &Node) -> Result<(), mdns_sd::Error> {
+    let port = Url::parse(&node.url)
+        .ok()
+        .and_then(|url| url.port_or_known_default())
+        .unwrap_or_default();
+
+    let mut properties = HashMap::from([
+        ("id".to_owned(), node.id.clone()),
+        ("url".to_owned(), node.url.clone()),
+        ("server".to_owned(), node.server.clone()),
+    ]);
+    properties.extend(node.apps.clone());
+
+    let info = ServiceInfo::new(
+        DISCOVERY_SERVICE_TYPE,
+        &node.id,
+        &format!("{}.local.", node.id),
+        "",
+        port,
+        properties,
+    )?
+    .enable_addr_auto();
+
+    daemon.register(info)
+}
+
+impl Discovery {
+    /// nodes found that hold the key of an application
+    pub async fn nodes(&self, config: &DbConfig, application_did: &Did) -> Vec<Node> {
+        let keys = db::discovery_keys(config).unwrap_or_default();
+        let Some((_, key)) = keys.iter().find(|(did, _)| did == application_did) else {
+            return Vec::new();
+        };
+
+        self.nodes
+            .lock()
+            .await
+            .values()
+            .filter(|node| holds_key(node, application_did, key))
+            .cloned()
+            .collect()
+    }
+
+    /// URLs of the nodes found
+    #[cfg(feature = "p2p")]
+    pub async fn urls(&self) -> Vec<String> {
+        self.nodes
+            .lock()
+            .await
+            .values()
+            .map(|node| node.url.clone())
+            .collect()
+    }
+
+    /// Return the peers the databases of the applications are replicated with among the nodes found.
+    /// Peers authenticate with the peer credential of the application on their node.
+    pub async fn peers(&self, config: &DbConfig) -> Vec<Peer> {
+        let keys = db::discovery_keys(config).unwrap_or_default();
+        let nodes = self.nodes.lock().await;
+
+        let mut peers = Vec::new();
+        for (application_did, key) in &keys {
+            for node in nodes
+                .values()
+                .filter(|node| holds_key(node, application_did, key))
+            {
+                let Ok(mut url) = Url::parse(&node.url) else {
+                    continue;
+                };
+                if url.set_username(application_did.address()).is_err()
+                    || url
+                        .set_password(Some(&peer_credential(key, &node.id)))
+                        .is_err()
+                {
+                    continue;
+                }
+
+                peers.push(Peer {
+                    name: format!("discovered-{}", node.id),
+                    url,
+                });
+            }
+        }
+
+        peers
+    }
+
+    /// Record the nodes heard of that prove they hold the key of an application syncing on this
+    /// node, keeping the latest news of each. Proofs that do not check out are dropped, and news
+    /// proving fewer applications than those known of a node do not replace them, so that
+    /// gossip cannot take a node out of the applications it syncs.
+    async fn record(&self, config: &DbConfig, nodes: impl IntoIterator<Item = Node>) {
+        let keys = db::discovery_keys(config).unwrap_or_default();
+        let this = self.this.lock().await.as_ref().map(|node| node.id.clone());
+        let now = util::get_unix_epoch_time();
+
+        let mut known = self.nodes.lock().await;
+        for mut node in nodes {
+            if this.as_ref() == Some(&node.id) {
+                continue;
+            }
+
+            let proven = keys
+                .iter()
+                .filter(|(application_did, key)| holds_key(&node, application_did, key))
+                .map(|(application_did, _)| application_did.0.clone())
+                .collect::<Vec<_>>();
+            node.apps
+                .retain(|application_did, _| proven.contains(application_did));
+            if node.apps.is_empty() {
+                continue;
+            }
+
+            // news from the future are as recent as can be
+            node.seen_at = node.seen_at.min(now);
+            match known.get(&node.id) {
+                Some(existing)
+                    if existing.seen_at >= node.seen_at
+                        || keys.iter().any(|(application_did, key)| {
+                            holds_key(existing, application_did, key)
+                                && !node.apps.contains_key(&application_did.0)
+                        }) => {}
+                None if known.len() >= DISCOVERY_MAX_NODES => {}
+                _ => {
+                    known.insert(node.id.clone(), node);
+                }
+            }
+        }
+    }
+
+    /// Answer the gossip of another node with this node and the nodes it knows
+    pub async fn gossip(&self, config: &DbConfig, gossip: Gossip) -> Option<Gossip> {
+        let this = self.this.lock().await.clone()?;
+
+        let mut sender = gossip.node;
+        sender.seen_at = util::get_unix_epoch_time();
+        self.record(config, gossip.nodes.into_iter().chain([sender]))
+            .await;
+
+        Some(Gossip {
+            node: this,
+            nodes: self.nodes.lock().await.values().cloned().collect(),
+        })
+    }
+
+    /// Gossip with the nodes known and the seeds at once, forgetting nodes that were not heard of
+    /// for a while
+    async fn exchange(&self, config: &DbConfig, this: &Node) {
+        let now = util::get_unix_epoch_time();
+        let known = {
+            let mut nodes = self.nodes.lock().await;
+            nodes.retain(|_, node| node.seen_at + DISCOVERY_NODE_TTL > now);
+            nodes.values().cloned().collect::<Vec<_>>()
+        };
+
+        let mut urls = known
+            .iter()
+            .map(|node| node.url.clone())
+            .collect::<Vec<_>>();
+        urls.extend(seeds());
+        urls.sort();
+        urls.dedup();
+        urls.retain(|url| *url != this.url);
+
+        let Ok(client) = Client::builder()
+            .timeout(Duration::from_secs(DISCOVERY_GOSSIP_INTERVAL))
+            .build()
+        else {
+            return;
+        };
+
+        let gossip = Gossip {
+            node: this.clone(),
+            nodes: known,
+        };
+        let replies = join_all(urls.iter().map(|url| {
+            let (client, gossip) = (&client, &gossip);
+            async move {
+                let response = client
+                    .post(format!("{}/_discovery/gossip", url))
+                    .json(gossip)
+                    .send()
+                    .await
+                    .ok()?;
+                response.json::<Gossip>().await.ok()
+            }
+        }))
+        .await;
+
+        for mut reply in replies.into_iter().flatten() {
+            reply.node.seen_at = now;
+            self.record(config, reply.nodes.into_iter().chain([reply.node]))
+                .await;
+        }
+    }
+
+    /// Look for nodes advertised with mDNS, returning the daemon advertising this node
+    fn browse(&self, config: &DbConfig) -> Result<ServiceDaemon, mdns_sd::Error> {
+        let daemon = ServiceDaemon::new()?;
+        let events = daemon.browse(DISCOVERY_SERVICE_TYPE)?;
+
+        let (discovery, config) = (self.clone(), config.clone());
+        tokio::spawn(async move {
+            while let Ok(event) = events.recv_async().await {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => {
+                        if let Some(node) = from_service(&info) {
+                            discovery.record(&config, [node]).await;
+                        }
+                    }
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        discovery.nodes.lock().await.retain(|id, _| {
+                            fullname != format!("{}.{}", id, DISCOVERY_SERVICE_TYPE)
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Ok(daemon)
+    }
+
+    /// Advertise this node and look for other nodes, with mDNS unless it is turned off
+    /// and by gossiping with the nodes known
+    pub async fn run(self, config: DbConfig, url: String) {
+        let Ok(id) = db::node_id(&config) else {
+            logging::error("Could not read the ID of the node, discovery is turned off.");
+            return;
+        };
+
+        let mdns = match config::get().discovery.mdns {
+            false => None,
+            true => match self.browse(&config) {
+                Ok(daemon) => Some(daemon),
+                Err(e) => {
+                    logging::error(format!("Could not start mDNS discovery: {}", e));
+                    None
+                }
+            },
+        };
+
+        let mut advertised = None;
+        loop {
+            let this = Node {
+                id: id.clone(),
+                url: url.clone(),
+                server: util::server_header(&config.version),
+                apps: db::discovery_keys(&config)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(application_did, key)| (application_did.0, proof(&key, &id, &url)))
+                    .collect(),
+                seen_at: util::get_unix_epoch_time(),
+            };
+            *self.this.lock().await = Some(this.clone());
+
+            // the advertisement changes with the applications syncing through discovery
+            if let Some(daemon) = &mdns {
+                if advertised.as_ref() != Some(&this.apps) && advertise(daemon, &this).is_ok() {
+                    advertised = Some(this.apps.clone());
+                }
+            }
+
+            self.exchange(&config, &this).await;
+
+            tokio::time::sleep(Duration::from_secs(DISCOVERY_GOSSIP_INTERVAL)).await;
+        }
+    }
+}
@@ -0,0 +1,280 @@
+/// Copyright (c) Algorealm, Inc.
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use hmac::{Hmac, Mac};
+use rocket::serde::json::{
+    serde_json::{self, from_slice, json, Map},
+    Value,
+};
+use sha2::Sha256;
+
+use crate::{db, prelude::*, util};
+
+/// length of the nonce prepended to ciphertexts
+const NONCE_LEN: usize = 24;
+
+/// Encrypt data, prepending the random nonce used to the ciphertext
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> DatabaseResult<Vec<u8>> {
+    encrypt_with_nonce(
+        key,
+        &XChaCha20Poly1305::generate_nonce(&mut OsRng),
+        plaintext,
+    )
+}
+
+/// Encrypt data with a given nonce, prepending it to the ciphertext
+fn encrypt_with_nonce(key: &[u8; 32], nonce: &XNonce, plaintext: &[u8]) -> DatabaseResult<Vec<u8>> {
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(nonce, plaintext)
+        .map_err(|_| DatabaseError::OtherError)?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Decrypt data produced by `encrypt`
+pub fn decrypt(key: &[u8; 32], data: &[u8]) -> DatabaseResult<Vec<u8>> {
+    if data.len() < NONCE_LEN {
+        return Err(DatabaseError::OtherError);
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| DatabaseError::OtherError)
+}
+
+/// Return the data key of a subject (a DID or a database) within an application.
+/// Data keys are stored wrapped by the master key and only created on write.
+fn data_key(
+    config: &DbConfig,
+    master_key: &[u8; 32],
+    application_did: &Did,
+    subject: &str,
+    create: bool,
+) -> DatabaseResult<[u8; 32]> {
+    let wrapped = match db::fetch_data_key(config, application_did, subject)? {
+        Some(wrapped) => wrapped,
+        None if create => {
+            let wrapped = encrypt(master_key, &rand::random::<[u8; 32]>())?;
+            db::store_data_key(config, application_did, subject, &wrapped)?
+        }
+        None => return Err(DatabaseError::DataKeyShredded),
+    };
+
+    decrypt(master_key, &wrapped)?
+        .try_into()
+        .map_err(|_| DatabaseError::OtherError)
+}
+
+/// subject of the key shared by the fields of a database
+fn database_subject(db_name: &str) -> String {
+    format!("db:{}", db_name)
+}
+
+/// Check whether a value is an encrypted field
+fn is_sealed_field(value: &Value) -> bool {
+    value.get("_encrypted").is_some()
+}
+
+/// Encrypt the value of a field.
+/// Deterministic encryption derives the nonce from the value, so that equal values can be matched.
+fn seal_field(
+    key: &[u8; 32],
+    key_name: &str,
+    path: &str,
+    value: &Value,
+    deterministic: bool,
+) -> DatabaseResult<Value> {
+    let plaintext = serde_json::to_vec(value)?;
+    let ciphertext = if deterministic {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(key).map_err(|_| DatabaseError::OtherError)?;
+        mac.update(path.as_bytes());
+        mac.update(&plaintext);
+        let digest = mac.finalize().into_bytes();
+        encrypt_with_nonce(key, XNonce::from_slice(&digest[..NONCE_LEN]), &plaintext)?
+    } else {
+        encrypt(key, &plaintext)?
+    };
+
+    Ok(json!({
+        "_encrypted": STANDARD.encode(ciphertext),
+        "key": key_name,
+    }))
+}
+
+/// Encrypt the fields of a document listed in the policy of its database
+pub fn encrypt_fields(
+    config: &DbConfig,
+    application_did: &Did,
+    db_name: &str,
+    did: &Did,
+    document: &mut Value,
+) -> DatabaseResult<()> {
+    let policy = match db::encryption_policy(config, application_did, db_name)? {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+    let master_key = config
+        .master_key
+        .as_ref()
+        .ok_or(DatabaseError::OtherError)?;
+
+    let (subject, key_name) = match policy.key {
+        FieldKey::Database => (database_subject(db_name), "database".to_owned()),
+        FieldKey::Did => (did.0.clone(), did.0.clone()),
+    };
+    let key = data_key(config, master_key, application_did, &subject, true)?;
+
+    for path in &policy.fields {
+        if let Some(value) = document.pointer_mut(&util::json_pointer(path)) {
+            // fields kept from the previous revision are already encrypted
+            if !is_sealed_field(value) {
+                *value = seal_field(&key, &key_name, path, value, policy.deterministic)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Decrypt the encrypted fields of a document the reader is allowed to see.
+/// `can_decrypt` receives the DID owning the key of a field, if it is not the database key.
+/// Fields the reader may not see, or whose key was destroyed, stay encrypted.
+pub fn decrypt_fields(
+    config: &DbConfig,
+    application_did: &Did,
+    db_name: &str,
+    document: &mut Value,
+    can_decrypt: &impl Fn(Option<&Did>) -> bool,
+) {
+    if !is_sealed_field(document) {
+        match document {
+            Value::Object(fields) => fields.values_mut().for_each(|value| {
+                decrypt_fields(config, application_did, db_name, value, can_decrypt)
+            }),
+            Value::Array(values) => values.iter_mut().for_each(|value| {
+                decrypt_fields(config, application_did, db_name, value, can_decrypt)
+            }),
+            _ => {}
+        }
+        return;
+    }
+
+    let key_name = document["key"].as_str().unwrap_or_default().to_owned();
+    let owner = (key_name != "database").then(|| Did(key_name.clone()));
+    if !can_decrypt(owner.as_ref()) {
+        return;
+    }
+
+    let subject = match owner {
+        Some(did) => did.0,
+        None => database_subject(db_name),
+    };
+    let plaintext = config.master_key.as_ref().and_then(|master_key| {
+        let key = data_key(config, master_key, application_did, &subject, false).ok()?;
+        let ciphertext = STANDARD
+            .decode(document["_encrypted"].as_str().unwrap_or_default())
+            .ok()?;
+        from_slice::<Value>(&decrypt(&key, &ciphertext).ok()?).ok()
+    });
+
+    if let Some(plaintext) = plaintext {
+        *document = plaintext;
+    }
+}
+
+/// Encrypt the values a query matches on encrypted fields with, so they can be compared to stored
+/// values. This is only possible when the fields are encrypted deterministically.
+pub fn seal_selector(
+    config: &DbConfig,
+    application_did: &Did,
+    db_name: &str,
+    selector: &mut Map<String, Value>,
+) -> DatabaseResult<()> {
+    let policy = match db::encryption_policy(config, application_did, db_name)? {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+
+    for (path, value) in selector.iter_mut() {
+        if !policy.fields.contains(path) {
+            continue;
+        }
+
+        if !policy.deterministic {
+            return Err(DatabaseError::EncryptedFieldQuery);
+        }
+
+        let master_key = config
+            .master_key
+            .as_ref()
+            .ok_or(DatabaseError::OtherError)?;
+        let key = data_key(
+            config,
+            master_key,
+            application_did,
+            &database_subject(db_name),
+            true,
+        )?;
+        *value = seal_field(&key, "database", path, value, true)?;
+    }
+
+    Ok(())
+}
+
+/// Serialize a document for storage, encrypting it with the data key of its owner
+/// when encryption at rest is enabled
+pub fn seal_document(
+    config: &DbConfig,
+    application_did: &Did,
+    did: &Did,
+    document: &Value,
+) -> DatabaseResult<Vec<u8>> {
+    let master_key = match &config.master_key {
+        Some(master_key) => master_key,
+        None => return Ok(document.to_string().into_bytes()),
+    };
+
+    let key = data_key(config, master_key, application_did, &did.0, true)?;
+    let ciphertext = encrypt(&key, &serde_json::to_vec(document)?)?;
+
+    Ok(json!({
+        "_envelope": {
+            "did": did.0,
+            "ciphertext": STANDARD.encode(ciphertext),
+        }
+    })
+    .to_string()
+    .into_bytes())
+}
+
+/// Deserialize a stored document, decrypting it if it was sealed in an envelope
+pub fn open_document(
+    config: &DbConfig,
+    application_did: &Did,
+    stored: &[u8],
+) -> DatabaseResult<Value> {
+    let document = from_slice::<Value>(stored)?;
+    let envelope = match document.get("_envelope") {
+        Some(envelope) => envelope,
+        // documents written while encryption was disabled are stored in plaintext
+        None => return Ok(document),
+    };
+
+    let master_key = config
+        .master_key
+        .as_ref()
+        .ok_or(DatabaseError::OtherError)?;
+    let did = Did(envelope["did"].as_str().unwrap_or_default().to_owned());
+    let ciphertext = STANDARD
+        .decode(envelope["ciphertext"].as_str().unwrap_or_default())
+        .map_err(|_| DatabaseError::OtherError)?;
+
+    let key = data_key(config, master_key, application_did, &did.0, false)?;
+
+    Ok(from_slice::<Value>(&decrypt(&key, &ciphertext)?)?)
+}
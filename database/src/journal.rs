@@ -0,0 +1,190 @@
+/// Copyright (c) Algorealm, Inc.
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bip39::{Language, Mnemonic};
+use blake2::{Blake2b512, Digest};
+use rocket::serde::json::{serde_json::json, Value};
+use schnorrkel::{signing_context, ExpansionMode, Keypair, PublicKey, Signature};
+use sha2::Sha256;
+
+/// hash the first entry of a journal is linked to
+pub static GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// context of the signatures made with substrate keys
+const SIGNING_CONTEXT: &[u8] = b"substrate";
+
+/// SS58 prefix of the addresses of the chain
+const SS58_PREFIX: u8 = 42;
+
+/// Hex-encoded SHA-256 digest of some data
+pub fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Hash of a journal entry, covering all its fields but the hash itself
+pub fn entry_hash(entry: &Value) -> String {
+    let fields = json!([
+        entry["seq"],
+        entry["at"],
+        entry["op"],
+        entry["db_name"],
+        entry["doc_id"],
+        entry["rev"],
+        entry["did"],
+        entry["body_hash"],
+        entry["prev"],
+    ]);
+
+    sha256(fields.to_string().as_bytes())
+}
+
+/// Message signed by a checkpoint of the journal of a database, up to the entry `seq`
+pub fn checkpoint_message(database: &str, seq: u64, hash: &str) -> Vec<u8> {
+    json!([database, seq, hash]).to_string().into_bytes()
+}
+
+/// Key of the chain account of the application, signing the checkpoints of the journals
+pub struct Signer(Keypair);
+
+impl Signer {
+    /// Derive the sr25519 key of an account from its mnemonic, the way substrate does
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        let phrase = mnemonic
+            .split(|c: char| c.is_whitespace() || c == '\u{a0}')
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let entropy = Mnemonic::parse_in(Language::English, phrase)
+            .ok()?
+            .to_entropy();
+        let secret = substrate_bip39::mini_secret_from_entropy(&entropy, "").ok()?;
+
+        Some(Signer(secret.expand_to_keypair(ExpansionMode::Ed25519)))
+    }
+
+    /// SS58 address of the account
+    pub fn address(&self) -> String {
+        ss58_address(&self.0.public)
+    }
+
+    /// Sign a message, returning the base64 signature
+    pub fn sign(&self, message: &[u8]) -> String {
+        let signature = self.0.sign(signing_context(SIGNING_CONTEXT).bytes(message));
+        STANDARD.encode(signature.to_bytes())
+    }
+}
+
+/// checksum of an SS58 address
+fn ss58_checksum(payload: &[u8]) -> Vec<u8> {
+    let mut hasher = Blake2b512::new();
+    hasher.update(b"SS58PRE");
+    hasher.update(payload);
+    hasher.finalize()[..2].to_vec()
+}
+
+/// SS58 address of a public key
+fn ss58_address(public: &PublicKey) -> String {
+    let payload = [&[SS58_PREFIX][..], &public.to_bytes()].concat();
+    let checksum = ss58_checksum(&payload);
+    bs58::encode([payload, checksum].concat()).into_string()
+}
+
+/// public key of an SS58 address, if its checksum is valid
+fn ss58_public_key(address: &str) -> Option<PublicKey> {
+    let data = bs58::decode(address).into_vec().ok()?;
+    if data.len() != 35 || data[0] != SS58_PREFIX {
+        return None;
+    }
+
+    let (payload, checksum) = data.split_at(33);
+    if ss58_checksum(payload) != checksum {
+        return None;
+    }
+    PublicKey::from_bytes(&payload[1..]).ok()
+}
+
+/// Check a base64 signature of a message by the account of an SS58 address
+pub fn verify(address: &str, message: &[u8], signature: &str) -> bool {
+    let (Some(public), Some(signature)) = (
+        ss58_public_key(address),
+        STANDARD
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::from_bytes(&bytes).ok()),
+    ) else {
+        return false;
+    };
+
+    public
+        .verify(signing_context(SIGNING_CONTEXT).bytes(message), &signature)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MNEMONIC: &str = "bottom drive obey lake curtain smoke basket hold race lonely fit walk";
+
+    fn entry() -> Value {
+        json!({
+            "seq": 3,
+            "at": 1700000000,
+            "op": "write",
+            "db_name": "notes",
+            "doc_id": "doc-1",
+            "rev": "2-abc",
+            "did": "did:sam:root:user",
+            "body_hash": sha256(b"{}"),
+            "prev": GENESIS,
+        })
+    }
+
+    #[test]
+    fn entry_hash_covers_every_field_but_itself() {
+        let hash = entry_hash(&entry());
+        assert_eq!(hash.len(), 64);
+
+        let mut hashed = entry();
+        hashed["hash"] = hash.clone().into();
+        assert_eq!(entry_hash(&hashed), hash);
+
+        for field in [
+            "seq",
+            "at",
+            "op",
+            "db_name",
+            "doc_id",
+            "rev",
+            "did",
+            "body_hash",
+            "prev",
+        ] {
+            let mut altered = entry();
+            altered[field] = "altered".into();
+            assert_ne!(entry_hash(&altered), hash, "{}", field);
+        }
+    }
+
+    #[test]
+    fn checkpoints_verify_with_the_signing_account_only() {
+        let signer = Signer::from_mnemonic(MNEMONIC).unwrap();
+        assert_eq!(
+            signer.address(),
+            "5DfhGyQdFobKM8NsWvEeAKk5EQQgYe9AydgJ7rMB6E1EqRzV"
+        );
+
+        let message = checkpoint_message("app/notes", 3, &entry_hash(&entry()));
+        let signature = signer.sign(&message);
+        assert!(verify(&signer.address(), &message, &signature));
+
+        let other = checkpoint_message("app/notes", 4, &entry_hash(&entry()));
+        assert!(!verify(&signer.address(), &other, &signature));
+        assert!(!verify(
+            "5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty",
+            &message,
+            &signature
+        ));
+        assert!(!verify(&signer.address(), &message, "not a signature"));
+        assert!(Signer::from_mnemonic("not a mnemonic").is_none());
+    }
+}
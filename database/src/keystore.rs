@@ -0,0 +1,193 @@
+/// Copyright (c) Algorealm, Inc.
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use rocket::serde::json::{
+    serde_json::{self, json, Map},
+    Value,
+};
+use std::{env, fs, io, path::Path, sync::OnceLock};
+
+use crate::{config, prelude::*, util};
+
+/// Secrets moved from the config file into the keystore, as (section, key)
+const KEYSTORE_SECRETS: [(&str, &str); 1] = [("contract", "mnemonic")];
+
+/// Check whether a value of the config is kept in the keystore once it exists
+pub fn holds(section: &str, key: &str) -> bool {
+    KEYSTORE_SECRETS.contains(&(section, key))
+}
+
+/// path of the keystore file
+pub fn keystore_path() -> String {
+    config::get().data.keystore.clone()
+}
+
+/// Check whether the secrets have been moved into a keystore
+pub fn exists() -> bool {
+    Path::new(&keystore_path()).exists()
+}
+
+/// Read the material the keystore key is derived from.
+/// It comes from a key file or a passphrase set in the environment, or is asked for on stdin once.
+fn unlock_material() -> Result<Vec<u8>, GenericError> {
+    static MATERIAL: OnceLock<Vec<u8>> = OnceLock::new();
+    if let Some(material) = MATERIAL.get() {
+        return Ok(material.clone());
+    }
+
+    let material = if let Ok(key_file) = env::var(KEYSTORE_KEY_FILE_ENV) {
+        fs::read(key_file)?
+    } else if let Ok(passphrase) = env::var(KEYSTORE_PASSPHRASE_ENV) {
+        passphrase.into_bytes()
+    } else {
+        println!("Enter the keystore passphrase:");
+        let mut passphrase = String::new();
+        io::stdin().read_line(&mut passphrase)?;
+        passphrase
+            .trim_end_matches(['\r', '\n'])
+            .as_bytes()
+            .to_vec()
+    };
+
+    Ok(MATERIAL.get_or_init(|| material).clone())
+}
+
+/// Derive the encryption key of the keystore with Argon2
+fn derive_key(material: &[u8], salt: &[u8]) -> Result<[u8; 32], GenericError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(material, salt, &mut key)
+        .map_err(|e| e.to_string())?;
+
+    Ok(key)
+}
+
+/// Decrypt the secrets held in the keystore
+pub fn unlock() -> Result<Map<String, Value>, GenericError> {
+    let keystore: Value = serde_json::from_slice(&fs::read(keystore_path())?)?;
+    let field = |name: &str| -> Result<Vec<u8>, GenericError> {
+        Ok(STANDARD.decode(keystore[name].as_str().unwrap_or_default())?)
+    };
+
+    let key = derive_key(&unlock_material()?, &field("salt")?)?;
+    let plaintext = XChaCha20Poly1305::new(&key.into())
+        .decrypt(
+            XNonce::from_slice(&field("nonce")?),
+            field("ciphertext")?.as_ref(),
+        )
+        .map_err(|_| "could not decrypt the keystore, check the passphrase or key file")?;
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+/// Encrypt secrets into the keystore, replacing its content
+fn seal(secrets: &Map<String, Value>) -> Result<(), GenericError> {
+    let salt = rand::random::<[u8; 16]>();
+    let key = derive_key(&unlock_material()?, &salt)?;
+
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(&key.into())
+        .encrypt(&nonce, serde_json::to_vec(secrets)?.as_ref())
+        .map_err(|_| "could not encrypt the keystore")?;
+
+    let keystore = json!({
+        "version": 1,
+        "kdf": "argon2id",
+        "cipher": "xchacha20poly1305",
+        "salt": STANDARD.encode(salt),
+        "nonce": STANDARD.encode(nonce),
+        "ciphertext": STANDARD.encode(ciphertext),
+    });
+
+    fs::write(keystore_path(), serde_json::to_vec_pretty(&keystore)?)?;
+
+    Ok(())
+}
+
+/// Read a secret, from the keystore if there is one and from the config file otherwise
+pub fn read_secret(section: &str, key: &str) -> Result<String, GenericError> {
+    if !exists() {
+        return Ok(util::read_config(section, key).to_string());
+    }
+
+    Ok(unlock()?
+        .get(&format!("{}.{}", section, key))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_owned())
+}
+
+/// Read a secret from the keystore, storing the output of `generate` if it is not set yet
+pub fn read_or_create_secret(
+    section: &str,
+    key: &str,
+    generate: impl FnOnce() -> String,
+) -> Result<String, GenericError> {
+    let mut secrets = unlock()?;
+    let name = format!("{}.{}", section, key);
+
+    if let Some(secret) = secrets.get(&name).and_then(Value::as_str) {
+        return Ok(secret.to_owned());
+    }
+
+    let secret = generate();
+    secrets.insert(name, secret.clone().into());
+    seal(&secrets)?;
+
+    Ok(secret)
+}
+
+/// Move the secrets in the config file into the keystore
+pub fn migrate_config() -> Result<usize, GenericError> {
+    let mut secrets = if exists() { unlock()? } else { Map::new() };
+
+    let mut moved = 0;
+    for (section, key) in KEYSTORE_SECRETS {
+        let value = util::read_config(section, key);
+        if !value.is_empty() {
+            secrets.insert(format!("{}.{}", section, key), value.to_string().into());
+            moved += 1;
+        }
+    }
+
+    seal(&secrets)?;
+
+    // only remove the secrets from the config file once they are safely stored
+    for (section, key) in KEYSTORE_SECRETS {
+        if !util::remove_config(section, key) {
+            return Err(format!("could not remove `{}` from the config file", key).into());
+        }
+    }
+
+    Ok(moved)
+}
+
+/// Run a `keystore` subcommand
+pub fn run_command(args: &[String]) {
+    match args.first().map(String::as_str) {
+        Some("migrate") => match migrate_config() {
+            Ok(moved) => println!(
+                "Moved {} secret(s) from {} into the keystore at {}.",
+                moved,
+                config::path().display(),
+                keystore_path()
+            ),
+            Err(e) => {
+                println!("Could not migrate secrets into the keystore: {}", e);
+                std::process::exit(2);
+            }
+        },
+        _ => {
+            println!("Usage: samaritan-db keystore migrate");
+            println!(
+                "The keystore is unlocked with the key file in `{}`, the passphrase in `{}`, or a passphrase read from stdin.",
+                KEYSTORE_KEY_FILE_ENV, KEYSTORE_PASSPHRASE_ENV
+            );
+            std::process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+/// Copyright (c) Algorealm, Inc.
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    serde::json::{
+        serde_json::{json, Map},
+        Value,
+    },
+    Data, Request, Response,
+};
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::{Instant, SystemTime},
+};
+use time::{format_description::well_known::Rfc3339, Date, OffsetDateTime};
+
+use crate::{config, prelude::*};
+
+/// Importance of a message, from the most to the least important
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// level configured in `[log]`
+    fn configured() -> Level {
+        match config::get().log.level.as_str() {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "debug" => Level::Debug,
+            _ => Level::Info,
+        }
+    }
+}
+
+/// The log file being written
+struct LogFile {
+    path: String,
+    file: File,
+    size: u64,
+    /// day the file was started, for daily rotation
+    day: Date,
+}
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Open the log file, appending to it
+fn open(path: &str) -> Option<LogFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .ok()?;
+    let metadata = file.metadata().ok()?;
+    let started = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+
+    Some(LogFile {
+        path: path.to_owned(),
+        file,
+        size: metadata.len(),
+        day: OffsetDateTime::from(started).date(),
+    })
+}
+
+/// Move the log file aside, named after the time it was rotated, and start a new one
+fn rotate(log: LogFile, now: OffsetDateTime) -> Option<LogFile> {
+    let path = log.path.clone();
+    drop(log);
+
+    let rotated = format!(
+        "{}.{}-{:02}{:02}{:02}",
+        path,
+        now.date(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    let _ = fs::rename(&path, rotated);
+
+    open(&path)
+}
+
+/// Write a line to the log file, rotating it first when it is too large or from another day.
+/// Lines are printed instead when no log file is configured or it cannot be opened.
+fn write(line: &str, now: OffsetDateTime) {
+    let settings = config::get();
+    let path = settings.data.log.as_str();
+    if path.is_empty() {
+        println!("{}", line);
+        return;
+    }
+
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut log = match log_file.take() {
+        Some(log) if log.path == path => Some(log),
+        _ => open(path),
+    };
+
+    let length = line.len() as u64 + 1;
+    if let Some(current) = log.take() {
+        let too_large = settings.log.max_size > 0
+            && current.size > 0
+            && current.size + length > settings.log.max_size;
+        let stale = settings.log.daily && current.day != now.date();
+        log = if too_large || stale {
+            rotate(current, now)
+        } else {
+            Some(current)
+        };
+    }
+
+    match log.as_mut() {
+        Some(current) => match writeln!(current.file, "{}", line) {
+            Ok(_) => current.size += length,
+            Err(_) => println!("{}", line),
+        },
+        None => println!("{}", line),
+    }
+    *log_file = log;
+}
+
+/// Log a message with fields, as text or as a JSON line depending on the `format` of `[log]`
+pub fn log(level: Level, message: impl Display, fields: Value) {
+    if level > Level::configured() {
+        return;
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let time = now.format(&Rfc3339).unwrap_or_default();
+    let fields = match fields {
+        Value::Object(fields) => fields,
+        _ => Map::new(),
+    };
+
+    let line = if config::get().log.format == "json" {
+        let mut entry = Map::new();
+        entry.insert("time".to_owned(), json!(time));
+        entry.insert("level".to_owned(), json!(level.as_str()));
+        entry.insert("message".to_owned(), json!(message.to_string()));
+        entry.extend(fields);
+        Value::Object(entry).to_string()
+    } else {
+        let mut line = format!("{} {:5} {}", time, level.as_str().to_uppercase(), message);
+        for (key, value) in fields {
+            match value {
+                Value::String(value) => line.push_str(&format!(" {}={:?}", key, value)),
+                value => line.push_str(&format!(" {}={}", key, value)),
+            }
+        }
+        line
+    };
+
+    write(&line, now);
+}
+
+pub fn error(message: impl Display) {
+    log(Level::Error, message, Value::Null);
+}
+
+pub fn warn(message: impl Display) {
+    log(Level::Warn, message, Value::Null);
+}
+
+pub fn info(message: impl Display) {
+    log(Level::Info, message, Value::Null);
+}
+
+/// when the request was received
+struct RequestStart(Option<Instant>);
+
+/// Fairing logging every request with its status, latency and application
+pub struct RequestLog;
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let latency = request
+            .local_cache(|| RequestStart(None))
+            .0
+            .map(|start| start.elapsed().as_secs_f64() * 1000.0)
+            .unwrap_or_default();
+        let application = request.local_cache(|| RequestApplication(None));
+
+        log(
+            Level::Info,
+            "request",
+            json!({
+                "method": request.method().as_str(),
+                "path": request.uri().path().as_str(),
+                "status": response.status().code,
+                "latency_ms": (latency * 1000.0).round() / 1000.0,
+                "application_did": application.0.as_ref().map(|did| did.0.clone()),
+            }),
+        );
+    }
+}
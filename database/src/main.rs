@@ -3,52 +3,96 @@
 #[macro_use]
 extern crate rocket;
 
+mod cluster;
+mod config;
 mod contract;
+mod crdt;
 mod db;
+mod discovery;
+mod encryption;
+mod journal;
+mod keystore;
+mod logging;
+mod metrics;
+#[cfg(feature = "p2p")]
+mod p2p;
 mod prelude;
+mod replicator;
 mod routes;
 mod util;
 mod rpc;
+mod session;
+mod tasks;
+mod telemetry;
 
 use std::{sync::Arc, time::Duration};
 
 use async_std::sync::Mutex;
 use prelude::*;
-use rocket::{fairing::AdHoc, http::Header};
+use rocket::{fairing::AdHoc, http::Header, serde::json::json};
 
 /// Rocket serves as the main entry point to the database.
 /// It accepts the HTTP requests and then passes it into other components of the DB
 /// Other components are independent of rocket and gets fired up when the database is run e.g networking
 #[tokio::main]
 async fn main() -> Result<(), rocket::Error> {
+    // the config file is read once, from `--config` or `SAMDB_CONFIG` if set, and overridden by
+    // `SAMDB_<SECTION>__<KEY>` environment variables
+    let args = std::env::args().collect::<Vec<_>>();
+    let (config_path, args) = config::path_from_args(&args);
+    let settings = match config::load(config_path) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // subcommands run instead of the database
+    if args.get(1).map(String::as_str) == Some("keystore") {
+        keystore::run_command(&args[2..]);
+        return Ok(());
+    }
+
     // read config into state
-    let path = util::read_config("data", "path");
-    let log_ = util::read_config("data", "log");
-    let flush_interval = util::read_config("data", "flush_interval")
-        .parse::<u64>()
-        .unwrap_or(1000);
-    let cache_capacity = util::read_config("data", "path")
-        .parse::<u64>()
-        .unwrap_or(10_000_000_000);
-    let version = util::read_config("data", "version");
+    let version = settings.data.version.clone();
     let vsn = version.clone();
 
     // check for important config and refuse to start the database if the config is not set
     let mnemonic = util::check_start_config();
 
-    // TODO!
-    // The default values should not be "empty" but should be set to meaningful defaults
+    // load the master key if documents are to be encrypted at rest
+    let master_key = util::check_encryption_config();
 
     // set up the config struct
     let config = DbConfig {
-        path: path.into(),
-        log: log_.into(),
-        flush_interval,
-        cache_capacity,
-        version: version.into(),
-        mnemonic
+        path: settings.data.path.clone(),
+        log: settings.data.log.clone(),
+        flush_interval: settings.data.flush_interval,
+        cache_capacity: settings.data.cache_capacity,
+        version,
+        mnemonic,
+        master_key,
     };
 
+    // spans are exported to an OpenTelemetry collector if `[tracing]` is enabled
+    if let Err(e) = telemetry::init() {
+        println!("Could not set up tracing: {}", e);
+        std::process::exit(2);
+    }
+
+    // databases created before applications were namespaced are moved into their owner's namespace
+    if db::migrate_single_application(&config).is_err() {
+        println!("Could not migrate databases into application namespaces.");
+        std::process::exit(2);
+    }
+
+    // credentials of peers are kept in the root database rather than in the config file
+    if replicator::migrate_peer_credentials(&config).is_err() {
+        println!("Could not move the credentials of peers out of the config file.");
+        std::process::exit(2);
+    }
+
     // Queue containing list of DIDs whose data have just been written to the database
     let did_list = Arc::new(Mutex::new(DidQueue::new()));
 
@@ -57,19 +101,67 @@ async fn main() -> Result<(), rocket::Error> {
     let cfg = config.clone();
     let did_queue = did_list.clone();
     tokio::task::spawn(async move {
+        let task = tasks::start("did_verifier", json!({}));
+        // DIDs checked since the queue was last empty, for the progress of the task
+        let mut checked = 0;
         loop {
-            // check queue
-            let mut guard = did_queue.lock().await;
-            println!("{:#?}", guard);
+            // check queue, releasing it before the chain is queried so that writes are not held up
+            let (db_entry, pending) = {
+                let mut guard = did_queue.lock().await;
+                logging::log(
+                    logging::Level::Debug,
+                    "DID queue",
+                    json!({
+                        "pending": guard.len(),
+                        "next": guard.front().map(|entry| entry.did.0.clone())
+                    }),
+                );
+                (guard.pop_front(), guard.len() as u64)
+            };
+
+            checked = if db_entry.is_some() { checked + 1 } else { 0 };
+            let progress = match checked + pending {
+                0 => 100,
+                total => checked * 100 / total,
+            };
+            task.update(
+                Some(progress),
+                json!({
+                    "checking": db_entry.as_ref().map(|entry| entry.did.0.clone()),
+                    "checked": checked,
+                    "pending": pending
+                }),
+            );
 
-            if let Some(db_entry) = guard.pop_front() {
+            if let Some(db_entry) = db_entry {
                 // check the list of DIDs we have recorded and recognized
-                if util::read_config("identifiers", &db_entry.did.0).is_empty() {
+                if config::get().identifiers.contains_key(&db_entry.did.0) {
+                    metrics::did_verification("known");
+                } else {
                     // check the chain if the DID is recognized
                     if !contract::did_exists(&cfg, &db_entry.did).await {
+                        metrics::did_verification("missing");
+                        logging::log(
+                            logging::Level::Warn,
+                            "Removing a document written by a DID missing from the chain",
+                            json!({
+                                "did": db_entry.did.0,
+                                "db_name": db_entry.db_name,
+                                "doc_id": db_entry.doc_id
+                            }),
+                        );
+
                         // remove data in association to "fake" DID
-                        let _ = db::delete_document(&db_entry.db_name, &db_entry.doc_id, &cfg);
+                        let _ = db::delete_document(
+                            &db_entry.application_did,
+                            &db_entry.db_name,
+                            &db_entry.doc_id,
+                            None,
+                            &cfg,
+                        );
                     } else {
+                        metrics::did_verification("verified");
+
                         // write to config file
                         util::write_config("identifiers", &db_entry.did.0, "true");
                     }
@@ -77,28 +169,120 @@ async fn main() -> Result<(), rocket::Error> {
             }
 
             // sleep for some seconds
-            async_std::task::sleep(Duration::from_secs(DID_CLEANUP_SLEEP_TIME)).await;
+            let interval = config::get().contract.did_check_interval;
+            async_std::task::sleep(Duration::from_secs(interval)).await;
         }
     });
 
-    rocket::build()
+    // replications started through `_replicate` or kept alive with peers
+    let jobs = replicator::Jobs::default();
+
+    // nodes found on the network, whose databases can be kept replicated like those of peers
+    let discovery = discovery::Discovery::default();
+
+    // This task keeps every database replicated in both directions with the peers of its application
+    let cfg = config.clone();
+    let did_queue = did_list.clone();
+    let peer_jobs = jobs.clone();
+    let peer_discovery = discovery.clone();
+    tokio::task::spawn(async move {
+        let task = tasks::start("peer_sync", json!({}));
+        loop {
+            replicator::sync_peers(&cfg, &did_queue, &peer_jobs, &peer_discovery).await;
+            task.update(
+                None,
+                json!({ "replications": peer_jobs.lock().await.len() }),
+            );
+
+            // sleep for some seconds
+            async_std::task::sleep(Duration::from_secs(PEER_SYNC_INTERVAL)).await;
+        }
+    });
+
+    // the heads of the journals of the databases are signed with the key of the application
+    let cfg = config.clone();
+    tokio::task::spawn(async move {
+        let Some(signer) = journal::Signer::from_mnemonic(&cfg.mnemonic) else {
+            logging::log(
+                logging::Level::Warn,
+                "Journals are not checkpointed, as no key can be derived from the mnemonic",
+                json!({}),
+            );
+            return;
+        };
+
+        let task = tasks::start("journal_checkpoints", json!({ "signer": signer.address() }));
+        let mut checkpoints = 0;
+        loop {
+            match db::checkpoint_journals(&cfg, &signer) {
+                Ok(made) => checkpoints += made,
+                Err(e) => logging::log(
+                    logging::Level::Error,
+                    "Could not checkpoint the journals",
+                    json!({ "error": format!("{:?}", e) }),
+                ),
+            }
+            task.update(
+                None,
+                json!({ "signer": signer.address(), "checkpoints": checkpoints }),
+            );
+
+            // sleep for some seconds
+            async_std::task::sleep(Duration::from_secs(JOURNAL_CHECKPOINT_INTERVAL)).await;
+        }
+    });
+
+    // the config file is read again when it changes, applying the settings that can change live
+    tokio::task::spawn(config::watch());
+
+    // nodes can also sync over libp2p when the database is built with the `p2p` feature
+    #[cfg(feature = "p2p")]
+    let transport = p2p::fairing(config.clone());
+
+    // Discovery starts once the node listens, so that it advertises the address it is reached at
+    let cfg = config.clone();
+    let node_discovery = discovery.clone();
+    let rocket = rocket::build()
+        .attach(AdHoc::on_liftoff("Discovery", move |rocket| {
+            let url = discovery::advertised_url(rocket.config());
+            Box::pin(async move {
+                if discovery::is_enabled() {
+                    tokio::spawn(node_discovery.run(cfg, url));
+                }
+            })
+        }))
+        .attach(logging::RequestLog)
+        .attach(metrics::RequestMetrics)
         .attach(AdHoc::on_response("Response Rewriter", move |_, res| {
             let vsn = vsn.clone();
             Box::pin(async move {
                 // add to response header
-                res.set_header(Header::new("Server", format!("SamaritanDB v{}", vsn)));
+                res.set_header(Header::new("Server", util::server_header(&vsn)));
             })
         }))
-        .mount("/", routes::routes())
+        .mount("/", telemetry::traced(routes::routes()))
         // add the did queue as a rocket state, so it can be accessed by internal DB functions
         .manage(did_list)
         .manage(config)
+        .manage(jobs)
+        .manage(discovery)
         .register(
             "/",
-            catchers![routes::not_found, routes::unauthorized, routes::bad_request],
-        )
-        .launch()
-        .await?;
+            catchers![
+                routes::not_found,
+                routes::unauthorized,
+                routes::forbidden_request,
+                routes::bad_request
+            ],
+        );
+
+    #[cfg(feature = "p2p")]
+    let rocket = rocket
+        .mount("/", telemetry::traced(routes::p2p_routes()))
+        .attach(transport);
+
+    rocket.launch().await?;
+    telemetry::shutdown();
 
     Ok(())
 }
@@ -0,0 +1,363 @@
+/// Copyright (c) Algorealm, Inc.
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    serde::json::{
+        serde_json::{json, Map},
+        Value,
+    },
+    Data, Request, Response,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    /// route the request matched, with its parameters unfilled
+    route: String,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    method: String,
+    route: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DocumentLabels {
+    application_did: String,
+    database: String,
+    /// `read`, `write` or `delete`
+    operation: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OutcomeLabels {
+    outcome: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CallLabels {
+    call: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DatabaseLabels {
+    database: String,
+}
+
+type Histograms<L> = Family<L, Histogram, fn() -> Histogram>;
+
+/// Metrics of the node, exposed in the Prometheus text format
+struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    request_duration: Histograms<RouteLabels>,
+    documents: Family<DocumentLabels, Counter>,
+    did_queue_depth: Gauge,
+    did_verifications: Family<OutcomeLabels, Counter>,
+    chain_calls: Family<CallLabels, Counter>,
+    chain_call_duration: Histograms<CallLabels>,
+    chain_call_failures: Family<CallLabels, Counter>,
+    disk_usage: Family<DatabaseLabels, Gauge>,
+}
+
+/// buckets of durations, from 1 millisecond to about 30 seconds
+fn duration_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.001, 2.0, 16))
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| {
+        let mut metrics = Metrics {
+            registry: Registry::with_prefix("samaritandb"),
+            requests: Family::default(),
+            request_duration: Family::new_with_constructor(duration_histogram),
+            documents: Family::default(),
+            did_queue_depth: Gauge::default(),
+            did_verifications: Family::default(),
+            chain_calls: Family::default(),
+            chain_call_duration: Family::new_with_constructor(duration_histogram),
+            chain_call_failures: Family::default(),
+            disk_usage: Family::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "http_requests",
+            "HTTP requests served, by route and status",
+            metrics.requests.clone(),
+        );
+        registry.register(
+            "http_request_duration_seconds",
+            "Time taken to serve HTTP requests, by route",
+            metrics.request_duration.clone(),
+        );
+        registry.register(
+            "documents",
+            "Documents read, written and deleted, by database",
+            metrics.documents.clone(),
+        );
+        registry.register(
+            "did_queue_depth",
+            "Written documents whose DID is waiting to be checked on the chain",
+            metrics.did_queue_depth.clone(),
+        );
+        registry.register(
+            "did_verifications",
+            "DIDs checked, by outcome: `known` DIDs were already verified, `verified` DIDs were found on the chain and `missing` DIDs were not",
+            metrics.did_verifications.clone(),
+        );
+        registry.register(
+            "chain_calls",
+            "Calls to the bridge to the chain",
+            metrics.chain_calls.clone(),
+        );
+        registry.register(
+            "chain_call_duration_seconds",
+            "Time taken by calls to the bridge to the chain",
+            metrics.chain_call_duration.clone(),
+        );
+        registry.register(
+            "chain_call_failures",
+            "Calls to the bridge to the chain that failed",
+            metrics.chain_call_failures.clone(),
+        );
+        registry.register(
+            "disk_usage_bytes",
+            "Size on disk of the databases opened since the node started",
+            metrics.disk_usage.clone(),
+        );
+
+        metrics
+    })
+}
+
+/// Totals reported by `_stats`, kept beside the metrics as these cannot be read back
+#[derive(Default)]
+struct Stats {
+    /// requests by status class, from 1xx to 5xx
+    requests: [AtomicU64; 5],
+    reads: AtomicU64,
+    writes: AtomicU64,
+    deletes: AtomicU64,
+    /// lookups of database handles that were already open
+    database_cache_hits: AtomicU64,
+    database_cache_misses: AtomicU64,
+}
+
+static STATS: OnceLock<Stats> = OnceLock::new();
+
+fn stats() -> &'static Stats {
+    STATS.get_or_init(Stats::default)
+}
+
+/// Count a lookup of a database handle, which is a hit if the database was already open
+pub fn database_cache(hit: bool) {
+    let stats = stats();
+    let counter = if hit {
+        &stats.database_cache_hits
+    } else {
+        &stats.database_cache_misses
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an operation on a document of a database
+pub fn document_operation(application_did: &Did, db_name: &str, operation: &str) {
+    let stats = stats();
+    match operation {
+        "read" => &stats.reads,
+        "write" => &stats.writes,
+        _ => &stats.deletes,
+    }
+    .fetch_add(1, Ordering::Relaxed);
+
+    metrics()
+        .documents
+        .get_or_create(&DocumentLabels {
+            application_did: application_did.0.clone(),
+            database: db_name.to_owned(),
+            operation: operation.to_owned(),
+        })
+        .inc();
+}
+
+/// Count the outcome of a check of a DID
+pub fn did_verification(outcome: &str) {
+    metrics()
+        .did_verifications
+        .get_or_create(&OutcomeLabels {
+            outcome: outcome.to_owned(),
+        })
+        .inc();
+}
+
+/// Record a call to the bridge to the chain
+pub fn chain_call(call: &str, duration: Duration, succeeded: bool) {
+    let labels = CallLabels {
+        call: call.to_owned(),
+    };
+    let metrics = metrics();
+    metrics.chain_calls.get_or_create(&labels).inc();
+    metrics
+        .chain_call_duration
+        .get_or_create(&labels)
+        .observe(duration.as_secs_f64());
+    if !succeeded {
+        metrics.chain_call_failures.get_or_create(&labels).inc();
+    }
+}
+
+/// Encode the metrics, with the gauges read at the time of the scrape
+pub fn render(did_queue_depth: usize, disk_usage: Vec<(String, u64)>) -> String {
+    let metrics = metrics();
+    metrics.did_queue_depth.set(did_queue_depth as i64);
+    // deleted databases are dropped from the series
+    metrics.disk_usage.clear();
+    for (database, size) in disk_usage {
+        metrics
+            .disk_usage
+            .get_or_create(&DatabaseLabels { database })
+            .set(size as i64);
+    }
+
+    let mut text = String::new();
+    let _ = encode(&mut text, &metrics.registry);
+    text
+}
+
+/// Counters of the node in the style of the `_stats` of CouchDB, each with its value, type and
+/// description
+pub fn summary(open_databases: usize) -> Value {
+    let stats = stats();
+    let counter =
+        |value: u64, desc: &str| json!({ "value": value, "type": "counter", "desc": desc });
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    let requests = stats.requests.iter().map(load).collect::<Vec<_>>();
+    let statuses = requests
+        .iter()
+        .enumerate()
+        .map(|(class, value)| {
+            (
+                format!("{}xx", class + 1),
+                counter(*value, "number of responses with a status of this class"),
+            )
+        })
+        .collect::<Map<_, _>>();
+
+    let hits = load(&stats.database_cache_hits);
+    let misses = load(&stats.database_cache_misses);
+    let hit_rate = if hits + misses == 0 {
+        0.0
+    } else {
+        hits as f64 / (hits + misses) as f64
+    };
+
+    json!({
+        "samaritandb": {
+            "open_databases": {
+                "value": open_databases,
+                "type": "gauge",
+                "desc": "number of databases open, including the root database"
+            },
+            "requests": counter(requests.iter().sum(), "number of HTTP requests"),
+            "request_statuses": statuses,
+            "database_reads": counter(load(&stats.reads), "number of documents read"),
+            "database_writes": counter(load(&stats.writes), "number of documents written"),
+            "database_deletes": counter(load(&stats.deletes), "number of documents deleted"),
+            "database_cache": {
+                "hits": counter(hits, "number of lookups of databases that were already open"),
+                "misses": counter(misses, "number of lookups that had to open a database"),
+                "hit_rate": {
+                    "value": hit_rate,
+                    "type": "gauge",
+                    "desc": "share of lookups of databases that were already open"
+                }
+            }
+        }
+    })
+}
+
+/// when the request was received
+struct RequestStart(Option<Instant>);
+
+/// Fairing counting the requests and their latency by route and status
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // routes rather than paths, so that the number of series stays bounded
+        let route = request
+            .route()
+            .map(|route| {
+                route
+                    .uri
+                    .as_str()
+                    .split('?')
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned()
+            })
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let method = request.method().as_str().to_owned();
+
+        let status = response.status().code;
+        if let Some(requests) = stats()
+            .requests
+            .get((status / 100).saturating_sub(1) as usize)
+        {
+            requests.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let metrics = metrics();
+        metrics
+            .requests
+            .get_or_create(&RequestLabels {
+                method: method.clone(),
+                route: route.clone(),
+                status,
+            })
+            .inc();
+
+        if let Some(start) = request.local_cache(|| RequestStart(None)).0 {
+            metrics
+                .request_duration
+                .get_or_create(&RouteLabels { method, route })
+                .observe(start.elapsed().as_secs_f64());
+        }
+    }
+}
@@ -0,0 +1,457 @@
+/// Copyright (c) Algorealm, Inc.
+use hmac::{Hmac, Mac};
+use libp2p::{
+    dcutr,
+    futures::StreamExt,
+    identify, identity,
+    multiaddr::Protocol,
+    noise, relay,
+    request_response::{self, Message, OutboundRequestId, ProtocolSupport, ResponseChannel},
+    swarm::{NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm,
+};
+use reqwest::{Client, Method, Url};
+use rocket::{
+    fairing::AdHoc,
+    http::RawStr,
+    serde::json::{serde_json::json, Value},
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{config, db, discovery::Discovery, logging, prelude::*};
+
+/// protocol carrying the requests of the replication protocol between nodes
+const PROTOCOL: &str = "/samaritandb/replication/1";
+
+/// time between two attempts to reach the nodes configured, in seconds
+const REDIAL_INTERVAL: u64 = 30;
+
+/// An HTTP request of a node to another, carried over libp2p
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub method: String,
+    /// path and query of the request
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Value>,
+}
+
+/// The response to a request carried over libp2p
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    replication: request_response::json::Behaviour<Request, Response>,
+    identify: identify::Behaviour,
+    /// reaches nodes behind NAT through the relays they listen on
+    relay: relay::client::Behaviour,
+    /// upgrades relayed connections to direct ones when the NAT lets it
+    dcutr: dcutr::Behaviour,
+}
+
+/// Work handed to the task driving the swarm
+enum Command {
+    Send {
+        peer: PeerId,
+        request: Request,
+        reply: oneshot::Sender<Result<Response, String>>,
+    },
+    Respond {
+        channel: ResponseChannel<Response>,
+        response: Response,
+    },
+}
+
+/// Handle to the transport once it is started
+struct Transport {
+    peer_id: PeerId,
+    commands: mpsc::Sender<Command>,
+    /// addresses the node is reached at, ending with its peer ID
+    addresses: Mutex<Vec<String>>,
+}
+
+static TRANSPORT: OnceLock<Transport> = OnceLock::new();
+
+/// Check whether nodes can reach this node and be reached over libp2p
+pub fn is_enabled() -> bool {
+    config::get().p2p.enabled
+}
+
+/// addresses of a comma-separated list of the `[p2p]` section
+fn addresses(list: &str) -> Vec<Multiaddr> {
+    list.split(',')
+        .filter_map(|address| address.trim().parse().ok())
+        .collect()
+}
+
+/// Derive the identity of the node from the chain key of the application.
+/// The ID of the node is mixed in, so that the nodes of an application each get their own.
+fn keypair(config: &DbConfig) -> Result<identity::Keypair, GenericError> {
+    let node_id = db::node_id(config).map_err(|_| "could not read the ID of the node")?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.mnemonic.as_bytes())?;
+    mac.update(format!("samaritandb-p2p:{}", node_id).as_bytes());
+    let secret = mac.finalize().into_bytes();
+
+    Ok(identity::Keypair::ed25519_from_bytes(secret.to_vec())?)
+}
+
+/// URL of the HTTP API of this node, which serves the requests of other nodes
+fn local_url(config: &rocket::Config) -> String {
+    let address = match config.address {
+        address if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        address => address,
+    };
+    format!("http://{}", SocketAddr::new(address, config.port))
+}
+
+/// Return the peer ID of this node and the addresses it is reached at, if the transport is started
+pub fn node() -> Option<(String, Vec<String>)> {
+    let transport = TRANSPORT.get()?;
+    let addresses = transport
+        .addresses
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+
+    Some((transport.peer_id.to_string(), addresses))
+}
+
+/// Send a request to a node, named by its peer ID, and wait for its response
+pub async fn send(peer: &str, request: Request) -> Result<Response, GenericError> {
+    let transport = TRANSPORT
+        .get()
+        .ok_or("the libp2p transport is turned off")?;
+    let peer = peer.parse::<PeerId>()?;
+
+    let (reply, response) = oneshot::channel();
+    transport
+        .commands
+        .send(Command::Send {
+            peer,
+            request,
+            reply,
+        })
+        .await
+        .map_err(|_| "the libp2p transport stopped")?;
+
+    Ok(response
+        .await
+        .map_err(|_| "the libp2p transport stopped")??)
+}
+
+/// Check whether a node may send requests to this node: it must be listed in `peers`, be a peer
+/// of the applications on this node or have been found through discovery, with a `p2p` URL
+async fn is_known(peer: &PeerId, discovery: &Discovery) -> bool {
+    let listed = addresses(&config::get().p2p.peers)
+        .iter()
+        .any(|address| address.iter().last() == Some(Protocol::P2p(*peer)));
+    if listed {
+        return true;
+    }
+
+    let peer = peer.to_string();
+    let mut urls = config::get().peers.values().cloned().collect::<Vec<_>>();
+    urls.extend(discovery.urls().await);
+    urls.iter().any(|url| {
+        Url::parse(url).is_ok_and(|url| url.scheme() == "p2p" && url.host_str() == Some(&peer))
+    })
+}
+
+/// Check whether a request is one of those the replication protocol sends to a node
+fn is_replication(method: &str, uri: &str) -> bool {
+    let path = uri.split('?').next().unwrap_or_default();
+    let Some(segments) = path
+        .strip_prefix('/')
+        .map(|path| {
+            path.split('/')
+                .map(|segment| RawStr::new(segment).percent_decode_lossy().into_owned())
+                .collect::<Vec<_>>()
+        })
+        .filter(|segments| {
+            segments.iter().all(|segment| {
+                !segment.is_empty() && segment != "." && segment != ".." && !segment.contains('/')
+            })
+        })
+    else {
+        return false;
+    };
+
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+    match (method, segments.as_slice()) {
+        ("GET", ["_all_dbs"]) => true,
+        ("GET" | "PUT", [db])
+        | ("GET", [db, "_changes"])
+        | ("POST", [db, "_revs_diff" | "_bulk_docs"])
+        | ("GET" | "PUT", [db, "_local", _]) => !db.starts_with('_'),
+        ("GET", [db, doc]) => !db.starts_with('_') && !doc.starts_with('_'),
+        _ => false,
+    }
+}
+
+/// Answer a request of another node with the HTTP API of this node.
+/// Only the routes of the replication protocol are served, and the request is authenticated
+/// there like any other.
+async fn serve(local_url: &str, request: Request) -> Response {
+    // the URI must not change the host the request is sent to
+    if !request.uri.starts_with('/') || request.uri.starts_with("//") {
+        return Response {
+            status: 400,
+            body: json!({
+                "error": "The request URI must be an absolute path"
+            }),
+        };
+    }
+
+    if !is_replication(&request.method, &request.uri) {
+        return Response {
+            status: 403,
+            body: json!({
+                "error": "Only the routes of the replication protocol are served over libp2p"
+            }),
+        };
+    }
+
+    let result = async {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(REPLICATION_REQUEST_TIMEOUT))
+            .build()?;
+
+        let mut builder = client.request(
+            Method::from_bytes(request.method.as_bytes())?,
+            format!("{}{}", local_url, request.uri),
+        );
+        // requests of other nodes are never taken for those of a node of the cluster
+        for (name, value) in request
+            .headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case(FORWARDED_HEADER))
+        {
+            builder = builder.header(name, value);
+        }
+        if let Some(body) = &request.body {
+            builder = builder.json(body);
+        }
+
+        let response = builder.send().await?;
+        Ok::<_, GenericError>(Response {
+            status: response.status().as_u16(),
+            body: response.json::<Value>().await.unwrap_or_default(),
+        })
+    }
+    .await;
+
+    result.unwrap_or_else(|e| Response {
+        status: 502,
+        body: json!({
+            "error": format!("Could not serve the request: {}", e)
+        }),
+    })
+}
+
+/// Make the nodes configured in `peers` reachable by their peer ID, and connect to them
+fn dial_peers(swarm: &mut Swarm<Behaviour>) {
+    for address in addresses(&config::get().p2p.peers) {
+        if let Some(Protocol::P2p(peer)) = address.iter().last() {
+            swarm.add_peer_address(peer, address.clone());
+            if !swarm.is_connected(&peer) {
+                let _ = swarm.dial(address);
+            }
+        }
+    }
+}
+
+/// Drive the swarm, sending the requests of this node and serving those of the other nodes
+async fn run(
+    mut swarm: Swarm<Behaviour>,
+    mut commands: mpsc::Receiver<Command>,
+    responses: mpsc::Sender<Command>,
+    local_url: String,
+    discovery: Discovery,
+) {
+    let mut pending: HashMap<OutboundRequestId, oneshot::Sender<Result<Response, String>>> =
+        HashMap::new();
+    let mut redial = tokio::time::interval(Duration::from_secs(REDIAL_INTERVAL));
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Send { peer, request, reply }) => {
+                    let request_id = swarm.behaviour_mut().replication.send_request(&peer, request);
+                    pending.insert(request_id, reply);
+                }
+                Some(Command::Respond { channel, response }) => {
+                    let _ = swarm.behaviour_mut().replication.send_response(channel, response);
+                }
+                None => return,
+            },
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Replication(request_response::Event::Message {
+                    peer, message, ..
+                })) => match message {
+                    Message::Request { request, channel, .. } => {
+                        let (responses, local_url, discovery) =
+                            (responses.clone(), local_url.clone(), discovery.clone());
+                        tokio::spawn(async move {
+                            let response = match is_known(&peer, &discovery).await {
+                                true => serve(&local_url, request).await,
+                                false => Response {
+                                    status: 403,
+                                    body: json!({
+                                        "error": "The node is not a known peer"
+                                    }),
+                                },
+                            };
+                            let _ = responses.send(Command::Respond { channel, response }).await;
+                        });
+                    }
+                    Message::Response { request_id, response } => {
+                        if let Some(reply) = pending.remove(&request_id) {
+                            let _ = reply.send(Ok(response));
+                        }
+                    }
+                },
+                SwarmEvent::Behaviour(BehaviourEvent::Replication(
+                    request_response::Event::OutboundFailure { request_id, error, .. },
+                )) => {
+                    if let Some(reply) = pending.remove(&request_id) {
+                        let _ = reply.send(Err(error.to_string()));
+                    }
+                }
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    let address = address.with(Protocol::P2p(*swarm.local_peer_id()));
+                    logging::info(format!("Listening for nodes on {}", address));
+                    if let Some(transport) = TRANSPORT.get() {
+                        transport
+                            .addresses
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .push(address.to_string());
+                    }
+                }
+                _ => {}
+            },
+            _ = redial.tick() => dial_peers(&mut swarm),
+        }
+    }
+}
+
+/// Start the transport, listening on the addresses configured in `listen`
+fn start(
+    config: &DbConfig,
+    local_url: String,
+    discovery: Discovery,
+) -> Result<PeerId, GenericError> {
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair(config)?)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|keypair, relay| Behaviour {
+            replication: request_response::json::Behaviour::new(
+                [(StreamProtocol::new(PROTOCOL), ProtocolSupport::Full)],
+                request_response::Config::default()
+                    .with_request_timeout(Duration::from_secs(REPLICATION_REQUEST_TIMEOUT)),
+            ),
+            identify: identify::Behaviour::new(identify::Config::new(
+                PROTOCOL.to_owned(),
+                keypair.public(),
+            )),
+            relay,
+            dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
+        })?
+        .with_swarm_config(|config| {
+            config.with_idle_connection_timeout(Duration::from_secs(REDIAL_INTERVAL * 2))
+        })
+        .build();
+
+    for address in addresses(&config::get().p2p.listen) {
+        swarm.listen_on(address)?;
+    }
+
+    let peer_id = *swarm.local_peer_id();
+    let (commands, receiver) = mpsc::channel(64);
+    let transport = Transport {
+        peer_id,
+        commands: commands.clone(),
+        addresses: Mutex::new(Vec::new()),
+    };
+    if TRANSPORT.set(transport).is_err() {
+        return Err("the libp2p transport is already started".into());
+    }
+
+    tokio::spawn(run(swarm, receiver, commands, local_url, discovery));
+
+    Ok(peer_id)
+}
+
+/// Start the transport once the node listens, so that it serves other nodes with its HTTP API
+pub fn fairing(config: DbConfig) -> AdHoc {
+    AdHoc::on_liftoff("libp2p transport", move |rocket| {
+        let local_url = local_url(rocket.config());
+        let discovery = rocket.state::<Discovery>().cloned().unwrap_or_default();
+        Box::pin(async move {
+            if !is_enabled() {
+                return;
+            }
+
+            match start(&config, local_url, discovery) {
+                Ok(peer_id) => logging::info(format!("Reachable over libp2p as {}", peer_id)),
+                Err(e) => logging::error(format!("Could not start the libp2p transport: {}", e)),
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_replication_routes_are_served() {
+        for (method, uri) in [
+            ("GET", "/_all_dbs"),
+            ("PUT", "/notes"),
+            ("GET", "/notes/_changes?since=4&limit=100"),
+            ("POST", "/notes/_revs_diff"),
+            ("POST", "/notes/_bulk_docs"),
+            ("PUT", "/notes/_local/checkpoint"),
+            ("GET", "/notes/doc-1?revs=true&open_revs=all"),
+        ] {
+            assert!(is_replication(method, uri), "{} {}", method, uri);
+        }
+
+        for (method, uri) in [
+            ("GET", "/_config"),
+            ("PUT", "/_config/admins/eve"),
+            ("POST", "/_session"),
+            ("DELETE", "/notes"),
+            ("PUT", "/notes/doc-1"),
+            ("DELETE", "/notes/doc-1"),
+            ("POST", "/notes/_find"),
+            ("GET", "/notes/_journal"),
+            ("GET", "/%5Fconfig/admins"),
+            ("GET", "/../_config"),
+            ("GET", "/notes/%2E%2E"),
+            ("GET", "/notes/a%2Fb"),
+            ("GET", "/notes//doc"),
+        ] {
+            assert!(!is_replication(method, uri), "{} {}", method, uri);
+        }
+    }
+}
@@ -5,7 +5,7 @@ use rocket::{
     request::{FromRequest, Outcome},
     serde::{
         json::{
-            serde_json::{self, json},
+            serde_json::{self, json, Map},
             Value,
         },
         Deserialize,
@@ -17,7 +17,7 @@ use serde_json::Error as SerdeError;
 use sled::Error as SledError;
 use std::{collections::VecDeque, io};
 
-use crate::util;
+use crate::{cluster, config, db, session, util};
 
 #[derive(Debug)]
 pub enum DatabaseError {
@@ -29,6 +29,9 @@ pub enum DatabaseError {
     DocumentRevisionNotFound,
     UserDidConflict,
     MissingDocument,
+    InvalidSessionToken,
+    DataKeyShredded,
+    EncryptedFieldQuery,
     OtherError,
 }
 
@@ -59,12 +62,91 @@ pub struct DbConfig {
     pub cache_capacity: u64,
     pub version: String,
     pub mnemonic: String,
+    /// key wrapping the data keys of DIDs, set when documents are encrypted at rest
+    pub master_key: Option<[u8; 32]>,
 }
 
-/// path to config file
+/// default path to config file
 pub static CONFIG_FILE_PATH: &str = "config.ini";
-/// time for task to go to sleep during DID validity cleanup
+/// environment variable holding the path to the config file, unless given with `--config`
+pub static CONFIG_PATH_ENV: &str = "SAMDB_CONFIG";
+/// default path to the encrypted keystore holding the config secrets
+pub static DEFAULT_KEYSTORE_PATH: &str = "keystore.json";
+/// environment variable holding the passphrase of the keystore
+pub static KEYSTORE_PASSPHRASE_ENV: &str = "SAMDB_KEYSTORE_PASSPHRASE";
+/// environment variable holding the path to a key file unlocking the keystore
+pub static KEYSTORE_KEY_FILE_ENV: &str = "SAMDB_KEYSTORE_KEY_FILE";
+/// default time for task to go to sleep during DID validity cleanup
 pub const DID_CLEANUP_SLEEP_TIME: u64 = 10;
+/// default URL of the bridge to the chain
+pub static DEFAULT_CHAIN_URL: &str = "http://localhost:5000";
+/// default URL of the OpenTelemetry collector traces are exported to
+pub static DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4318/v1/traces";
+/// time after which the bridge to the chain is considered unreachable by readiness checks, in seconds
+pub const CHAIN_PING_TIMEOUT: u64 = 5;
+/// number of DIDs waiting to be checked above which the node is not ready
+pub const DID_QUEUE_READY_LIMIT: usize = 1000;
+/// default size the log file is rotated at, in bytes
+pub const LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// time between two checks of the config file for changes, in seconds
+pub const CONFIG_WATCH_INTERVAL: u64 = 2;
+/// default lifetime of session access tokens, in seconds
+pub const SESSION_TOKEN_TTL: u64 = 15 * 60;
+/// default lifetime of session refresh tokens, in seconds
+pub const SESSION_REFRESH_TTL: u64 = 24 * 60 * 60;
+/// time a successful check of the Basic credentials of an application is remembered, in seconds
+pub const AUTH_CACHE_TTL: u64 = 60;
+/// number of entries of an audit log listed at once, unless a limit is given
+pub const AUDIT_PAGE_SIZE: usize = 100;
+/// time between two checkpoints of the journals of the databases, in seconds
+pub const JOURNAL_CHECKPOINT_INTERVAL: u64 = 60;
+/// number of past revisions of a document remembered for replication
+pub const REVS_LIMIT: usize = 1000;
+/// number of deleted branches of a document kept besides its winning revision, the oldest
+/// being pruned first
+pub const TOMBSTONE_LIMIT: usize = 16;
+/// age after which the tags of the elements removed from arrays of CRDT documents are forgotten,
+/// in milliseconds. Replicas must exchange their changes within it.
+pub const CRDT_TOMBSTONE_TTL: u64 = 7 * 24 * 60 * 60 * 1000;
+/// number of changes replicated between two checkpoints
+pub const REPLICATION_BATCH_SIZE: usize = 100;
+/// time continuous replications wait for new changes, in seconds
+pub const REPLICATION_POLL_INTERVAL: u64 = 5;
+/// time after which requests to other nodes are abandoned, in seconds
+pub const REPLICATION_REQUEST_TIMEOUT: u64 = 60;
+/// time between two checks that every database is replicated with every peer, in seconds
+pub const PEER_SYNC_INTERVAL: u64 = 30;
+/// number of shards databases are split into, unless configured otherwise
+pub const CLUSTER_SHARDS: u32 = 8;
+/// number of nodes holding each document, unless configured otherwise
+pub const CLUSTER_REPLICAS: usize = 3;
+/// points each node of the cluster gets on the hash ring placing shards
+pub const CLUSTER_VNODES: u32 = 64;
+/// header marking requests forwarded by another node of the cluster, which are never forwarded again
+pub const FORWARDED_HEADER: &str = "X-Samaritan-Forwarded";
+/// mDNS service type nodes advertise themselves with on the local network
+pub const DISCOVERY_SERVICE_TYPE: &str = "_samaritandb._tcp.local.";
+/// time between two exchanges of known nodes with the other nodes, in seconds
+pub const DISCOVERY_GOSSIP_INTERVAL: u64 = 15;
+/// time after which a node that was not heard of is forgotten, in seconds
+pub const DISCOVERY_NODE_TTL: u64 = 120;
+/// minimum length of the key shared by the nodes of an application that sync through discovery
+pub const DISCOVERY_KEY_MIN_LENGTH: usize = 16;
+/// maximum number of nodes found through discovery that are kept track of
+pub const DISCOVERY_MAX_NODES: usize = 1024;
+/// routes nodes found through discovery can call with their peer credential, those replication uses
+pub const PEER_ROUTES: [&str; 10] = [
+    "all_dbs",
+    "create_db",
+    "database_info",
+    "changes",
+    "revs_diff",
+    "bulk_docs",
+    "local_document",
+    "update_local_document",
+    "coordinate_fetch_document",
+    "fetch_document",
+];
 /// The database error type
 pub type DatabaseResult<T> = Result<T, DatabaseError>;
 /// The rust generic error type
@@ -97,10 +179,13 @@ pub struct AuthPayload {
     pub secret: String,
 }
 
+/// Application a request was authenticated as, cached by the guards for the request log
+pub struct RequestApplication(pub Option<Did>);
+
 // Define an authentication guard
 pub struct BasicAuth {
-    pub username: String,
-    pub password: String,
+    /// DID of the application the credentials belong to
+    pub application_did: Did,
 }
 
 #[rocket::async_trait]
@@ -108,36 +193,24 @@ impl<'r> FromRequest<'r> for BasicAuth {
     type Error = Value;
 
     async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
-        match request.headers().get_one("Authorization") {
-            Some(header) => {
-                if let Some(credentials) = header.strip_prefix("Basic ") {
-                    if let Ok(decoded) = STANDARD.decode(credentials) {
-                        if let Ok(credentials_str) = String::from_utf8(decoded) {
-                            let mut parts = credentials_str.splitn(2, ':');
-                            let username = parts.next();
-                            let password = parts.next();
-
-                            if let Some(username) = username {
-                                if let Some(password) = password {
-                                    // check for equility
-                                    let ss58_address = util::read_config("auth", "application_did");
-                                    let ss58_address =
-                                        ss58_address.split(":").nth(3).unwrap_or_default(); // SS58 address
-                                    if username == ss58_address
-                                        && password == util::read_config("auth", "auth_secret")
-                                    {
-                                        return Outcome::Success(BasicAuth {
-                                            username: username.to_owned(),
-                                            password: password.to_string(),
-                                        });
-                                    }
-                                }
-                            }
-                        }
-                    }
+        if let (Some(header), Some(config)) = (
+            request.headers().get_one("Authorization"),
+            request.rocket().state::<DbConfig>(),
+        ) {
+            if let Some((username, password)) = parse_basic_credentials(header) {
+                // the username is the SS58 address of the application DID
+                let application_did = Did(format!("did:sam:apps:{}", username));
+                // Argon2 runs on the blocking threads, so that it does not hold up other requests
+                let (config, did) = (config.clone(), application_did.clone());
+                let verified = tokio::task::spawn_blocking(move || {
+                    db::verify_auth_secret(&config, &did, &password)
+                })
+                .await;
+                if let Ok(Ok(true)) = verified {
+                    request.local_cache(|| RequestApplication(Some(application_did.clone())));
+                    return Outcome::Success(BasicAuth { application_did });
                 }
             }
-            None => {}
         }
 
         Outcome::Error((
@@ -149,10 +222,407 @@ impl<'r> FromRequest<'r> for BasicAuth {
     }
 }
 
+/// Authentication guard of the administrators of the node, listed in the `[admins]` section
+pub struct AdminAuth {
+    pub name: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAuth {
+    type Error = Value;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some((name, password)) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(parse_basic_credentials)
+        {
+            // Argon2 runs on the blocking threads, so that it does not hold up other requests
+            let hash = config::get().admins.get(&name).cloned().unwrap_or_default();
+            let verified = tokio::task::spawn_blocking(move || {
+                // passwords set in the environment are not hashed
+                if hash.starts_with("$argon2") {
+                    util::verify_secret(&password, &hash)
+                } else {
+                    !hash.is_empty() && util::constant_time_eq(&hash, &password)
+                }
+            })
+            .await;
+            if let Ok(true) = verified {
+                return Outcome::Success(AdminAuth { name });
+            }
+        }
+
+        Outcome::Error((
+            Status::Unauthorized,
+            json!({
+                "error": "Invalid or missing administrator credentials"
+            }),
+        ))
+    }
+}
+
+/// Decode the username and password from a Basic `Authorization` header
+fn parse_basic_credentials(header: &str) -> Option<(String, String)> {
+    let credentials = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_owned(), password.to_owned()))
+}
+
+/// Operations an API key can be granted on a database
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    Admin,
+    /// see the plaintext of encrypted fields
+    Decrypt,
+}
+
+/// Databases and operations a credential is limited to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Scope {
+    /// databases the scope applies to, `*` matches all databases of the application
+    pub databases: Vec<String>,
+    pub permissions: Vec<Permission>,
+}
+
+impl Scope {
+    /// Check whether the scope allows an operation on a database
+    pub fn permits(&self, db_name: &str, permission: Permission) -> bool {
+        self.databases.iter().any(|db| db == "*" || db == db_name)
+            && self.permissions.contains(&permission)
+    }
+}
+
+/// An API key issued by an application, scoped to some of its databases and operations
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub application_did: String,
+    #[serde(flatten)]
+    pub scope: Scope,
+    pub created_at: u64,
+}
+
+/// Kind of session token
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    /// authenticates requests
+    Access,
+    /// can only be exchanged for a new pair of tokens
+    Refresh,
+}
+
+/// Claims carried by a session token
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Claims {
+    /// unique token ID, used to revoke the token
+    pub jti: String,
+    /// ID of the session, shared by its access and refresh tokens to revoke them at once
+    pub sid: String,
+    /// DID of the application the session belongs to
+    pub sub: String,
+    /// DID of the user acting through the application, if any
+    pub user_did: Option<String>,
+    /// operations allowed during the session, all of them if absent
+    pub scope: Option<Scope>,
+    pub typ: TokenType,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+/// Payload for starting a session
+#[derive(serde::Deserialize, Default)]
+pub struct SessionPayload {
+    pub user_did: Option<Did>,
+    pub scope: Option<Scope>,
+}
+
+/// Payload for refreshing a session
+#[derive(serde::Deserialize)]
+pub struct RefreshPayload {
+    pub refresh_token: String,
+}
+
+/// Key the encrypted fields of a database are encrypted with
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKey {
+    /// a key shared by all documents of the database
+    #[default]
+    Database,
+    /// the data key of the DID owning the document
+    Did,
+}
+
+/// Fields of the documents of a database that are encrypted on write
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncryptionPolicy {
+    /// dotted JSON paths of the fields, e.g. `contact.email`
+    pub fields: Vec<String>,
+    #[serde(default)]
+    pub key: FieldKey,
+    /// encrypt equal values to equal ciphertexts, so that the fields can be queried
+    #[serde(default)]
+    pub deterministic: bool,
+}
+
+/// How concurrent writes to the documents of a database are reconciled
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseMode {
+    /// writes must name the current revision, and concurrent edits become conflicts
+    #[default]
+    Revisions,
+    /// documents are merged field by field, so that concurrent edits never conflict
+    Crdt,
+}
+
+/// Request to change the mode of a database
+#[derive(serde::Deserialize)]
+pub struct DatabaseModePayload {
+    pub mode: DatabaseMode,
+}
+
+/// Query matching documents whose fields equal the values of the selector
+#[derive(serde::Deserialize)]
+pub struct FindPayload {
+    /// values by dotted JSON path
+    pub selector: Map<String, Value>,
+}
+
+/// A leaf of the revision tree of a document
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RevisionLeaf {
+    pub rev: String,
+    /// revisions leading to the leaf, newest first, starting with the leaf itself
+    pub path: Vec<String>,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+/// Request to replicate the changes of a database into another
+#[derive(serde::Deserialize)]
+pub struct ReplicatePayload {
+    /// name of a database of the application, URL of a remote database,
+    /// or `{ "url": ..., "headers": {...} }`
+    pub source: Value,
+    pub target: Value,
+    /// keep replicating new changes until cancelled
+    #[serde(default)]
+    pub continuous: bool,
+    #[serde(default)]
+    pub create_target: bool,
+    /// stop the continuous replication with the same source and target
+    #[serde(default)]
+    pub cancel: bool,
+}
+
+/// Peer to replicate the databases of the application with
+#[derive(serde::Deserialize)]
+pub struct PeerPayload {
+    pub name: String,
+    /// URL of the peer node, holding the Basic credentials of the application on it
+    pub url: String,
+}
+
+/// Key shared by the nodes of the application, to sync with the nodes they discover
+#[derive(serde::Deserialize)]
+pub struct DiscoveryPayload {
+    pub key: String,
+}
+
+/// Options of a document read
+#[derive(rocket::FromForm)]
+pub struct DocumentQuery {
+    /// a revision in conflict to read instead of the winning one
+    pub rev: Option<String>,
+    /// include the revision history and owner of the document
+    pub revs: Option<bool>,
+    /// list the revisions in conflict in `_conflicts`
+    pub conflicts: Option<bool>,
+    /// `all` to read every leaf revision
+    pub open_revs: Option<String>,
+}
+
+/// Documents written at once
+#[derive(serde::Deserialize)]
+pub struct BulkDocsPayload {
+    pub docs: Vec<Value>,
+    /// `false` when the documents keep the revisions given by another replica
+    pub new_edits: Option<bool>,
+}
+
+/// Request guard accepting the application's Basic credentials, one of its API keys
+/// or a session token
+#[derive(Clone)]
+pub struct Access {
+    pub application_did: Did,
+    /// DID of the user the session was started for
+    pub user_did: Option<Did>,
+    /// limits of the credential used, `None` when the application has full access
+    pub scope: Option<Scope>,
+    /// claims of the session token used for the request
+    pub session: Option<Claims>,
+    /// whether the request comes from a node found through discovery, which can only replicate
+    pub peer: bool,
+}
+
+impl Access {
+    /// Check whether the request may perform an operation on a database
+    pub fn permits(&self, db_name: &str, permission: Permission) -> bool {
+        self.scope
+            .as_ref()
+            .is_none_or(|scope| scope.permits(db_name, permission))
+    }
+
+    /// Check whether the request may act on the data of a user
+    pub fn permits_user(&self, did: &Did) -> bool {
+        self.user_did
+            .as_ref()
+            .is_none_or(|user_did| user_did == did)
+    }
+}
+
+/// outcome of the `Access` guard, checked once per request even when routes are forwarded
+struct AccessOutcome(Outcome<Access, Value>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Access {
+    type Error = Value;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let outcome = request
+            .local_cache_async(async { AccessOutcome(Access::check(request).await) })
+            .await
+            .0
+            .clone();
+
+        match outcome {
+            Outcome::Success(access)
+                if access.peer
+                    && !request
+                        .route()
+                        .and_then(|route| route.name.as_deref())
+                        .is_some_and(|name| PEER_ROUTES.contains(&name)) =>
+            {
+                Outcome::Error((
+                    Status::Forbidden,
+                    json!({
+                        "error": "The credential does not permit this operation."
+                    }),
+                ))
+            }
+            outcome => outcome,
+        }
+    }
+}
+
+impl Access {
+    /// Check the credentials of a request
+    async fn check(request: &rocket::Request<'_>) -> Outcome<Access, Value> {
+        let config = match request.rocket().state::<DbConfig>() {
+            Some(config) => config,
+            None => return Outcome::Forward(Status::InternalServerError),
+        };
+
+        if let Some(header) = request.headers().get_one("X-API-Key") {
+            // keys are presented as `<id>.<secret>`
+            if let Some((id, secret)) = header.split_once('.') {
+                if let Ok(Some((api_key, hash))) = db::fetch_api_key(config, id) {
+                    if util::verify_api_secret(secret, &hash) {
+                        let application_did = Did(api_key.application_did);
+                        request.local_cache(|| RequestApplication(Some(application_did.clone())));
+                        return Outcome::Success(Access {
+                            application_did,
+                            user_did: None,
+                            scope: Some(api_key.scope),
+                            session: None,
+                            peer: false,
+                        });
+                    }
+                }
+            }
+
+            return Outcome::Error((
+                Status::Unauthorized,
+                json!({
+                    "error": "Invalid X-API-Key header"
+                }),
+            ));
+        }
+
+        if let Some(token) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        {
+            return match session::verify(config, token, TokenType::Access) {
+                Ok(claims) => {
+                    request.local_cache(|| RequestApplication(Some(Did(claims.sub.clone()))));
+                    Outcome::Success(Access {
+                        application_did: Did(claims.sub.clone()),
+                        user_did: claims.user_did.clone().map(Did),
+                        scope: claims.scope.clone(),
+                        session: Some(claims),
+                        peer: false,
+                    })
+                }
+                Err(_) => Outcome::Error((
+                    Status::Unauthorized,
+                    json!({
+                        "error": "Invalid or expired session token"
+                    }),
+                )),
+            };
+        }
+
+        // nodes found through discovery authenticate with a credential derived from the shared key
+        if let Some((username, password)) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(parse_basic_credentials)
+        {
+            let application_did = Did(format!("did:sam:apps:{}", username));
+            if let Ok(true) = db::verify_peer_credential(config, &application_did, &password) {
+                request.local_cache(|| RequestApplication(Some(application_did.clone())));
+                return Outcome::Success(Access {
+                    application_did,
+                    user_did: None,
+                    scope: None,
+                    session: None,
+                    peer: true,
+                });
+            }
+        }
+
+        request.guard::<BasicAuth>().await.map(|auth| Access {
+            application_did: auth.application_did,
+            user_did: None,
+            scope: None,
+            session: None,
+            peer: false,
+        })
+    }
+}
+
 // DID type
 #[derive(serde::Deserialize, Clone, PartialEq, Debug)]
 pub struct Did(pub String);
 
+impl Did {
+    /// The SS58 address suffix of the DID
+    pub fn address(&self) -> &str {
+        self.0.split(':').nth(3).unwrap_or_default()
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Did {
     type Error = Value;
@@ -164,7 +634,6 @@ impl<'r> FromRequest<'r> for Did {
         if DbConfig::is_valid_did(&did, true) {
             return Outcome::Success(Did(did));
         } else {
-            println!("----> {}", DbConfig::is_valid_did(&did, true));
             return Outcome::Error((
                 Status::BadRequest,
                 json!({
@@ -184,6 +653,110 @@ fn extract_did_from_request(request: &Request<'_>) -> String {
         .to_owned()
 }
 
+/// A request as another node of the cluster would receive it, with the client's credentials
+#[derive(Clone)]
+pub struct ClusterRequest {
+    /// path and query of the request
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    /// whether another node of the cluster forwarded the request
+    pub forwarded: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClusterRequest {
+    type Error = Value;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = ["Authorization", "X-API-Key", "X-DID", "Content-Type"]
+            .into_iter()
+            .filter_map(|name| {
+                let value = request.headers().get_one(name)?;
+                Some((name.to_owned(), value.to_owned()))
+            })
+            .collect();
+
+        Outcome::Success(ClusterRequest {
+            uri: request.uri().to_string(),
+            headers,
+            forwarded: request.headers().contains(FORWARDED_HEADER),
+        })
+    }
+}
+
+/// A request for a document held by other nodes of the cluster, which this node coordinates.
+/// Requests for documents held by this node alone are forwarded to the routes serving them.
+pub struct Coordinator {
+    /// nodes holding the document, the one writes go to first
+    pub replicas: Vec<cluster::Member>,
+    pub request: ClusterRequest,
+    /// number of replicas to read from before answering
+    pub r: usize,
+    /// number of replicas to write to before acknowledging a write
+    pub w: usize,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Coordinator {
+    type Error = Value;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let (Some(Ok(db_name)), Some(Ok(doc_id))) =
+            (request.param::<&str>(0), request.param::<&str>(1))
+        else {
+            return Outcome::Forward(Status::NotFound);
+        };
+        if !cluster::is_clustered(&cluster::members())
+            || db_name.starts_with('_')
+            || doc_id.starts_with('_')
+        {
+            return Outcome::Forward(Status::NotFound);
+        }
+        let cluster_request = match request.guard::<ClusterRequest>().await {
+            Outcome::Success(cluster_request) => cluster_request,
+            _ => return Outcome::Forward(Status::InternalServerError),
+        };
+        if cluster_request.forwarded {
+            return Outcome::Forward(Status::NotFound);
+        }
+
+        let config = match request.rocket().state::<DbConfig>() {
+            Some(config) => config,
+            None => return Outcome::Forward(Status::InternalServerError),
+        };
+        // the nodes holding the document check the credentials again
+        let application_did = match request.guard::<Access>().await {
+            Outcome::Success(auth) => auth.application_did,
+            _ => return Outcome::Forward(Status::Unauthorized),
+        };
+
+        let n = cluster::replica_count(config, &application_did, db_name);
+        let replicas = cluster::replicas(&application_did, db_name, doc_id, n);
+        if replicas
+            .iter()
+            .all(|member| member.name == cluster::node_name())
+        {
+            return Outcome::Forward(Status::NotFound);
+        }
+
+        // a majority of the replicas by default
+        let quorum = |name: &str| {
+            request
+                .query_value::<usize>(name)
+                .and_then(Result::ok)
+                .unwrap_or(replicas.len() / 2 + 1)
+                .clamp(1, replicas.len())
+        };
+
+        Outcome::Success(Coordinator {
+            r: quorum("r"),
+            w: quorum("w"),
+            replicas,
+            request: cluster_request,
+        })
+    }
+}
+
 // A generic wrapper struct that includes the data and an optional "_rev" field
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DataWrapper<T> {
@@ -197,6 +770,7 @@ pub type DidQueue = VecDeque<DbEntry>;
 #[derive(Debug)]
 pub struct DbEntry {
     pub did: Did,
+    pub application_did: Did,
     pub db_name: String,
     pub doc_id: String,
 }
@@ -0,0 +1,983 @@
+/// Copyright (c) Algorealm, Inc.
+use async_std::sync::Mutex;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    redirect::Policy,
+    Client, Method, StatusCode, Url,
+};
+use rocket::serde::json::{
+    serde_json::{json, Map},
+    Value,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::task::AbortHandle;
+
+#[cfg(feature = "p2p")]
+use crate::p2p;
+use crate::{config, db, discovery::Discovery, encryption, prelude::*, util};
+
+/// Replication jobs of all applications, by replication ID
+pub type Jobs = Arc<Mutex<HashMap<String, Job>>>;
+
+/// A replication job, with the task running it if it is continuous
+pub struct Job {
+    pub status: ReplicationStatus,
+    pub task: Option<AbortHandle>,
+    /// URL of the peer the job was started for, with its credentials
+    pub peer_url: Option<String>,
+}
+
+/// State of a replication job
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationState {
+    Running,
+    Completed,
+    /// a one-shot replication stopped on an error
+    Failed,
+    /// the last attempt of a continuous replication failed, it is retried after a while
+    Crashing,
+}
+
+/// Progress of a replication job
+#[derive(Serialize, Clone, Debug)]
+pub struct ReplicationStatus {
+    pub id: String,
+    #[serde(skip)]
+    pub application_did: String,
+    /// name of the peer the replication is kept alive for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    pub source: String,
+    pub target: String,
+    pub continuous: bool,
+    pub state: ReplicationState,
+    pub docs_read: u64,
+    pub docs_written: u64,
+    pub doc_write_failures: u64,
+    /// source sequence number up to which changes have been replicated
+    pub checkpointed_source_seq: u64,
+    pub checkpointed_at: Option<u64>,
+    /// changes of the source left to replicate when it was last read
+    pub pending_changes: u64,
+    pub error: Option<String>,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+/// A database changes are replicated from or to
+pub enum Endpoint {
+    /// a database of the application on this node
+    Local {
+        application_did: Did,
+        db_name: String,
+        config: DbConfig,
+        did_queue: Arc<Mutex<DidQueue>>,
+        /// whether encrypted fields are replicated in plaintext
+        decrypt: bool,
+    },
+    /// a database on another node, reached over HTTP
+    Remote {
+        url: Url,
+        headers: HeaderMap,
+        client: Client,
+    },
+}
+
+/// convert the error of a local database operation
+fn local_error(error: DatabaseError) -> GenericError {
+    format!("{:?}", error).into()
+}
+
+/// Whether an address is on the internet, rather than loopback, link-local or private
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local addresses, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // link-local addresses, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Whether replications may reach an address
+fn is_reachable(ip: IpAddr) -> bool {
+    config::get().replication.allow_private_hosts || is_public(ip)
+}
+
+/// Resolver of the hosts of remote databases, leaving out the addresses replications may not reach
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_reachable(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                let error: GenericError =
+                    format!("`{}` resolves to no public address", name.as_str()).into();
+                return Err(error);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+impl Endpoint {
+    /// Parse a remote database, given as a URL or as `{ "url", "headers" }`.
+    /// Credentials in the URL are sent as a Basic `Authorization` header.
+    pub fn remote(value: &Value) -> Result<Endpoint, GenericError> {
+        let (url, extra_headers) = match value {
+            Value::String(url) => (url.as_str(), None),
+            Value::Object(fields) => (
+                fields["url"].as_str().ok_or("the endpoint has no `url`")?,
+                fields.get("headers").and_then(Value::as_object),
+            ),
+            _ => return Err("endpoints are database names, URLs or objects".into()),
+        };
+
+        let mut url = Url::parse(url)?;
+        let literal = url
+            .host_str()
+            .and_then(|host| host.trim_matches(|c| c == '[' || c == ']').parse().ok());
+        if let Some(ip) = literal.filter(|_| url.scheme() != "p2p") {
+            if !is_reachable(ip) {
+                return Err(format!("replicating with `{}` is not allowed", ip).into());
+            }
+        }
+
+        let mut headers = HeaderMap::new();
+        if !url.username().is_empty() {
+            let credentials = format!("{}:{}", url.username(), url.password().unwrap_or_default());
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials)))?,
+            );
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+        }
+
+        // replications read and write the shards held by the node, rather than being coordinated
+        headers.insert(
+            FORWARDED_HEADER,
+            HeaderValue::from_str(&config::get().cluster.node)?,
+        );
+        for (name, value) in extra_headers.into_iter().flatten() {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value.as_str().unwrap_or_default())?,
+            );
+        }
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(REPLICATION_REQUEST_TIMEOUT))
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(Policy::none())
+            .build()?;
+
+        Ok(Endpoint::Remote {
+            url,
+            headers,
+            client,
+        })
+    }
+
+    /// name of the endpoint, without credentials
+    pub fn describe(&self) -> String {
+        match self {
+            Endpoint::Local { db_name, .. } => db_name.clone(),
+            Endpoint::Remote { url, .. } => url.to_string(),
+        }
+    }
+
+    /// send a request to a remote database, returning `None` when it answers 404
+    async fn request(
+        &self,
+        method: Method,
+        segments: &[&str],
+        query: &[(&str, String)],
+        body: Option<&Value>,
+    ) -> Result<Option<Value>, GenericError> {
+        let Endpoint::Remote {
+            url,
+            headers,
+            client,
+        } = self
+        else {
+            return Err("not a remote database".into());
+        };
+
+        let mut url = url.clone();
+        url.path_segments_mut()
+            .map_err(|_| "the URL cannot have a path")?
+            .pop_if_empty()
+            .extend(segments);
+
+        let (status, body) = if url.scheme() == "p2p" {
+            if !query.is_empty() {
+                url.query_pairs_mut()
+                    .extend_pairs(query.iter().map(|(key, value)| (key, value)));
+            }
+            send_p2p(&url, method, headers, body).await?
+        } else {
+            let mut request = client
+                .request(method, url)
+                .headers(headers.clone())
+                .query(query);
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+
+            let response = request.send().await?;
+            let status = response.status();
+            (status, response.json::<Value>().await.unwrap_or_default())
+        };
+
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(format!(
+                "{} answered {}: {}",
+                self.describe(),
+                status,
+                body["error"].as_str().unwrap_or_default()
+            )
+            .into());
+        }
+
+        Ok(Some(body))
+    }
+
+    /// return the name, document count and latest sequence number of the database,
+    /// or `None` if it does not exist
+    pub async fn info(&self) -> Result<Option<Value>, GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                ..
+            } => {
+                if !db::database_exists(config, application_did, db_name) {
+                    return Ok(None);
+                }
+                Ok(Some(
+                    db::database_info(application_did, db_name, config).map_err(local_error)?,
+                ))
+            }
+            Endpoint::Remote { .. } => self.request(Method::GET, &[], &[], None).await,
+        }
+    }
+
+    /// create the database
+    pub async fn create(&self) -> Result<(), GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                ..
+            } => db::create_database(config, application_did, db_name).map_err(local_error),
+            Endpoint::Remote { .. } => self.request(Method::PUT, &[], &[], None).await.map(|_| ()),
+        }
+    }
+
+    /// return the documents changed after the sequence number `since`, with all their leaf revisions
+    pub async fn changes(&self, since: u64, limit: usize) -> Result<Value, GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                ..
+            } => db::changes(application_did, db_name, since, Some(limit), true, config)
+                .map_err(local_error),
+            Endpoint::Remote { .. } => self
+                .request(
+                    Method::GET,
+                    &["_changes"],
+                    &[
+                        ("since", since.to_string()),
+                        ("limit", limit.to_string()),
+                        ("style", "all_docs".to_owned()),
+                    ],
+                    None,
+                )
+                .await?
+                .ok_or_else(|| "the database does not exist".into()),
+        }
+    }
+
+    /// return the revisions of each document that the database does not have
+    pub async fn revs_diff(&self, revs: &Map<String, Value>) -> Result<Value, GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                ..
+            } => db::revs_diff(application_did, db_name, revs, config).map_err(local_error),
+            Endpoint::Remote { .. } => self
+                .request(
+                    Method::POST,
+                    &["_revs_diff"],
+                    &[],
+                    Some(&Value::Object(revs.clone())),
+                )
+                .await?
+                .ok_or_else(|| "the database does not exist".into()),
+        }
+    }
+
+    /// read every leaf revision of a document with its revision history
+    pub async fn fetch_revs(&self, doc_id: &str) -> Result<Vec<Value>, GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                decrypt,
+                ..
+            } => match db::fetch_open_revs(application_did, db_name, doc_id, config) {
+                Ok(mut documents) => {
+                    for document in documents.iter_mut() {
+                        encryption::decrypt_fields(
+                            config,
+                            application_did,
+                            db_name,
+                            document,
+                            &|_| *decrypt,
+                        );
+                    }
+                    Ok(documents)
+                }
+                Err(DatabaseError::MissingDocument) => Ok(Vec::new()),
+                Err(e) => Err(local_error(e)),
+            },
+            Endpoint::Remote { .. } => Ok(self
+                .request(
+                    Method::GET,
+                    &[doc_id],
+                    &[("revs", "true".to_owned()), ("open_revs", "all".to_owned())],
+                    None,
+                )
+                .await?
+                .and_then(|leaves| leaves.as_array().cloned())
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|leaf| leaf.get("ok").cloned())
+                .collect()),
+        }
+    }
+
+    /// write documents keeping their revisions, returning the outcome for each of them
+    pub async fn bulk_docs(&self, docs: Vec<Value>) -> Result<Vec<Value>, GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                did_queue,
+                ..
+            } => {
+                let mut results = Vec::with_capacity(docs.len());
+                for doc in docs {
+                    let doc_id = doc["_id"].clone();
+                    results.push(
+                        match db::write_replicated_document(
+                            application_did,
+                            db_name,
+                            doc,
+                            config,
+                            did_queue,
+                        )
+                        .await
+                        {
+                            Ok(result) => result,
+                            Err(e) => json!({ "id": doc_id, "error": format!("{:?}", e) }),
+                        },
+                    );
+                }
+                Ok(results)
+            }
+            Endpoint::Remote { .. } => {
+                let results = self
+                    .request(
+                        Method::POST,
+                        &["_bulk_docs"],
+                        &[],
+                        Some(&json!({ "docs": docs, "new_edits": false })),
+                    )
+                    .await?
+                    .ok_or("the database does not exist")?;
+                Ok(results.as_array().cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    /// read a local document
+    pub async fn local(&self, doc_id: &str) -> Result<Option<Value>, GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                ..
+            } => match db::fetch_local_document(application_did, db_name, doc_id, config) {
+                Ok(document) => Ok(Some(document)),
+                Err(DatabaseError::MissingDocument) => Ok(None),
+                Err(e) => Err(local_error(e)),
+            },
+            Endpoint::Remote { .. } => {
+                self.request(Method::GET, &["_local", doc_id], &[], None)
+                    .await
+            }
+        }
+    }
+
+    /// write a local document
+    pub async fn put_local(&self, doc_id: &str, document: &Value) -> Result<(), GenericError> {
+        match self {
+            Endpoint::Local {
+                application_did,
+                db_name,
+                config,
+                ..
+            } => db::write_local_document(application_did, db_name, doc_id, document, config)
+                .map_err(local_error),
+            Endpoint::Remote { .. } => self
+                .request(Method::PUT, &["_local", doc_id], &[], Some(document))
+                .await
+                .map(|_| ()),
+        }
+    }
+}
+
+/// Replication of the changes of a source database into a target database
+pub struct Replication {
+    pub id: String,
+    application_did: Did,
+    source: Endpoint,
+    target: Endpoint,
+    continuous: bool,
+    create_target: bool,
+    jobs: Jobs,
+    peer: Option<String>,
+}
+
+impl Replication {
+    pub fn new(
+        application_did: Did,
+        source: Endpoint,
+        target: Endpoint,
+        continuous: bool,
+        create_target: bool,
+        jobs: Jobs,
+    ) -> Self {
+        // the same replication always resumes from the same checkpoints
+        let id = util::hash_string(&format!(
+            "{}|{}|{}|{}",
+            application_did.0,
+            source.describe(),
+            target.describe(),
+            continuous
+        ))
+        .into_owned();
+
+        Self {
+            id,
+            application_did,
+            source,
+            target,
+            continuous,
+            create_target,
+            jobs,
+            peer: None,
+        }
+    }
+
+    /// mark the replication as kept alive for a peer
+    pub fn with_peer(mut self, name: &str) -> Self {
+        self.peer = Some(name.to_owned());
+        self
+    }
+
+    /// status of the replication before it runs
+    pub fn status(&self) -> ReplicationStatus {
+        let now = util::get_unix_epoch_time();
+        ReplicationStatus {
+            id: self.id.clone(),
+            application_did: self.application_did.0.clone(),
+            peer: self.peer.clone(),
+            source: self.source.describe(),
+            target: self.target.describe(),
+            continuous: self.continuous,
+            state: ReplicationState::Running,
+            docs_read: 0,
+            docs_written: 0,
+            doc_write_failures: 0,
+            checkpointed_source_seq: 0,
+            checkpointed_at: None,
+            pending_changes: 0,
+            error: None,
+            started_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// update the status of the job running the replication
+    async fn update(&self, update: impl FnOnce(&mut ReplicationStatus)) {
+        if let Some(job) = self.jobs.lock().await.get_mut(&self.id) {
+            update(&mut job.status);
+            job.status.updated_at = util::get_unix_epoch_time();
+        }
+    }
+
+    /// sequence number to resume from, recorded when both databases last agreed on it
+    async fn checkpoint(&self) -> Result<u64, GenericError> {
+        let source = self.source.local(&self.id).await?;
+        let target = self.target.local(&self.id).await?;
+
+        Ok(match (source, target) {
+            (Some(source), Some(target)) if source["last_seq"] == target["last_seq"] => {
+                source["last_seq"].as_u64().unwrap_or_default()
+            }
+            _ => 0,
+        })
+    }
+
+    /// replicate the changes made to the source since the last checkpoint
+    async fn replicate_changes(&self) -> Result<(), GenericError> {
+        if self.source.info().await?.is_none() {
+            return Err("the source database does not exist".into());
+        }
+        if self.target.info().await?.is_none() {
+            if !self.create_target {
+                return Err("the target database does not exist".into());
+            }
+            self.target.create().await?;
+        }
+
+        let mut since = self.checkpoint().await?;
+        self.update(|status| status.checkpointed_source_seq = since)
+            .await;
+        loop {
+            let changes = self.source.changes(since, REPLICATION_BATCH_SIZE).await?;
+            let results = changes["results"].as_array().cloned().unwrap_or_default();
+            if results.is_empty() {
+                self.update(|status| status.pending_changes = 0).await;
+                return Ok(());
+            }
+
+            // only fetch the revisions the target is missing
+            let mut revs = Map::new();
+            for change in &results {
+                let revs_of_doc = change["changes"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|change| change["rev"].clone())
+                    .collect::<Vec<_>>();
+                if let Some(doc_id) = change["id"].as_str() {
+                    revs.insert(doc_id.to_owned(), revs_of_doc.into());
+                }
+            }
+            let diff = self.target.revs_diff(&revs).await?;
+
+            let mut docs = Vec::new();
+            for (doc_id, missing) in diff.as_object().into_iter().flatten() {
+                let missing = missing["missing"].as_array().cloned().unwrap_or_default();
+                docs.extend(
+                    self.source
+                        .fetch_revs(doc_id)
+                        .await?
+                        .into_iter()
+                        .filter(|doc| missing.contains(&doc["_rev"])),
+                );
+            }
+
+            let docs_read = docs.len() as u64;
+            let failures = if docs.is_empty() {
+                0
+            } else {
+                self.target
+                    .bulk_docs(docs)
+                    .await?
+                    .iter()
+                    .filter(|result| result.get("error").is_some())
+                    .count() as u64
+            };
+
+            // record progress on both sides
+            since = changes["last_seq"].as_u64().unwrap_or(since);
+            let checkpoint = json!({
+                "last_seq": since,
+                "updated_at": util::get_unix_epoch_time()
+            });
+            self.source.put_local(&self.id, &checkpoint).await?;
+            self.target.put_local(&self.id, &checkpoint).await?;
+
+            let pending = changes["pending"].as_u64().unwrap_or_default();
+            self.update(|status| {
+                status.checkpointed_at = Some(util::get_unix_epoch_time());
+                status.pending_changes = pending;
+                status.docs_read += docs_read;
+                status.docs_written += docs_read - failures;
+                status.doc_write_failures += failures;
+                status.checkpointed_source_seq = since;
+            })
+            .await;
+        }
+    }
+
+    /// run the replication once, or until it is cancelled if it is continuous
+    pub async fn run(self) {
+        loop {
+            let result = self.replicate_changes().await;
+            let continuous = self.continuous;
+            self.update(|status| match result {
+                Ok(_) => {
+                    status.error = None;
+                    status.state = if continuous {
+                        ReplicationState::Running
+                    } else {
+                        ReplicationState::Completed
+                    };
+                }
+                Err(e) => {
+                    status.state = if continuous {
+                        ReplicationState::Crashing
+                    } else {
+                        ReplicationState::Failed
+                    };
+                    status.error = Some(e.to_string());
+                }
+            })
+            .await;
+
+            if !self.continuous {
+                return;
+            }
+
+            // wait for new changes
+            tokio::time::sleep(Duration::from_secs(REPLICATION_POLL_INTERVAL)).await;
+        }
+    }
+}
+
+/// Send a request to a node named by its peer ID in a `p2p` URL, over libp2p
+#[cfg(feature = "p2p")]
+async fn send_p2p(
+    url: &Url,
+    method: Method,
+    headers: &HeaderMap,
+    body: Option<&Value>,
+) -> Result<(StatusCode, Value), GenericError> {
+    let peer = url.host_str().ok_or("the URL names no node")?;
+    let uri = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+
+    let request = p2p::Request {
+        method: method.to_string(),
+        uri,
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect(),
+        body: body.cloned(),
+    };
+    let response = p2p::send(peer, request).await?;
+
+    Ok((StatusCode::from_u16(response.status)?, response.body))
+}
+
+#[cfg(not(feature = "p2p"))]
+async fn send_p2p(
+    _: &Url,
+    _: Method,
+    _: &HeaderMap,
+    _: Option<&Value>,
+) -> Result<(StatusCode, Value), GenericError> {
+    Err("the database was built without the libp2p transport".into())
+}
+
+/// A node the databases of an application are replicated with, configured in `[peers]`.
+/// Its credentials are stored in the root database, out of the config file.
+pub struct Peer {
+    pub name: String,
+    /// URL of the node, holding the Basic credentials of the application on it
+    pub url: Url,
+}
+
+impl Peer {
+    /// Parse a peer, which must hold the credentials of the application it replicates
+    pub fn parse(name: &str, url: &str) -> Result<Peer, GenericError> {
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err("peer names only contain letters, digits, `-` and `_`".into());
+        }
+
+        // nodes are reached by their peer ID over libp2p when the database is built with it
+        let schemes: &[&str] = if cfg!(feature = "p2p") {
+            &["http", "https", "p2p"]
+        } else {
+            &["http", "https"]
+        };
+
+        let url = Url::parse(url)?;
+        if !schemes.contains(&url.scheme()) || url.username().is_empty() {
+            return Err(
+                "peer URLs are HTTP or p2p URLs holding the Basic credentials of the application"
+                    .into(),
+            );
+        }
+
+        Ok(Peer {
+            name: name.to_owned(),
+            url,
+        })
+    }
+
+    /// application whose databases are replicated, whose address is the username of the URL
+    pub fn application_did(&self) -> Did {
+        Did(format!("did:sam:apps:{}", self.url.username()))
+    }
+
+    /// URL of the node, without credentials
+    pub fn describe(&self) -> String {
+        let mut url = self.url.clone();
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        url.to_string()
+    }
+
+    /// a database of the peer
+    fn database(&self, db_name: &str) -> Result<Endpoint, GenericError> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| "the URL cannot have a path")?
+            .pop_if_empty()
+            .push(db_name);
+
+        Endpoint::remote(&Value::String(url.to_string()))
+    }
+
+    /// databases of the application on the peer
+    async fn all_dbs(&self) -> Result<Vec<String>, GenericError> {
+        let node = Endpoint::remote(&Value::String(self.url.to_string()))?;
+        let dbs = node
+            .request(Method::GET, &["_all_dbs"], &[], None)
+            .await?
+            .unwrap_or_default();
+
+        Ok(dbs
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|db| db.as_str().map(str::to_owned))
+            .collect())
+    }
+}
+
+/// peers configured in `[peers]` with their stored credentials, skipping those that are invalid
+pub fn peers(config: &DbConfig) -> Vec<Peer> {
+    config::get()
+        .peers
+        .iter()
+        .filter_map(|(name, host)| {
+            let mut url = Url::parse(host).ok()?;
+            // credentials are stored apart, unless they have not been moved out of the file yet
+            if url.username().is_empty() {
+                let credentials = db::peer_credentials(config, name).ok()??;
+                let (username, password) = credentials.split_once(':')?;
+                url.set_username(username).ok()?;
+                url.set_password(Some(password)).ok()?;
+            }
+            Peer::parse(name, url.as_str()).ok()
+        })
+        .collect()
+}
+
+/// Move the credentials of the peers in `[peers]` out of the config file, into the root database
+pub fn migrate_peer_credentials(config: &DbConfig) -> Result<usize, GenericError> {
+    let mut moved = 0;
+    for (name, url) in config::get().peers.iter() {
+        let Ok(peer) = Peer::parse(name, url) else {
+            continue;
+        };
+
+        let credentials = format!(
+            "{}:{}",
+            peer.url.username(),
+            peer.url.password().unwrap_or_default()
+        );
+        db::store_peer_credentials(config, name, &credentials).map_err(local_error)?;
+        // only remove the credentials from the config file once they are safely stored
+        if !util::write_config("peers", name, &peer.describe()) {
+            return Err(format!("could not rewrite peer `{}` in the config file", name).into());
+        }
+        moved += 1;
+    }
+
+    Ok(moved)
+}
+
+/// Start the replications missing in both directions between each database of an application
+/// and its peers, configured or discovered, and stop those of peers that were removed or changed
+pub async fn sync_peers(
+    config: &DbConfig,
+    did_queue: &Arc<Mutex<DidQueue>>,
+    jobs: &Jobs,
+    discovery: &Discovery,
+) {
+    let mut peers = peers(config);
+    peers.extend(discovery.peers(config).await);
+
+    jobs.lock().await.retain(|_, job| {
+        let configured = match &job.peer_url {
+            Some(url) => peers.iter().any(|peer| peer.url.as_str() == url),
+            None => true,
+        };
+        // replications whose task ended unexpectedly are started again
+        let alive =
+            job.peer_url.is_none() || job.task.as_ref().is_some_and(|task| !task.is_finished());
+
+        if !configured || !alive {
+            if let Some(task) = &job.task {
+                task.abort();
+            }
+        }
+        configured && alive
+    });
+
+    for peer in &peers {
+        let application_did = peer.application_did();
+        if !matches!(db::fetch_application(config, &application_did), Ok(Some(_))) {
+            continue;
+        }
+
+        // databases missing on either side are created by the replication
+        let mut dbs = db::all_dbs(config, &application_did).unwrap_or_default();
+        dbs.extend(peer.all_dbs().await.unwrap_or_default());
+        dbs.sort();
+        dbs.dedup();
+
+        for db_name in dbs {
+            // each replication owns its endpoints
+            let (Ok(pull_source), Ok(push_target)) =
+                (peer.database(&db_name), peer.database(&db_name))
+            else {
+                continue;
+            };
+            let local = |db_name: &str| Endpoint::Local {
+                application_did: application_did.clone(),
+                db_name: db_name.to_owned(),
+                config: config.clone(),
+                did_queue: did_queue.clone(),
+                decrypt: true,
+            };
+
+            for (source, target) in [
+                (pull_source, local(&db_name)),
+                (local(&db_name), push_target),
+            ] {
+                let replication = Replication::new(
+                    application_did.clone(),
+                    source,
+                    target,
+                    true,
+                    true,
+                    jobs.clone(),
+                )
+                .with_peer(&peer.name);
+
+                let mut guard = jobs.lock().await;
+                if guard.contains_key(&replication.id) {
+                    continue;
+                }
+
+                let id = replication.id.clone();
+                let status = replication.status();
+                let task = tokio::spawn(replication.run()).abort_handle();
+                guard.insert(
+                    id,
+                    Job {
+                        status,
+                        task: Some(task),
+                        peer_url: Some(peer.url.to_string()),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Summarize the replications kept alive with a peer
+pub async fn peer_status(peer: &Peer, jobs: &Jobs) -> Value {
+    let jobs = jobs.lock().await;
+    let replications = jobs
+        .values()
+        .filter(|job| job.status.peer.as_ref() == Some(&peer.name))
+        .map(|job| job.status.clone())
+        .collect::<Vec<_>>();
+
+    let errors = replications
+        .iter()
+        .filter_map(|status| {
+            status.error.as_ref().map(|error| {
+                json!({
+                    "source": status.source,
+                    "target": status.target,
+                    "error": error
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "name": peer.name,
+        "url": peer.describe(),
+        "last_checkpoint": replications.iter().filter_map(|status| status.checkpointed_at).max(),
+        "lag": replications.iter().map(|status| status.pending_changes).sum::<u64>(),
+        "errors": errors,
+        "replications": replications
+    })
+}
@@ -1,12 +1,22 @@
 /// Copyright (c) Algorealm, Inc.
-use rocket::http::Status;
+use rocket::data::{Data, Limits};
+use rocket::http::{ContentType, Status};
 use rocket::response::status::{self, Custom};
-use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::serde::json::{
+    serde_json::{json, Map},
+    Json, Value,
+};
 use rocket::Request;
 use rocket::State;
 
-use crate::{contract, db, prelude::*, util};
+#[cfg(feature = "p2p")]
+use crate::p2p;
+use crate::{
+    cluster, config, contract, db, discovery, encryption, keystore, logging, metrics, prelude::*,
+    replicator, rpc, session, tasks, util,
+};
 use async_std::sync::Mutex;
+use reqwest::Method;
 use std::sync::Arc;
 
 #[post("/_auth", data = "<auth_payload>")]
@@ -17,100 +27,175 @@ async fn init_application(
     let credentials = auth_payload.into_inner();
 
     // check the DID for lexical compliance
-    if DbConfig::is_valid_did(&credentials.did.0, false) {
-        return Custom(
+    if !DbConfig::is_valid_did(&credentials.did.0, false) {
+        Custom(
             Status::InternalServerError,
             json!({
                 "error" : format!("DID `{}` is not well formed", credentials.did.0)
             }),
-        );
+        )
     } else {
         // check that DID and password is recognized onchain
         if contract::authenticate(&credentials).await {
             // check that the SS58 DID suffix matches the onchain authenticated account address
-            if contract::did_exists(&config, &credentials.did).await {
-                // set the auth details, only if it hasn't been set
-                // read config file
-                let (hash_secret, application_did) = (
-                    util::read_config("auth", "secret"),
-                    util::read_config("auth", "application_did"),
-                );
+            if contract::did_exists(config, &credentials.did).await {
+                // each application is registered separately and owns its own namespace
+                match db::fetch_application(config, &credentials.did) {
+                    Ok(None) => {
+                        // TODO!
+                        // spawn task to manage data operations
 
-                if hash_secret.is_empty() {
-                    // TODO!
-                    // spawn task to manage data operations
+                        // generate new password
+                        let secret_password = util::generate_strong_password(10);
 
-                    // generate new password
-                    let secret_password = util::generate_strong_password(10);
+                        // record the application in the registry
+                        let registered =
+                            util::hash_secret(&credentials.secret).is_some_and(|secret_hash| {
+                                db::register_application(
+                                    config,
+                                    &credentials.did,
+                                    &secret_hash,
+                                    &secret_password,
+                                )
+                                .is_ok()
+                            });
 
-                    // write details to config file
-                    if util::write_config("auth", "application_did", &credentials.did.0)
-                        && util::write_config("auth", "auth_secret", &secret_password)
-                        && util::write_config(
-                            "auth",
-                            "secret",
-                            util::hash_string(&credentials.secret).as_ref(),
-                        )
-                    {
-                        return Custom(
-                            Status::Ok,
-                            json!({
-                                "ok" : true,
-                                "secret": secret_password
-                            }),
-                        );
-                    } else {
-                        return Custom(
-                            Status::InternalServerError,
-                            json!({
-                                "error" : "Could not modify config file"
-                            }),
-                        );
+                        if registered {
+                            Custom(
+                                Status::Ok,
+                                with_session(
+                                    config,
+                                    &credentials.did,
+                                    json!({
+                                        "ok" : true,
+                                        "secret": secret_password
+                                    }),
+                                ),
+                            )
+                        } else {
+                            Custom(
+                                Status::InternalServerError,
+                                json!({
+                                    "error" : "Could not register application"
+                                }),
+                            )
+                        }
                     }
-                } else {
-                    // generate new password
-                    let secret_password = util::generate_strong_password(10);
+                    Ok(Some(app)) => {
+                        let secret_hash = app["secret"].as_str().unwrap_or_default();
 
-                    // check whether account has been initialized before and we can continue session
-                    if util::hash_string(&credentials.secret) == hash_secret {
-                        // TODO!
-                        // spawn task to manage data operations
+                        // check whether account has been initialized before and we can continue session
+                        if util::verify_secret(&credentials.secret, secret_hash) {
+                            // TODO!
+                            // spawn task to manage data operations
+
+                            // the previous password stops working, so the new one must be persisted
+                            let secret_password = util::generate_strong_password(10);
+                            if db::rotate_auth_secret(config, &credentials.did, &secret_password, 0)
+                                .is_err()
+                            {
+                                return Custom(
+                                    Status::InternalServerError,
+                                    json!({
+                                        "error" : "Could not update application registry"
+                                    }),
+                                );
+                            }
 
-                        return Custom(
-                            Status::Ok,
+                            // replace digests stored before secrets were hashed with Argon2
+                            if !secret_hash.starts_with("$argon2") {
+                                if let Some(secret_hash) = util::hash_secret(&credentials.secret) {
+                                    let _ = db::update_application_secret(
+                                        config,
+                                        &credentials.did,
+                                        &secret_hash,
+                                    );
+                                }
+                            }
+
+                            return Custom(
+                                Status::Ok,
+                                with_session(
+                                    config,
+                                    &credentials.did,
+                                    json!({
+                                        "ok" : true,
+                                        "secret": secret_password
+                                    }),
+                                ),
+                            );
+                        }
+
+                        Custom(
+                            Status::Unauthorized,
                             json!({
-                                "ok" : true,
-                                "secret": secret_password
+                                "error" : format!("DID `{}` already initialized in database.", credentials.did.0)
                             }),
-                        );
+                        )
                     }
-
-                    return Custom(
-                        Status::Unauthorized,
+                    Err(_) => Custom(
+                        Status::InternalServerError,
                         json!({
-                            "error" : format!("DID `{}` already initialized in database.", application_did)
+                            "error" : "Could not read application registry"
                         }),
-                    );
+                    ),
                 }
             } else {
-                return Custom(
+                Custom(
                     Status::NotFound,
                     json!({
                         "error" : "provided SS58 DID suffix does not match address of authenticated account onchain."
                     }),
-                );
+                )
             }
         } else {
-            return Custom(
+            Custom(
                 Status::NotFound,
                 json!({
                     "error" : "provided details not registered onchain"
                 }),
-            );
+            )
         }
     }
 }
 
+/// add the tokens of a new full-access session to a response
+fn with_session(config: &DbConfig, application_did: &Did, response: Value) -> Value {
+    match session::issue(config, application_did, None, None, None) {
+        Ok(tokens) => util::merge_json_values(response, tokens),
+        Err(_) => response,
+    }
+}
+
+/// replace the auth secret of the application.
+/// The current secret keeps working for `grace` seconds, so that clients can be updated.
+#[post("/_auth/rotate?<grace>")]
+pub fn rotate_secret(
+    grace: Option<u64>,
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+) -> (Status, Value) {
+    let secret_password = util::generate_strong_password(10);
+    let grace = grace.unwrap_or_default();
+
+    match db::rotate_auth_secret(config, &auth.application_did, &secret_password, grace) {
+        Ok(_) => (
+            Status::Ok,
+            json!({
+                "ok": true,
+                "secret": secret_password,
+                "grace": grace
+            }),
+        ),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not rotate secret."
+            }),
+        ),
+    }
+}
+
 #[get("/")]
 pub fn index(config: &State<DbConfig>) -> Value {
     json!({
@@ -119,23 +204,114 @@ pub fn index(config: &State<DbConfig>) -> Value {
         "vendor": {
             "name": "Algorealm, Inc."
         },
-        "application_did": util::read_config("auth", "application_did")
+        "applications": db::application_count(config).unwrap_or_default()
     })
 }
 
-/// create a database
-#[put("/<db_name>")]
-pub fn create_db(db_name: &str, _auth: BasicAuth, config: &State<DbConfig>) -> (Status, Value) {
+/// check that the root database can be read, returning the number of applications
+fn storage_check(config: &DbConfig) -> (bool, Value) {
+    match db::application_count(config) {
+        Ok(applications) => (true, json!({ "ok": true, "applications": applications })),
+        Err(e) => (false, json!({ "ok": false, "error": format!("{:?}", e) })),
+    }
+}
+
+/// liveness of the node, which is up as long as it can read its data
+#[get("/_up")]
+pub fn up(config: &State<DbConfig>) -> (Status, Value) {
+    let (ok, storage) = storage_check(config);
+    let status = if ok {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (
+        status,
+        json!({
+            "status": if ok { "ok" } else { "degraded" },
+            "checks": { "storage": storage }
+        }),
+    )
+}
+
+/// readiness of the node to serve applications: its data can be read, an application is
+/// initialized, the chain can be reached and DIDs are checked without falling behind
+#[get("/_ready")]
+pub async fn ready(
+    config: &State<DbConfig>,
+    did_queue: &State<Arc<Mutex<DidQueue>>>,
+) -> (Status, Value) {
+    let (storage_ok, storage) = storage_check(config);
+    let application_ok = storage["applications"].as_u64().unwrap_or_default() > 0;
+    let chain = rpc::ping().await;
+    let pending = did_queue.lock().await.len();
+    let queue_ok = pending <= DID_QUEUE_READY_LIMIT;
+
+    let ok = storage_ok && application_ok && chain.is_ok() && queue_ok;
+    let status = if ok {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (
+        status,
+        json!({
+            "status": if ok { "ok" } else { "degraded" },
+            "checks": {
+                "storage": storage,
+                "application": { "ok": application_ok },
+                "chain": match chain {
+                    Ok(_) => json!({ "ok": true }),
+                    Err(e) => json!({ "ok": false, "error": e.to_string() }),
+                },
+                "did_queue": {
+                    "ok": queue_ok,
+                    "pending": pending,
+                    "limit": DID_QUEUE_READY_LIMIT
+                }
+            }
+        }),
+    )
+}
+
+/// create a database.
+/// In a cluster, `n` sets the number of nodes holding each of its documents.
+#[put("/<db_name>?<n>")]
+pub async fn create_db(
+    db_name: &str,
+    n: Option<usize>,
+    auth: Access,
+    config: &State<DbConfig>,
+    request: ClusterRequest,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Admin) {
+        return forbidden();
+    }
+
+    if n == Some(0) {
+        return (
+            Status::BadRequest,
+            json!({
+                "error": "Documents must be held by at least one node."
+            }),
+        );
+    }
+
     // check if database is in existence
     let config = config.inner();
-    if !db::database_exists(db_name) {
+    if !db::database_exists(config, &auth.application_did, db_name) {
         // create new database
-        match db::create_database(config, &db_name) {
+        let created =
+            db::create_database(config, &auth.application_did, db_name).and_then(|_| match n {
+                Some(n) => db::set_database_replicas(config, &auth.application_did, db_name, n),
+                None => Ok(()),
+            });
+        match created {
             Ok(_) => (
                 Status::Created,
-                json!({
-                    "ok": "true"
-                }),
+                cluster_outcome(cluster::broadcast(&request, Method::PUT).await),
             ),
             Err(_) => (
                 Status::InternalServerError,
@@ -156,17 +332,24 @@ pub fn create_db(db_name: &str, _auth: BasicAuth, config: &State<DbConfig>) -> (
 
 /// delete a database
 #[delete("/<db_name>")]
-pub fn delete_db(db_name: &str, config: &State<DbConfig>, _auth: BasicAuth) -> (Status, Value) {
+pub async fn delete_db(
+    db_name: &str,
+    config: &State<DbConfig>,
+    auth: Access,
+    request: ClusterRequest,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Admin) {
+        return forbidden();
+    }
+
     // check if database is in existence
     let config = config.inner();
-    if db::database_exists(db_name) {
+    if db::database_exists(config, &auth.application_did, db_name) {
         // delete the database
-        match db::delete_database(config, &db_name) {
+        match db::delete_database(config, &auth.application_did, db_name) {
             Ok(_) => (
                 Status::Ok,
-                json!({
-                    "ok": "true"
-                }),
+                cluster_outcome(cluster::broadcast(&request, Method::DELETE).await),
             ),
             Err(_) => (
                 Status::InternalServerError,
@@ -185,11 +368,27 @@ pub fn delete_db(db_name: &str, config: &State<DbConfig>, _auth: BasicAuth) -> (
     }
 }
 
-/// retrieve a list of all databases
+/// response to a database operation repeated on the other nodes of the cluster
+fn cluster_outcome(failed_nodes: Vec<String>) -> Value {
+    if failed_nodes.is_empty() {
+        json!({ "ok": "true" })
+    } else {
+        json!({ "ok": "true", "failed_nodes": failed_nodes })
+    }
+}
+
+/// retrieve a list of all databases owned by the application
 #[get("/_all_dbs")]
-pub fn all_dbs(config: &State<DbConfig>) -> (Status, Value) {
-    match db::all_dbs(config) {
-        Ok(dbs) => (Status::Ok, json!(dbs)),
+pub fn all_dbs(config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    match db::all_dbs(config, &auth.application_did) {
+        Ok(dbs) => (
+            Status::Ok,
+            // API keys only see the databases they can read
+            json!(dbs
+                .into_iter()
+                .filter(|db| auth.permits(db, Permission::Read))
+                .collect::<Vec<_>>()),
+        ),
         Err(_) => (
             Status::InternalServerError,
             json!({
@@ -213,25 +412,104 @@ pub fn uuids(count: Option<u32>) -> Value {
     json!(uuids)
 }
 
+/// read a document held by other nodes of the cluster, from `r` of them
+#[get("/<_db_name>/<_doc_id>", rank = 1)]
+pub async fn coordinate_fetch_document(
+    _db_name: &str,
+    _doc_id: &str,
+    coordinator: Coordinator,
+) -> (Status, Value) {
+    cluster::read(&coordinator.replicas, &coordinator.request, coordinator.r).await
+}
+
+/// write a document held by other nodes of the cluster, to `w` of them
+#[put("/<_db_name>/<_doc_id>", data = "<body>", rank = 1)]
+pub async fn coordinate_update_document(
+    _db_name: &str,
+    _doc_id: &str,
+    coordinator: Coordinator,
+    body: Data<'_>,
+    limits: &Limits,
+) -> (Status, Value) {
+    let body = match body
+        .open(limits.get("json").unwrap_or(Limits::JSON))
+        .into_bytes()
+        .await
+    {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        _ => {
+            return (
+                Status::PayloadTooLarge,
+                json!({
+                    "error": "The document is too large."
+                }),
+            )
+        }
+    };
+
+    cluster::write(
+        &coordinator.replicas,
+        &coordinator.request,
+        Method::PUT,
+        Some(body),
+        coordinator.w,
+    )
+    .await
+}
+
+/// delete a document held by other nodes of the cluster, from `w` of them
+#[delete("/<_db_name>/<_doc_id>", rank = 1)]
+pub async fn coordinate_delete_document(
+    _db_name: &str,
+    _doc_id: &str,
+    coordinator: Coordinator,
+) -> (Status, Value) {
+    cluster::write(
+        &coordinator.replicas,
+        &coordinator.request,
+        Method::DELETE,
+        None,
+        coordinator.w,
+    )
+    .await
+}
+
 /// write data
-#[put("/<db_name>/<doc_id>", data = "<data_wrapper>")]
+#[put("/<db_name>/<doc_id>", data = "<data_wrapper>", rank = 2)]
 pub async fn update_document(
     db_name: &str,
     doc_id: &str,
     did: Did,
     config: &State<DbConfig>,
-    _auth: BasicAuth,
+    auth: Access,
     data_wrapper: Json<DataWrapper<Value>>,
     did_queue: &State<Arc<Mutex<std::collections::VecDeque<DbEntry>>>>,
 ) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Write) || !auth.permits_user(&did) {
+        return forbidden();
+    }
+
     // check if database is in existence
     let config = config.inner();
     let data = data_wrapper.into_inner();
     let did_queue = did_queue.inner();
-    if db::database_exists(db_name) {
+    if db::database_exists(config, &auth.application_did, db_name) {
         // write to it
-        match db::update_document(db_name, doc_id, did, config, data, &did_queue).await {
-            Ok(json) => (Status::Ok, json),
+        match db::update_document(
+            &auth.application_did,
+            db_name,
+            doc_id,
+            did.clone(),
+            config,
+            data,
+            did_queue,
+        )
+        .await
+        {
+            Ok(json) => {
+                audit(config, &auth, db_name, doc_id, "write", Some(&did));
+                (Status::Ok, json)
+            }
             Err(e) => match e {
                 DatabaseError::DocumentUpdateConflict => (
                     Status::Conflict,
@@ -245,6 +523,18 @@ pub async fn update_document(
                         "error": "User DID conflict"
                     }),
                 ),
+                DatabaseError::MissingDocument => (
+                    Status::NotFound,
+                    json!({
+                        "error": "The document does not exist"
+                    }),
+                ),
+                DatabaseError::DataKeyShredded => (
+                    Status::Gone,
+                    json!({
+                        "error": "The document was encrypted with a key that has been destroyed"
+                    }),
+                ),
                 _ => (
                     Status::InternalServerError,
                     json!({
@@ -263,15 +553,78 @@ pub async fn update_document(
     }
 }
 
-/// read data
-#[get("/<db_name>/<doc_id>")]
-pub fn fetch_document(db_name: &str, doc_id: &str, config: &State<DbConfig>) -> (Status, Value) {
+/// read data.
+/// With `revs=true`, the revision history and owner of the document are included for replication.
+/// `rev` reads a revision in conflict instead of the winning one, `conflicts=true` lists those
+/// revisions in `_conflicts`, and `open_revs=all` returns every leaf revision.
+#[get("/<db_name>/<doc_id>?<query..>", rank = 2)]
+pub fn fetch_document(
+    db_name: &str,
+    doc_id: &str,
+    query: DocumentQuery,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
     // check if database is in existence
     let config = config.inner();
-    if db::database_exists(db_name) {
+    if db::database_exists(config, &auth.application_did, db_name) {
+        if !permits_document(config, &auth, db_name, doc_id) {
+            return forbidden();
+        }
+
         // fetch document
-        match db::fetch_document(db_name, doc_id, config) {
-            Ok(json) => (Status::Ok, json),
+        let revs = query.revs.unwrap_or_default();
+        let rev = query.rev.as_deref();
+        let document = if query.open_revs.as_deref() == Some("all") {
+            db::fetch_open_revs(&auth.application_did, db_name, doc_id, config).map(|leaves| {
+                Value::Array(
+                    leaves
+                        .into_iter()
+                        .map(|leaf| json!({ "ok": leaf }))
+                        .collect(),
+                )
+            })
+        } else if revs || rev.is_some() {
+            db::fetch_document_revs(&auth.application_did, db_name, doc_id, rev, config).map(
+                |mut document| {
+                    if let (false, Value::Object(fields)) = (revs, &mut document) {
+                        for field in ["_id", "_did", "_revisions"] {
+                            fields.remove(field);
+                        }
+                    }
+                    document
+                },
+            )
+        } else {
+            db::fetch_document(&auth.application_did, db_name, doc_id, config)
+        };
+        let document = match (document, query.conflicts.unwrap_or_default()) {
+            (Ok(mut document), true) if document.is_object() => {
+                db::conflicts(&auth.application_did, db_name, doc_id, config).map(|conflicts| {
+                    if !conflicts.is_empty() {
+                        document["_conflicts"] = conflicts.into();
+                    }
+                    document
+                })
+            }
+            (document, _) => document,
+        };
+        match document {
+            Ok(mut json) => {
+                encryption::decrypt_fields(
+                    config,
+                    &auth.application_did,
+                    db_name,
+                    &mut json,
+                    &field_reader(&auth, db_name),
+                );
+                audit(config, &auth, db_name, doc_id, "read", None);
+                (Status::Ok, json)
+            }
             Err(e) => match e {
                 DatabaseError::MissingDocument => (
                     Status::NotFound,
@@ -279,6 +632,12 @@ pub fn fetch_document(db_name: &str, doc_id: &str, config: &State<DbConfig>) ->
                         "error": "The document does not exist"
                     }),
                 ),
+                DatabaseError::DataKeyShredded => (
+                    Status::Gone,
+                    json!({
+                        "error": "The document was encrypted with a key that has been destroyed"
+                    }),
+                ),
                 _ => (
                     Status::InternalServerError,
                     json!({
@@ -297,23 +656,136 @@ pub fn fetch_document(db_name: &str, doc_id: &str, config: &State<DbConfig>) ->
     }
 }
 
-/// get document metadata
-#[delete("/<db_name>/<doc_id>")]
-pub fn delete_document(db_name: &str, doc_id: &str, config: &State<DbConfig>) -> (Status, Value) {
-    // check if database is in existence
+/// find the documents whose fields equal the values of a selector.
+/// On a cluster, the documents are gathered from every node.
+#[post("/<db_name>/_find", data = "<payload>")]
+pub async fn find_documents(
+    db_name: &str,
+    payload: Json<FindPayload>,
+    config: &State<DbConfig>,
+    auth: Access,
+    cluster_request: ClusterRequest,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
     let config = config.inner();
-    if db::database_exists(db_name) {
-        // fetch document
-        match db::delete_document(db_name, doc_id, config) {
-            Ok(_) => (Status::Ok, json!({ "ok": true})),
-            Err(_) => (
-                Status::InternalServerError,
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return (
+            Status::NotFound,
+            json!({
+                "error": "The database does not exist."
+            }),
+        );
+    }
+
+    // the other nodes are sent the selector as given
+    let request = json!({ "selector": payload.selector }).to_string();
+    let replies =
+        cluster::scatter(&cluster_request, Method::POST, Some(request.into_bytes())).await;
+    if let Some(replies) = &replies {
+        let n = cluster::replica_count(config, &auth.application_did, db_name);
+        let mut reached = vec![cluster::node_name()];
+        reached.extend(replies.iter().map(|(node, _)| node.clone()));
+        if !cluster::covers(&auth.application_did, db_name, n, &reached) {
+            return (
+                Status::ServiceUnavailable,
                 json!({
-                    "error": "Could not delete document"
+                    "error": "Some shards of the database could not be reached."
                 }),
-            ),
+            );
         }
-    } else {
+    }
+
+    // encrypted fields can only be matched when they are encrypted deterministically
+    let mut selector = payload.into_inner().selector;
+    let docs = encryption::seal_selector(config, &auth.application_did, db_name, &mut selector)
+        .and_then(|_| db::find_documents(&auth.application_did, db_name, &selector, config));
+
+    match docs {
+        Ok(mut docs) => {
+            // sessions of a user only find the documents of the user
+            docs.retain(|doc| {
+                doc["id"]
+                    .as_str()
+                    .or(doc["_id"].as_str())
+                    .is_none_or(|doc_id| permits_document(config, &auth, db_name, doc_id))
+            });
+
+            let can_decrypt = field_reader(&auth, db_name);
+            for doc in docs.iter_mut() {
+                encryption::decrypt_fields(
+                    config,
+                    &auth.application_did,
+                    db_name,
+                    doc,
+                    &can_decrypt,
+                );
+                // replicated documents may only carry `_id`
+                if let Some(doc_id) = doc["id"].as_str().or(doc["_id"].as_str()) {
+                    audit(config, &auth, db_name, doc_id, "query", None);
+                }
+            }
+
+            if let Some(replies) = replies {
+                let gathered = replies
+                    .into_iter()
+                    .filter_map(|(_, reply)| reply["docs"].as_array().cloned())
+                    .flatten();
+                docs = cluster::latest_revisions(docs.into_iter().chain(gathered));
+            }
+            (Status::Ok, json!({ "docs": docs }))
+        }
+        Err(DatabaseError::EncryptedFieldQuery) => (
+            Status::BadRequest,
+            json!({
+                "error": "Encrypted fields can only be queried when they are encrypted deterministically."
+            }),
+        ),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not query database."
+            }),
+        ),
+    }
+}
+
+/// set the fields of the documents of a database that are encrypted on write
+#[put("/<db_name>/_encryption", data = "<policy>")]
+pub fn set_encryption_policy(
+    db_name: &str,
+    policy: Json<EncryptionPolicy>,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Admin) {
+        return forbidden();
+    }
+
+    let config = config.inner();
+    let policy = policy.into_inner();
+    if config.master_key.is_none() {
+        return (
+            Status::BadRequest,
+            json!({
+                "error": "Encryption at rest must be enabled to encrypt fields."
+            }),
+        );
+    }
+
+    // each DID encrypts with its own key, so equal values cannot be matched across documents
+    if policy.deterministic && policy.key == FieldKey::Did {
+        return (
+            Status::BadRequest,
+            json!({
+                "error": "Deterministic encryption requires the database key."
+            }),
+        );
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
         return (
             Status::NotFound,
             json!({
@@ -321,39 +793,1537 @@ pub fn delete_document(db_name: &str, doc_id: &str, config: &State<DbConfig>) ->
             }),
         );
     }
+
+    match db::set_encryption_policy(config, &auth.application_did, db_name, &policy) {
+        Ok(_) => (Status::Ok, json!({ "ok": true })),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not set encryption policy."
+            }),
+        ),
+    }
 }
 
-#[catch(404)]
-pub fn not_found(req: &Request) -> Value {
-    json!({
-        "error": format!("`{}` route not recognized", req.uri())
-    })
+/// get the field encryption policy of a database
+#[get("/<db_name>/_encryption")]
+pub fn encryption_policy(db_name: &str, config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return (
+            Status::NotFound,
+            json!({
+                "error": "The database does not exist."
+            }),
+        );
+    }
+
+    match db::encryption_policy(config, &auth.application_did, db_name) {
+        Ok(policy) => (Status::Ok, json!(policy)),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not fetch encryption policy."
+            }),
+        ),
+    }
 }
 
-#[catch(401)]
-pub fn unauthorized(_req: &Request) -> Value {
-    json!({
-        "error": "authentication details is incorrect"
-    })
+/// set how concurrent writes to the documents of a database are reconciled
+#[put("/<db_name>/_mode", data = "<payload>")]
+pub fn set_database_mode(
+    db_name: &str,
+    payload: Json<DatabaseModePayload>,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Admin) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::set_database_mode(config, &auth.application_did, db_name, payload.mode) {
+        Ok(_) => (Status::Ok, json!({ "ok": true })),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not set database mode."
+            }),
+        ),
+    }
 }
 
-#[catch(400)]
-pub fn bad_request(_req: &Request) -> Value {
-    json!({
-        "error": "Invalid or missing important header"
-    })
+/// get how concurrent writes to the documents of a database are reconciled
+#[get("/<db_name>/_mode")]
+pub fn database_mode(db_name: &str, config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::database_mode(config, &auth.application_did, db_name) {
+        Ok(mode) => (Status::Ok, json!({ "mode": mode })),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not fetch database mode."
+            }),
+        ),
+    }
 }
 
-pub fn routes() -> Vec<rocket::Route> {
-    routes![
-        index,
-        init_application,
-        create_db,
-        delete_db,
-        all_dbs,
-        uuids,
-        update_document,
-        fetch_document,
-        delete_document
-    ]
+/// verify the write journal of a database, reporting the first entry whose link is broken
+#[get("/<db_name>/_journal")]
+pub fn verify_journal(db_name: &str, config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::verify_journal(config, &auth.application_did, db_name) {
+        Ok(report) => (Status::Ok, report),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not verify the journal of the database."
+            }),
+        ),
+    }
+}
+
+/// decide whether a request may act on a document, which sessions of a user may only do on the
+/// documents of the user
+fn permits_document(config: &DbConfig, auth: &Access, db_name: &str, doc_id: &str) -> bool {
+    if auth.user_did.is_none() {
+        return true;
+    }
+
+    match db::document_owner(config, &auth.application_did, db_name, doc_id) {
+        Ok(Some(owner)) => auth.permits_user(&owner),
+        // the request goes on to find that the document does not exist
+        Ok(None) => true,
+        Err(_) => false,
+    }
+}
+
+/// decide whether a request may see the plaintext of an encrypted field,
+/// given the DID owning the field's key if it is not the database key
+fn field_reader<'a>(auth: &'a Access, db_name: &'a str) -> impl Fn(Option<&Did>) -> bool + 'a {
+    move |owner| {
+        auth.permits(db_name, Permission::Decrypt) && owner.is_none_or(|did| auth.permits_user(did))
+    }
+}
+
+/// get document metadata.
+/// `rev` deletes a revision in conflict instead of the winning one.
+#[delete("/<db_name>/<doc_id>?<rev>", rank = 2)]
+pub fn delete_document(
+    db_name: &str,
+    doc_id: &str,
+    rev: Option<&str>,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Delete) {
+        return forbidden();
+    }
+
+    // check if database is in existence
+    let config = config.inner();
+    if db::database_exists(config, &auth.application_did, db_name) {
+        if !permits_document(config, &auth, db_name, doc_id) {
+            return forbidden();
+        }
+
+        // fetch document
+        match db::delete_document(&auth.application_did, db_name, doc_id, rev, config) {
+            Ok(rev) => {
+                audit(config, &auth, db_name, doc_id, "delete", None);
+                (Status::Ok, json!({ "ok": true, "id": doc_id, "rev": rev }))
+            }
+            Err(DatabaseError::MissingDocument) => (
+                Status::NotFound,
+                json!({
+                    "error": "The document does not exist"
+                }),
+            ),
+            Err(DatabaseError::DocumentUpdateConflict) => (
+                Status::Conflict,
+                json!({
+                    "error": "The revision is not a current revision of the document"
+                }),
+            ),
+            Err(_) => (
+                Status::InternalServerError,
+                json!({
+                    "error": "Could not delete document"
+                }),
+            ),
+        }
+    } else {
+        return (
+            Status::NotFound,
+            json!({
+                "error": "The database does not exist."
+            }),
+        );
+    }
+}
+
+/// get the document count and latest sequence number of a database
+#[get("/<db_name>")]
+pub fn database_info(db_name: &str, config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::database_info(&auth.application_did, db_name, config) {
+        Ok(info) => (Status::Ok, info),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not read from database."
+            }),
+        ),
+    }
+}
+
+/// list the documents changed after the sequence number `since`.
+/// With `style=all_docs`, every leaf revision of the documents is listed.
+/// Sequence numbers are those of a node, so clustered nodes only list their changes to
+/// the other nodes.
+#[get("/<db_name>/_changes?<since>&<limit>&<style>")]
+pub fn changes(
+    db_name: &str,
+    since: Option<u64>,
+    limit: Option<usize>,
+    style: Option<&str>,
+    config: &State<DbConfig>,
+    auth: Access,
+    cluster_request: ClusterRequest,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !cluster_request.forwarded && cluster::is_clustered(&cluster::members()) {
+        return (
+            Status::NotImplemented,
+            json!({
+                "error": "The changes feed is not available on the nodes of a cluster."
+            }),
+        );
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::changes(
+        &auth.application_did,
+        db_name,
+        since.unwrap_or_default(),
+        limit,
+        style == Some("all_docs"),
+        config,
+    ) {
+        Ok(changes) => (Status::Ok, changes),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not read changes."
+            }),
+        ),
+    }
+}
+
+/// show the node holding each shard of a database
+#[get("/<db_name>/_shards")]
+pub fn shards(db_name: &str, config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    let n = cluster::replica_count(config, &auth.application_did, db_name);
+    (
+        Status::Ok,
+        json!({ "shards": cluster::placement(&auth.application_did, db_name, n) }),
+    )
+}
+
+/// list the documents of a database with conflicting revisions
+#[get("/<db_name>/_conflicts")]
+pub fn conflicts(db_name: &str, config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::documents_with_conflicts(&auth.application_did, db_name, config) {
+        Ok(docs) => (Status::Ok, json!({ "docs": docs })),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not read from database."
+            }),
+        ),
+    }
+}
+
+/// given revisions by document, return those the database does not have
+#[post("/<db_name>/_revs_diff", data = "<revs>")]
+pub fn revs_diff(
+    db_name: &str,
+    revs: Json<Map<String, Value>>,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    // replications pushing changes into the database need it as much as readers
+    if !auth.permits(db_name, Permission::Read) && !auth.permits(db_name, Permission::Write) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::revs_diff(&auth.application_did, db_name, &revs, config) {
+        Ok(diff) => (Status::Ok, diff),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not read from database."
+            }),
+        ),
+    }
+}
+
+/// write documents replicated from another node, keeping their revisions.
+/// On a cluster, each document is written on the nodes holding it.
+#[post("/<db_name>/_bulk_docs", data = "<payload>")]
+pub async fn bulk_docs(
+    db_name: &str,
+    payload: Json<BulkDocsPayload>,
+    config: &State<DbConfig>,
+    auth: Access,
+    cluster_request: ClusterRequest,
+    did_queue: &State<Arc<Mutex<DidQueue>>>,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Write) {
+        return forbidden();
+    }
+
+    let payload = payload.into_inner();
+    if payload.new_edits.unwrap_or(true) {
+        return (
+            Status::BadRequest,
+            json!({
+                "error": "Only replicated documents can be written in bulk, with `new_edits` set to false."
+            }),
+        );
+    }
+
+    let config = config.inner();
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    let n = cluster::replica_count(config, &auth.application_did, db_name);
+    let mut routes = cluster::route_documents(
+        &cluster_request,
+        &auth.application_did,
+        db_name,
+        n,
+        &payload.docs,
+    );
+    let local = routes.remove(&cluster::node_name()).unwrap_or_default();
+    let batches = routes
+        .iter()
+        .map(|(node, indexes)| {
+            let docs = indexes.iter().map(|index| payload.docs[*index].clone());
+            (node.clone(), docs.collect())
+        })
+        .collect();
+    let remote = cluster::bulk_docs(&cluster_request, db_name, batches).await;
+
+    // the result of a document is the first success among the nodes holding it
+    let mut replies = vec![Vec::new(); payload.docs.len()];
+    for (node, results) in remote {
+        let indexes = &routes[&node];
+        match results {
+            Some(results) => {
+                for (index, result) in indexes.iter().zip(results) {
+                    replies[*index].push(result);
+                }
+            }
+            None => {
+                for index in indexes {
+                    let doc_id = payload.docs[*index]["_id"].clone();
+                    replies[*index].push(json!({
+                        "id": doc_id,
+                        "error": format!("Could not reach node `{}`", node)
+                    }));
+                }
+            }
+        }
+    }
+
+    let docs = payload.docs.into_iter().enumerate();
+    for (index, doc) in docs.filter(|(index, _)| local.contains(index)) {
+        let doc_id = doc["_id"].clone();
+        let owner = Did(doc["_did"].as_str().unwrap_or_default().to_owned());
+        let deleted = doc["_deleted"] == true;
+        if !auth.permits_user(&owner) || (deleted && !auth.permits(db_name, Permission::Delete)) {
+            replies[index].push(json!({ "id": doc_id, "error": "forbidden" }));
+            continue;
+        }
+
+        replies[index].push(
+            match db::write_replicated_document(
+                &auth.application_did,
+                db_name,
+                doc,
+                config,
+                did_queue,
+            )
+            .await
+            {
+                Ok(result) => {
+                    if let Some(doc_id) = doc_id.as_str() {
+                        let operation = if deleted { "delete" } else { "write" };
+                        audit(config, &auth, db_name, doc_id, operation, Some(&owner));
+                    }
+                    result
+                }
+                Err(DatabaseError::UserDidConflict) => {
+                    json!({ "id": doc_id, "error": "conflict", "reason": "User DID conflict" })
+                }
+                Err(_) => json!({ "id": doc_id, "error": "Could not write document." }),
+            },
+        );
+    }
+
+    let results = replies
+        .into_iter()
+        .filter_map(|replies| {
+            let written = replies
+                .iter()
+                .position(|reply| reply.get("error").is_none());
+            replies.into_iter().nth(written.unwrap_or(0))
+        })
+        .collect::<Vec<_>>();
+    (Status::Created, json!(results))
+}
+
+/// read a local document, used to store replication checkpoints.
+/// It is ranked after the `_config` routes, since database names never start with `_`.
+#[get("/<db_name>/_local/<doc_id>", rank = 1)]
+pub fn local_document(
+    db_name: &str,
+    doc_id: &str,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Read) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::fetch_local_document(&auth.application_did, db_name, doc_id, config) {
+        Ok(document) => (Status::Ok, document),
+        Err(DatabaseError::MissingDocument) => (
+            Status::NotFound,
+            json!({
+                "error": "The document does not exist"
+            }),
+        ),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not read from database."
+            }),
+        ),
+    }
+}
+
+/// write a local document, which is never replicated
+#[put("/<db_name>/_local/<doc_id>", data = "<document>", rank = 1)]
+pub fn update_local_document(
+    db_name: &str,
+    doc_id: &str,
+    document: Json<Value>,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    if !auth.permits(db_name, Permission::Write) {
+        return forbidden();
+    }
+
+    if !db::database_exists(config, &auth.application_did, db_name) {
+        return missing_database();
+    }
+
+    match db::write_local_document(&auth.application_did, db_name, doc_id, &document, config) {
+        Ok(_) => (Status::Ok, json!({ "ok": true, "id": doc_id })),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not update database."
+            }),
+        ),
+    }
+}
+
+/// replicate the changes of a database into another, on this node or a remote one.
+/// One-shot replications answer once done, continuous ones run in the background until cancelled.
+#[post("/_replicate", data = "<payload>")]
+pub async fn replicate(
+    payload: Json<ReplicatePayload>,
+    config: &State<DbConfig>,
+    auth: Access,
+    did_queue: &State<Arc<Mutex<DidQueue>>>,
+    jobs: &State<replicator::Jobs>,
+) -> (Status, Value) {
+    // replications copy the documents of every user
+    if auth.user_did.is_some() {
+        return forbidden();
+    }
+
+    let payload = payload.into_inner();
+    let endpoint = |value: &Value, permission: Permission| match value {
+        Value::String(db_name) if !db_name.contains("://") => {
+            if !auth.permits(db_name, permission) {
+                return Err(forbidden());
+            }
+            Ok(replicator::Endpoint::Local {
+                application_did: auth.application_did.clone(),
+                db_name: db_name.to_owned(),
+                config: config.inner().clone(),
+                did_queue: did_queue.inner().clone(),
+                decrypt: auth.permits(db_name, Permission::Decrypt),
+            })
+        }
+        _ => replicator::Endpoint::remote(value).map_err(|e| {
+            (
+                Status::BadRequest,
+                json!({
+                    "error": format!("Invalid replication endpoint: {}", e)
+                }),
+            )
+        }),
+    };
+
+    let source = match endpoint(&payload.source, Permission::Read) {
+        Ok(source) => source,
+        Err(response) => return response,
+    };
+    let target_permission = if payload.create_target {
+        Permission::Admin
+    } else {
+        Permission::Write
+    };
+    let target = match endpoint(&payload.target, target_permission) {
+        Ok(target) => target,
+        Err(response) => return response,
+    };
+
+    let replication = replicator::Replication::new(
+        auth.application_did.clone(),
+        source,
+        target,
+        payload.continuous,
+        payload.create_target,
+        jobs.inner().clone(),
+    );
+    let id = replication.id.clone();
+
+    let mut guard = jobs.lock().await;
+    if payload.cancel {
+        return match guard.remove(&id) {
+            Some(job) => {
+                if let Some(task) = job.task {
+                    task.abort();
+                }
+                (Status::Ok, json!({ "ok": true, "_local_id": id }))
+            }
+            None => (
+                Status::NotFound,
+                json!({
+                    "error": "The replication is not running."
+                }),
+            ),
+        };
+    }
+
+    if guard.contains_key(&id) {
+        return (Status::Accepted, json!({ "ok": true, "_local_id": id }));
+    }
+
+    guard.insert(
+        id.clone(),
+        replicator::Job {
+            status: replication.status(),
+            task: None,
+            peer_url: None,
+        },
+    );
+
+    if payload.continuous {
+        let task = tokio::spawn(replication.run()).abort_handle();
+        if let Some(job) = guard.get_mut(&id) {
+            job.task = Some(task);
+        }
+        return (Status::Accepted, json!({ "ok": true, "_local_id": id }));
+    }
+
+    // the job is listed while it runs
+    drop(guard);
+    replication.run().await;
+
+    match jobs.lock().await.remove(&id) {
+        Some(job) if job.status.state == replicator::ReplicationState::Completed => (
+            Status::Ok,
+            util::merge_json_values(json!({ "ok": true }), json!(job.status)),
+        ),
+        Some(job) => (
+            Status::InternalServerError,
+            json!({
+                "error": format!(
+                    "Replication failed: {}",
+                    job.status.error.unwrap_or_default()
+                )
+            }),
+        ),
+        None => (
+            Status::InternalServerError,
+            json!({
+                "error": "Replication was interrupted."
+            }),
+        ),
+    }
+}
+
+/// list the replications of the application that are running
+#[get("/_replicate")]
+pub async fn replications(auth: Access, jobs: &State<replicator::Jobs>) -> (Status, Value) {
+    let statuses = jobs
+        .lock()
+        .await
+        .values()
+        .filter(|job| job.status.application_did == auth.application_did.0)
+        .map(|job| job.status.clone())
+        .collect::<Vec<_>>();
+
+    (Status::Ok, json!(statuses))
+}
+
+/// list the peers of the application with the status of their replications
+#[get("/_peers")]
+pub async fn peers(
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+    jobs: &State<replicator::Jobs>,
+) -> (Status, Value) {
+    let mut peers = Vec::new();
+    for peer in replicator::peers(config) {
+        if peer.application_did() == auth.application_did {
+            peers.push(replicator::peer_status(&peer, jobs).await);
+        }
+    }
+
+    (Status::Ok, json!(peers))
+}
+
+/// add or replace a peer whose databases are kept replicated with those of the application
+#[post("/_peers", data = "<payload>")]
+pub async fn update_peer(
+    payload: Json<PeerPayload>,
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+    did_queue: &State<Arc<Mutex<DidQueue>>>,
+    jobs: &State<replicator::Jobs>,
+    discovery: &State<discovery::Discovery>,
+) -> (Status, Value) {
+    let name = payload.name.as_str();
+    let peer = match replicator::Peer::parse(name, &payload.url) {
+        Ok(peer) => peer,
+        Err(e) => {
+            return (
+                Status::BadRequest,
+                json!({
+                    "error": format!("Invalid peer: {}", e)
+                }),
+            )
+        }
+    };
+
+    // the credentials decide which application the peer replicates
+    if peer.application_did() != auth.application_did {
+        return forbidden();
+    }
+
+    let taken = replicator::peers(config)
+        .iter()
+        .any(|peer| peer.name == name && peer.application_did() != auth.application_did);
+    if taken {
+        return (
+            Status::Conflict,
+            json!({
+                "error": "The peer name is used by another application."
+            }),
+        );
+    }
+
+    // the credentials are kept out of the config file, which only holds the host of the peer
+    let credentials = format!(
+        "{}:{}",
+        peer.url.username(),
+        peer.url.password().unwrap_or_default()
+    );
+    if db::store_peer_credentials(config, name, &credentials).is_err()
+        || !util::write_config("peers", name, &peer.describe())
+    {
+        return (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not save peer."
+            }),
+        );
+    }
+
+    // start replicating without waiting for the supervisor
+    let (config, did_queue, jobs, discovery) = (
+        config.inner().clone(),
+        did_queue.inner().clone(),
+        jobs.inner().clone(),
+        discovery.inner().clone(),
+    );
+    tokio::spawn(
+        async move { replicator::sync_peers(&config, &did_queue, &jobs, &discovery).await },
+    );
+
+    (Status::Created, json!({ "ok": true, "name": name }))
+}
+
+/// remove a peer and stop replicating with it
+#[delete("/_peers/<name>")]
+pub async fn remove_peer(
+    name: &str,
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+    jobs: &State<replicator::Jobs>,
+) -> (Status, Value) {
+    let exists = replicator::peers(config)
+        .iter()
+        .any(|peer| peer.name == name && peer.application_did() == auth.application_did);
+    if !exists {
+        return (
+            Status::NotFound,
+            json!({
+                "error": "The peer does not exist."
+            }),
+        );
+    }
+
+    if !util::remove_config("peers", name) || db::remove_peer_credentials(config, name).is_err() {
+        return (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not remove peer."
+            }),
+        );
+    }
+
+    jobs.lock().await.retain(|_, job| {
+        let removed = job.status.peer.as_deref() == Some(name);
+        if let (true, Some(task)) = (removed, &job.task) {
+            task.abort();
+        }
+        !removed
+    });
+
+    (Status::Ok, json!({ "ok": true }))
+}
+
+/// list the nodes found on the network that sync with the application
+#[get("/_discovery")]
+pub async fn discovered_nodes(
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+    discovery: &State<discovery::Discovery>,
+) -> (Status, Value) {
+    let syncing = db::discovery_keys(config)
+        .unwrap_or_default()
+        .iter()
+        .any(|(application_did, _)| *application_did == auth.application_did);
+
+    let nodes = discovery
+        .nodes(config, &auth.application_did)
+        .await
+        .into_iter()
+        .map(|node| {
+            json!({
+                "id": node.id,
+                "url": node.url,
+                "server": node.server,
+                "seen_at": node.seen_at
+            })
+        })
+        .collect::<Vec<_>>();
+
+    (
+        Status::Ok,
+        json!({
+            "enabled": discovery::is_enabled(),
+            "syncing": syncing,
+            "nodes": nodes
+        }),
+    )
+}
+
+/// sync the databases of the application with the nodes found that hold the same key
+#[put("/_discovery", data = "<payload>")]
+pub async fn enable_discovery(
+    payload: Json<DiscoveryPayload>,
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+    did_queue: &State<Arc<Mutex<DidQueue>>>,
+    jobs: &State<replicator::Jobs>,
+    discovery: &State<discovery::Discovery>,
+) -> (Status, Value) {
+    if payload.key.len() < DISCOVERY_KEY_MIN_LENGTH {
+        return (
+            Status::BadRequest,
+            json!({
+                "error": format!("The key must be at least {} characters long.", DISCOVERY_KEY_MIN_LENGTH)
+            }),
+        );
+    }
+
+    if db::set_discovery_key(config, &auth.application_did, Some(&payload.key)).is_err() {
+        return (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not save discovery key."
+            }),
+        );
+    }
+
+    // start replicating with the nodes already found without waiting for the supervisor
+    let (config, did_queue, jobs, discovery) = (
+        config.inner().clone(),
+        did_queue.inner().clone(),
+        jobs.inner().clone(),
+        discovery.inner().clone(),
+    );
+    tokio::spawn(
+        async move { replicator::sync_peers(&config, &did_queue, &jobs, &discovery).await },
+    );
+
+    (Status::Ok, json!({ "ok": true }))
+}
+
+/// stop syncing the databases of the application with the nodes found
+#[delete("/_discovery")]
+pub async fn disable_discovery(
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+    did_queue: &State<Arc<Mutex<DidQueue>>>,
+    jobs: &State<replicator::Jobs>,
+    discovery: &State<discovery::Discovery>,
+) -> (Status, Value) {
+    if db::set_discovery_key(config, &auth.application_did, None).is_err() {
+        return (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not remove discovery key."
+            }),
+        );
+    }
+
+    // stop the replications with the nodes found without waiting for the supervisor
+    let (config, did_queue, jobs, discovery) = (
+        config.inner().clone(),
+        did_queue.inner().clone(),
+        jobs.inner().clone(),
+        discovery.inner().clone(),
+    );
+    tokio::spawn(
+        async move { replicator::sync_peers(&config, &did_queue, &jobs, &discovery).await },
+    );
+
+    (Status::Ok, json!({ "ok": true }))
+}
+
+/// exchange the nodes known with another node
+#[post("/_discovery/gossip", data = "<gossip>")]
+pub async fn gossip(
+    gossip: Json<discovery::Gossip>,
+    config: &State<DbConfig>,
+    discovery: &State<discovery::Discovery>,
+) -> (Status, Value) {
+    if !discovery::is_enabled() {
+        return (
+            Status::NotFound,
+            json!({
+                "error": "Discovery is turned off on this node."
+            }),
+        );
+    }
+
+    match discovery.gossip(config, gossip.into_inner()).await {
+        Some(reply) => (Status::Ok, json!(reply)),
+        None => (
+            Status::ServiceUnavailable,
+            json!({
+                "error": "The node is not listening yet."
+            }),
+        ),
+    }
+}
+
+/// read the config file again, applying the settings that can change without a restart
+#[post("/_config/reload")]
+pub fn reload_config(auth: AdminAuth) -> (Status, Value) {
+    let reload = match config::reload() {
+        Ok(reload) => reload,
+        Err(e) => {
+            return (
+                Status::InternalServerError,
+                json!({
+                    "error": format!("{}, the previous config is kept.", e)
+                }),
+            )
+        }
+    };
+
+    let changes = json!({
+        "applied": reload.applied,
+        "restart_required": reload.restart_required
+    });
+    logging::log(
+        logging::Level::Info,
+        format!("{} reloaded the config", auth.name),
+        changes.clone(),
+    );
+
+    (
+        Status::Ok,
+        util::merge_json_values(json!({ "ok": true }), changes),
+    )
+}
+
+/// list the settings of the node by section, with their secrets hidden
+#[get("/_config")]
+pub fn node_config(_auth: AdminAuth) -> (Status, Value) {
+    (Status::Ok, json!(config::redacted_sections()))
+}
+
+/// read a setting of the node, hidden if it is a secret
+#[get("/_config/<section>/<key>")]
+pub fn config_value(section: &str, key: &str, _auth: AdminAuth) -> (Status, Value) {
+    let settings = config::get();
+    match settings
+        .sections
+        .get(section)
+        .and_then(|values| values.get(key))
+    {
+        Some(value) => (Status::Ok, json!(config::redact(section, key, value))),
+        None => (
+            Status::NotFound,
+            json!({
+                "error": "The config key does not exist."
+            }),
+        ),
+    }
+}
+
+/// change a setting of the node in the config file, which is then reloaded.
+/// The change is undone if it makes the config invalid.
+#[put("/_config/<section>/<key>", data = "<value>")]
+pub fn update_config_value(
+    section: &str,
+    key: &str,
+    value: Json<String>,
+    auth: AdminAuth,
+) -> (Status, Value) {
+    // names and values must not change the structure of the config file
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    };
+    if !is_name(section) || !is_name(key) || value.contains(['\n', '\r']) {
+        return (
+            Status::BadRequest,
+            json!({
+                "error": "Sections and keys are made of letters, digits, `_`, `-` and `.`, and values hold a single line."
+            }),
+        );
+    }
+
+    if keystore::holds(section, key) && keystore::exists() {
+        return (
+            Status::BadRequest,
+            json!({
+                "error": "The value is kept in the keystore."
+            }),
+        );
+    }
+
+    // the config file is only written once the settings it would hold are valid
+    let restart_required = match config::check(section, key, &value) {
+        Ok(restart_required) => restart_required,
+        Err(e) => {
+            return (
+                Status::BadRequest,
+                json!({
+                    "error": e
+                }),
+            )
+        }
+    };
+
+    let previous = config::file_value(section, key);
+    if !util::write_config(section, key, &value) {
+        return (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not write the config file."
+            }),
+        );
+    }
+
+    logging::log(
+        logging::Level::Info,
+        format!("{} changed the config", auth.name),
+        json!({ "section": section, "key": key }),
+    );
+    (
+        Status::Ok,
+        json!({
+            "ok": true,
+            "previous": previous.map(|previous| config::redact(section, key, &previous)),
+            "restart_required": restart_required.contains(&format!("{}.{}", section, key))
+        }),
+    )
+}
+
+/// counters of the node, like those of the `_stats` of CouchDB
+#[get("/_stats")]
+pub fn node_stats(_auth: AdminAuth) -> (Status, Value) {
+    (Status::Ok, metrics::summary(db::open_database_count()))
+}
+
+/// the background work of the node and the replications running, with their progress
+#[get("/_active_tasks")]
+pub async fn active_tasks(jobs: &State<replicator::Jobs>, _auth: AdminAuth) -> (Status, Value) {
+    let mut active = tasks::list();
+    for job in jobs.lock().await.values() {
+        let status = &job.status;
+        let replicated = status.checkpointed_source_seq;
+        active.push(json!({
+            "id": status.id,
+            "type": "replication",
+            "started_at": status.started_at,
+            "updated_at": status.updated_at,
+            "progress": match replicated + status.pending_changes {
+                0 => 100,
+                total => replicated * 100 / total,
+            },
+            "status": status,
+        }));
+    }
+
+    (Status::Ok, json!(active))
+}
+
+/// metrics of the node, in the Prometheus text format
+#[get("/_metrics")]
+pub async fn node_metrics(
+    config: &State<DbConfig>,
+    did_queue: &State<Arc<Mutex<DidQueue>>>,
+    _auth: AdminAuth,
+) -> (Status, (ContentType, String)) {
+    let pending = did_queue.lock().await.len();
+    let text = metrics::render(pending, db::disk_usage(config).unwrap_or_default());
+
+    (
+        Status::Ok,
+        (
+            ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+            text,
+        ),
+    )
+}
+
+/// show the peer ID of the node and the addresses other nodes reach it at over libp2p
+#[cfg(feature = "p2p")]
+#[get("/_p2p")]
+pub fn p2p_node(_auth: BasicAuth) -> (Status, Value) {
+    match p2p::node() {
+        Some((peer_id, addresses)) => (
+            Status::Ok,
+            json!({
+                "peer_id": peer_id,
+                "addresses": addresses
+            }),
+        ),
+        None => (
+            Status::NotFound,
+            json!({
+                "error": "The libp2p transport is turned off on this node."
+            }),
+        ),
+    }
+}
+
+/// create an API key scoped to some databases and operations
+#[post("/_api_keys", data = "<payload>")]
+pub fn create_api_key(
+    payload: Json<Scope>,
+    config: &State<DbConfig>,
+    auth: BasicAuth,
+) -> (Status, Value) {
+    let api_key = ApiKey {
+        id: util::generate_uuid().simple().to_string(),
+        application_did: auth.application_did.0,
+        scope: payload.into_inner(),
+        created_at: util::get_unix_epoch_time(),
+    };
+    let secret = util::generate_strong_password(32);
+
+    match db::create_api_key(config, &api_key, &secret) {
+        Ok(_) => (
+            Status::Created,
+            json!({
+                "ok": true,
+                "id": api_key.id,
+                // the key is only ever returned here
+                "key": format!("{}.{}", api_key.id, secret)
+            }),
+        ),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not create API key."
+            }),
+        ),
+    }
+}
+
+/// list the API keys of the application
+#[get("/_api_keys")]
+pub fn api_keys(config: &State<DbConfig>, auth: BasicAuth) -> (Status, Value) {
+    match db::list_api_keys(config, &auth.application_did) {
+        Ok(keys) => (Status::Ok, json!(keys)),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not fetch API keys."
+            }),
+        ),
+    }
+}
+
+/// revoke an API key
+#[delete("/_api_keys/<id>")]
+pub fn revoke_api_key(id: &str, config: &State<DbConfig>, auth: BasicAuth) -> (Status, Value) {
+    match db::revoke_api_key(config, &auth.application_did, id) {
+        Ok(_) => (Status::Ok, json!({ "ok": true })),
+        Err(DatabaseError::MissingDocument) => (
+            Status::NotFound,
+            json!({
+                "error": "The API key does not exist."
+            }),
+        ),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not revoke API key."
+            }),
+        ),
+    }
+}
+
+/// start a session, returning short-lived tokens limited to the requested user and scope
+#[post("/_session", data = "<payload>")]
+pub fn create_session(
+    payload: Option<Json<SessionPayload>>,
+    config: &State<DbConfig>,
+    auth: Access,
+) -> (Status, Value) {
+    let payload = payload.map(Json::into_inner).unwrap_or_default();
+
+    // a session cannot do more than the credential that started it
+    let scope = payload.scope.or_else(|| auth.scope.clone());
+    let within_scope = scope.as_ref().is_none_or(|scope| {
+        scope.databases.iter().all(|db| {
+            scope
+                .permissions
+                .iter()
+                .all(|permission| auth.permits(db, *permission))
+        })
+    });
+    let user_did = payload.user_did.or_else(|| auth.user_did.clone());
+    let within_user = user_did.as_ref().is_none_or(|did| auth.permits_user(did));
+
+    if !within_scope || !within_user {
+        return forbidden();
+    }
+
+    if let Some(did) = &user_did {
+        if !DbConfig::is_valid_did(&did.0, true) {
+            return (
+                Status::BadRequest,
+                json!({
+                    "error": format!("DID `{}` is not well formed", did.0)
+                }),
+            );
+        }
+    }
+
+    match session::issue(
+        config,
+        &auth.application_did,
+        user_did.as_ref(),
+        scope,
+        None,
+    ) {
+        Ok(tokens) => (Status::Created, tokens),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not start session."
+            }),
+        ),
+    }
+}
+
+/// exchange a refresh token for a new pair of tokens
+#[post("/_session/refresh", data = "<payload>")]
+pub fn refresh_session(payload: Json<RefreshPayload>, config: &State<DbConfig>) -> (Status, Value) {
+    let claims = match session::verify(config, &payload.refresh_token, TokenType::Refresh) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return (
+                Status::Unauthorized,
+                json!({
+                    "error": "Invalid or expired refresh token"
+                }),
+            )
+        }
+    };
+
+    // refresh tokens can only be used once, and the new tokens continue the same session
+    let user_did = claims.user_did.clone().map(Did);
+    let sid = Some(claims.sid.clone());
+    match session::revoke(config, &claims).and_then(|_| {
+        session::issue(
+            config,
+            &Did(claims.sub),
+            user_did.as_ref(),
+            claims.scope,
+            sid,
+        )
+    }) {
+        Ok(tokens) => (Status::Ok, tokens),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not refresh session."
+            }),
+        ),
+    }
+}
+
+/// return the details of the current session
+#[get("/_session")]
+pub fn session_info(auth: Access) -> (Status, Value) {
+    match auth.session {
+        Some(claims) => (Status::Ok, json!(claims)),
+        None => (
+            Status::NotFound,
+            json!({
+                "error": "The request is not authenticated with a session token."
+            }),
+        ),
+    }
+}
+
+/// end the current session by revoking its access and refresh tokens
+#[delete("/_session")]
+pub fn delete_session(config: &State<DbConfig>, auth: Access) -> (Status, Value) {
+    match auth.session {
+        Some(claims) => match session::end(config, &claims) {
+            Ok(_) => (Status::Ok, json!({ "ok": true })),
+            Err(_) => (
+                Status::InternalServerError,
+                json!({
+                    "error": "Could not end session."
+                }),
+            ),
+        },
+        None => (
+            Status::NotFound,
+            json!({
+                "error": "The request is not authenticated with a session token."
+            }),
+        ),
+    }
+}
+
+/// destroy the data key of a user, which makes the documents of the user encrypted at rest
+/// by the application unrecoverable
+#[delete("/_user/<did>/key")]
+pub fn shred_user_key(did: &str, config: &State<DbConfig>, auth: BasicAuth) -> (Status, Value) {
+    match db::remove_data_key(config, &auth.application_did, did) {
+        Ok(true) => (Status::Ok, json!({ "ok": true })),
+        Ok(false) => (
+            Status::NotFound,
+            json!({
+                "error": "The user has no data key."
+            }),
+        ),
+        Err(_) => (
+            Status::InternalServerError,
+            json!({
+                "error": "Could not remove data key."
+            }),
+        ),
+    }
+}
+
+/// list the accesses to the documents of a user, oldest first.
+/// Administrators of the node see the accesses of every application, and applications their own.
+/// With `format=ndjson`, the entries are exported as a JSON object per line.
+#[get("/_user/<did>/audit?<since>&<limit>&<format>")]
+pub fn user_audit(
+    did: &str,
+    since: Option<u64>,
+    limit: Option<usize>,
+    format: Option<&str>,
+    config: &State<DbConfig>,
+    admin: Option<AdminAuth>,
+    auth: Option<Access>,
+) -> (Status, (ContentType, String)) {
+    let error = |status: Status, error: &str| {
+        (
+            status,
+            (ContentType::JSON, json!({ "error": error }).to_string()),
+        )
+    };
+
+    let application_did = match (&admin, &auth) {
+        (Some(_), _) => None,
+        (None, Some(auth)) if auth.permits_user(&Did(did.to_owned())) => {
+            Some(&auth.application_did)
+        }
+        (None, Some(_)) => {
+            return error(
+                Status::Forbidden,
+                "The credential does not permit this operation.",
+            )
+        }
+        (None, None) => return error(Status::Unauthorized, "Invalid or missing credentials"),
+    };
+    if !DbConfig::is_valid_did(&did.to_owned(), true) {
+        return error(Status::BadRequest, "The DID is not well formed.");
+    }
+
+    let export = format == Some("ndjson");
+    let limit = if export {
+        limit
+    } else {
+        Some(limit.unwrap_or(AUDIT_PAGE_SIZE))
+    };
+    let entries = match db::audit_entries(config, did, application_did, since, limit) {
+        Ok(entries) => entries,
+        Err(_) => return error(Status::InternalServerError, "Could not read the audit log."),
+    };
+
+    if export {
+        let lines = entries
+            .iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect::<String>();
+        return (
+            Status::Ok,
+            (ContentType::new("application", "x-ndjson"), lines),
+        );
+    }
+
+    let next = entries.last().map(|entry| entry["seq"].clone());
+    (
+        Status::Ok,
+        (
+            ContentType::JSON,
+            json!({ "did": did, "entries": entries, "next": next }).to_string(),
+        ),
+    )
+}
+
+/// record an access to a document in the audit log of its owner
+fn audit(
+    config: &DbConfig,
+    auth: &Access,
+    db_name: &str,
+    doc_id: &str,
+    operation: &str,
+    owner: Option<&Did>,
+) {
+    if let Err(e) = db::audit(
+        config,
+        &auth.application_did,
+        db_name,
+        doc_id,
+        operation,
+        owner,
+    ) {
+        logging::log(
+            logging::Level::Error,
+            "Could not record an access in the audit log",
+            json!({ "db_name": db_name, "doc_id": doc_id, "error": format!("{:?}", e) }),
+        );
+    }
+}
+
+/// response for requests on a database that does not exist
+fn missing_database() -> (Status, Value) {
+    (
+        Status::NotFound,
+        json!({
+            "error": "The database does not exist."
+        }),
+    )
+}
+
+/// response for requests whose credential does not allow the operation
+fn forbidden() -> (Status, Value) {
+    (
+        Status::Forbidden,
+        json!({
+            "error": "The credential does not permit this operation."
+        }),
+    )
+}
+
+#[catch(404)]
+pub fn not_found(req: &Request) -> Value {
+    json!({
+        "error": format!("`{}` route not recognized", req.uri())
+    })
+}
+
+#[catch(401)]
+pub fn unauthorized(_req: &Request) -> Value {
+    json!({
+        "error": "authentication details is incorrect"
+    })
+}
+
+#[catch(403)]
+pub fn forbidden_request(_req: &Request) -> Value {
+    json!({
+        "error": "The credential does not permit this operation."
+    })
+}
+
+#[catch(400)]
+pub fn bad_request(_req: &Request) -> Value {
+    json!({
+        "error": "Invalid or missing important header"
+    })
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        index,
+        up,
+        ready,
+        init_application,
+        rotate_secret,
+        create_db,
+        delete_db,
+        all_dbs,
+        uuids,
+        update_document,
+        fetch_document,
+        delete_document,
+        coordinate_update_document,
+        coordinate_fetch_document,
+        coordinate_delete_document,
+        create_api_key,
+        api_keys,
+        revoke_api_key,
+        create_session,
+        refresh_session,
+        session_info,
+        delete_session,
+        shred_user_key,
+        user_audit,
+        find_documents,
+        set_encryption_policy,
+        encryption_policy,
+        set_database_mode,
+        database_mode,
+        verify_journal,
+        database_info,
+        changes,
+        shards,
+        conflicts,
+        revs_diff,
+        bulk_docs,
+        local_document,
+        update_local_document,
+        replicate,
+        replications,
+        peers,
+        update_peer,
+        remove_peer,
+        discovered_nodes,
+        enable_discovery,
+        disable_discovery,
+        gossip,
+        reload_config,
+        node_config,
+        config_value,
+        update_config_value,
+        node_stats,
+        active_tasks,
+        node_metrics
+    ]
+}
+
+/// routes of the libp2p transport, when the database is built with it
+#[cfg(feature = "p2p")]
+pub fn p2p_routes() -> Vec<rocket::Route> {
+    routes![p2p_node]
 }
@@ -1,31 +1,74 @@
 /// Copyright (c) Algorealm, Inc.
-use crate::prelude::*;
+use crate::{config, metrics, prelude::*, telemetry};
+use reqwest::Client;
 use rocket::serde::json::Value;
+use std::time::{Duration, Instant};
+use tracing::instrument;
 
-/// TODO! The address is hardcoded for now. It should be configurable
+/// URL of the bridge to the chain, read on every call so that it can be changed live
+fn chain_url() -> String {
+    config::get().contract.url.trim_end_matches('/').to_owned()
+}
+
+/// Send a request to the bridge, recording its latency and whether it failed
+#[instrument(name = "chain", skip(url), fields(otel.kind = "client"))]
+async fn get(call: &str, url: &str) -> Result<Value, GenericError> {
+    let start = Instant::now();
+    let response = async {
+        Client::new()
+            .get(url)
+            .headers(telemetry::trace_headers())
+            .send()
+            .await?
+            .json::<Value>()
+            .await
+    }
+    .await;
+    metrics::chain_call(call, start.elapsed(), response.is_ok());
+
+    Ok(response?)
+}
 
 /// Send an RPC to the contract to verify the validity of an account
 pub async fn auth_account(mnemonic: &str) -> Result<Value, GenericError> {
     let url = format!(
-        "http://localhost:5000/authenticate?mnemonic={}",
+        "{}/authenticate?mnemonic={}",
+        chain_url(),
         mnemonic.replace("\u{a0}", "+")
     );
-    let response = reqwest::get(&url).await?.json::<Value>().await?;
-
-    Ok(response)
+    get("authenticate", &url).await
 }
 
 /// Send an RPC to check if a DID exists on the network
 pub async fn did_exists(did: &str, mnemonic: &str) -> Result<Value, GenericError> {
     let url = format!(
-        "http://localhost:5000/didExists?address={}&mnemonic={}",
+        "{}/didExists?address={}&mnemonic={}",
+        chain_url(),
         did,
         mnemonic
             .split_ascii_whitespace()
             .collect::<Vec<_>>()
             .join("+")
     );
-    let response = reqwest::get(&url).await?.json::<Value>().await?;
+    get("didExists", &url).await
+}
+
+/// Check that the bridge to the chain answers, whatever its answer
+#[instrument(name = "chain", fields(call = "ping", otel.kind = "client"))]
+pub async fn ping() -> Result<(), GenericError> {
+    let start = Instant::now();
+    let response = async {
+        Client::builder()
+            .timeout(Duration::from_secs(CHAIN_PING_TIMEOUT))
+            .build()?
+            .get(chain_url())
+            .headers(telemetry::trace_headers())
+            .send()
+            .await
+    }
+    .await;
+    metrics::chain_call("ping", start.elapsed(), response.is_ok());
 
-    Ok(response)
+    response?;
+    Ok(())
 }
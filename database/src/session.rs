@@ -0,0 +1,111 @@
+/// Copyright (c) Algorealm, Inc.
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use rocket::serde::json::{
+    serde_json::{self, json},
+    Value,
+};
+use sha2::Sha256;
+
+use crate::{config, db, prelude::*, util};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Sign claims into a compact JWT (HS256)
+pub fn sign(config: &DbConfig, claims: &Claims) -> DatabaseResult<String> {
+    let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "HS256", "typ": "JWT" }).to_string());
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+    let signing_input = format!("{}.{}", header, payload);
+
+    let mut mac = HmacSha256::new_from_slice(&db::session_signing_key(config)?)
+        .map_err(|_| DatabaseError::OtherError)?;
+    mac.update(signing_input.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+    Ok(format!("{}.{}", signing_input, signature))
+}
+
+/// Check the signature, expiry and revocation of a token and return its claims
+pub fn verify(config: &DbConfig, token: &str, typ: TokenType) -> DatabaseResult<Claims> {
+    let (signing_input, signature) = token
+        .rsplit_once('.')
+        .ok_or(DatabaseError::InvalidSessionToken)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| DatabaseError::InvalidSessionToken)?;
+
+    let mut mac = HmacSha256::new_from_slice(&db::session_signing_key(config)?)
+        .map_err(|_| DatabaseError::OtherError)?;
+    mac.update(signing_input.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| DatabaseError::InvalidSessionToken)?;
+
+    let payload = signing_input
+        .split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .ok_or(DatabaseError::InvalidSessionToken)?;
+    let claims: Claims = serde_json::from_slice(&payload)?;
+
+    if claims.typ != typ
+        || claims.exp <= util::get_unix_epoch_time()
+        || db::is_session_revoked(config, &claims.jti)?
+        || db::is_session_revoked(config, &claims.sid)?
+    {
+        return Err(DatabaseError::InvalidSessionToken);
+    }
+
+    Ok(claims)
+}
+
+/// Issue an access token and a refresh token for a session.
+/// A new session is started unless the ID of the session being refreshed is given.
+pub fn issue(
+    config: &DbConfig,
+    application_did: &Did,
+    user_did: Option<&Did>,
+    scope: Option<Scope>,
+    sid: Option<String>,
+) -> DatabaseResult<Value> {
+    let now = util::get_unix_epoch_time();
+    let (ttl, refresh_ttl) = {
+        let settings = config::get();
+        (settings.session.ttl, settings.session.refresh_ttl)
+    };
+
+    let mut claims = Claims {
+        jti: util::generate_uuid().simple().to_string(),
+        sid: sid.unwrap_or_else(|| util::generate_uuid().simple().to_string()),
+        sub: application_did.0.clone(),
+        user_did: user_did.map(|did| did.0.clone()),
+        scope,
+        typ: TokenType::Access,
+        iat: now,
+        exp: now + ttl,
+    };
+    let token = sign(config, &claims)?;
+
+    claims.jti = util::generate_uuid().simple().to_string();
+    claims.typ = TokenType::Refresh;
+    claims.exp = now + refresh_ttl;
+    let refresh_token = sign(config, &claims)?;
+
+    Ok(json!({
+        "token": token,
+        "token_type": "Bearer",
+        "expires_in": ttl,
+        "refresh_token": refresh_token
+    }))
+}
+
+/// Revoke a token until it would have expired anyway
+pub fn revoke(config: &DbConfig, claims: &Claims) -> DatabaseResult<()> {
+    db::revoke_session(config, &claims.jti, claims.exp)
+}
+
+/// End the session of a token, revoking all its tokens, including those refreshed later
+pub fn end(config: &DbConfig, claims: &Claims) -> DatabaseResult<()> {
+    // the tokens of the session expire with the last refresh token issued for it
+    let expires_at = util::get_unix_epoch_time() + config::get().session.refresh_ttl;
+    db::revoke_session(config, &claims.sid, expires_at)
+}
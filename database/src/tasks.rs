@@ -0,0 +1,89 @@
+/// Copyright (c) Algorealm, Inc.
+use rocket::serde::json::{serde_json::json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::util;
+
+/// Background work of the node, as listed by `_active_tasks`
+struct Task {
+    kind: &'static str,
+    started_at: u64,
+    updated_at: u64,
+    /// percentage of the work done, unknown for work that never ends
+    progress: Option<u64>,
+    status: Value,
+}
+
+static TASKS: Mutex<BTreeMap<u64, Task>> = Mutex::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Handle to a task that is listed until it is dropped
+pub struct TaskHandle(u64);
+
+/// Start listing a task
+pub fn start(kind: &'static str, status: Value) -> TaskHandle {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let now = util::get_unix_epoch_time();
+    TASKS.lock().unwrap_or_else(|e| e.into_inner()).insert(
+        id,
+        Task {
+            kind,
+            started_at: now,
+            updated_at: now,
+            progress: None,
+            status,
+        },
+    );
+
+    TaskHandle(id)
+}
+
+impl TaskHandle {
+    /// Report the progress of the task
+    pub fn update(&self, progress: Option<u64>, status: Value) {
+        if let Some(task) = TASKS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(&self.0)
+        {
+            task.updated_at = util::get_unix_epoch_time();
+            task.progress = progress;
+            task.status = status;
+        }
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        TASKS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.0);
+    }
+}
+
+/// the tasks running, oldest first
+pub fn list() -> Vec<Value> {
+    TASKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(id, task)| {
+            json!({
+                "id": id,
+                "type": task.kind,
+                "started_at": task.started_at,
+                "updated_at": task.updated_at,
+                "progress": task.progress,
+                "status": task.status,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,172 @@
+/// Copyright (c) Algorealm, Inc.
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use reqwest::header::{HeaderMap as OutgoingHeaders, HeaderName, HeaderValue};
+use rocket::{
+    http::HeaderMap,
+    route::{Handler, Outcome},
+    Data, Request, Route,
+};
+use std::sync::OnceLock;
+use tracing::{field, info_span, Instrument, Level, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, Layer, Registry};
+
+use crate::config;
+
+/// provider exporting the spans, flushed when the node stops
+static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+/// Export the spans of the node to the OpenTelemetry collector configured in `[tracing]`
+pub fn init() -> Result<(), String> {
+    let settings = config::get().tracing.clone();
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(settings.endpoint)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            settings.service_name,
+        )]))
+        .build();
+
+    // only the spans of the node are exported, not those of the libraries sending them
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("samaritan-db"))
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::TRACE));
+    tracing::subscriber::set_global_default(Registry::default().with(layer))
+        .map_err(|e| e.to_string())?;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let _ = PROVIDER.set(provider);
+
+    Ok(())
+}
+
+/// Export the spans not sent yet
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        let _ = provider.shutdown();
+    }
+}
+
+/// Trace context of an incoming request, read from its `traceparent` and `tracestate` headers
+struct IncomingHeaders<'a, 'h>(&'a HeaderMap<'h>);
+
+impl Extractor for IncomingHeaders<'_, '_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    /// only the headers of the trace context are read
+    fn keys(&self) -> Vec<&str> {
+        ["traceparent", "tracestate"]
+            .into_iter()
+            .filter(|name| self.0.contains(name))
+            .collect()
+    }
+}
+
+/// Headers of an outgoing request the trace context is written to
+struct Outgoing<'a>(&'a mut OutgoingHeaders);
+
+impl Injector for Outgoing<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Headers carrying the context of the current span, so that the receiver continues its trace
+pub fn trace_headers() -> OutgoingHeaders {
+    let mut headers = OutgoingHeaders::new();
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut Outgoing(&mut headers))
+    });
+
+    headers
+}
+
+/// A route handler run within a span, continuing the trace of the client if it sent one
+#[derive(Clone)]
+struct Traced {
+    name: String,
+    handler: Box<dyn Handler>,
+}
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let span = info_span!(
+            "request",
+            otel.name = %self.name,
+            otel.kind = "server",
+            otel.status_code = field::Empty,
+            http.response.status_code = field::Empty,
+            url.path = %request.uri().path(),
+        );
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&IncomingHeaders(request.headers()))
+        });
+        span.set_parent(parent);
+
+        let outcome = self
+            .handler
+            .handle(request, data)
+            .instrument(span.clone())
+            .await;
+        let status = match &outcome {
+            Outcome::Success(response) => Some(response.status()),
+            Outcome::Error(status) => Some(*status),
+            // another route handles the request, in its own span
+            Outcome::Forward(_) => None,
+        };
+        if let Some(status) = status {
+            span.record("http.response.status_code", status.code);
+            if status.code >= 500 {
+                span.record("otel.status_code", "ERROR");
+            }
+        }
+
+        outcome
+    }
+}
+
+/// Run the handlers of routes within spans named after their method and URI
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            let name = format!(
+                "{} {}",
+                route.method,
+                route.uri.as_str().split('?').next().unwrap_or_default()
+            );
+            route.handler = Box::new(Traced {
+                name,
+                handler: route.handler,
+            });
+            route
+        })
+        .collect()
+}
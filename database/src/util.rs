@@ -1,12 +1,19 @@
 /// Copyright (c) Algorealm, Inc.
-use crate::prelude::*;
+use crate::{config, keystore, logging};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use ini::Ini;
 use rand::Rng;
 use rocket::serde::json::Value;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::path::Path;
 use std::process;
 use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 use uuid::Uuid;
 
 /// get uuid
@@ -21,6 +28,11 @@ pub fn get_unix_epoch_time() -> u64 {
     duration_since_epoch.as_secs()
 }
 
+/// value of the `Server` header, naming the database and its version
+pub fn server_header(version: &str) -> String {
+    format!("SamaritanDB v{}", version)
+}
+
 pub fn is_directory_within_parent(child: &str, parent: &str) -> bool {
     let child_path = Path::new(child).canonicalize().ok();
     let parent_path = Path::new(parent).canonicalize().ok();
@@ -35,27 +47,38 @@ pub fn is_directory_within_parent(child: &str, parent: &str) -> bool {
 
 /// read value from config file
 pub fn read_config(section: &str, key: &str) -> Cow<'static, str> {
-    if let Ok(conf) = Ini::load_from_file(CONFIG_FILE_PATH) {
-        if let Some(section) = conf.section(Some(section)) {
-            if let Some(value) = section.get(key) {
-                return Cow::Owned(value.to_owned());
-            }
-        }
+    config::get()
+        .sections
+        .get(section)
+        .and_then(|section| section.get(key))
+        .map(|value| Cow::Owned(value.to_owned()))
+        .unwrap_or_default()
+}
+
+/// Change the config file and read it again.
+/// The change is kept on disk even if the new config is refused, like an edit by hand.
+fn update_config(update: impl FnOnce(&mut Ini)) -> bool {
+    let path = config::path();
+    let Ok(mut conf) = Ini::load_from_file(&path) else {
+        return false;
+    };
+
+    update(&mut conf);
+    if conf.write_to_file(&path).is_err() {
+        return false;
     }
 
-    "".into()
+    if let Err(e) = config::reload() {
+        logging::error(e);
+    }
+    true
 }
 
 /// write value into config file
 pub fn write_config(section: &str, key: &str, new_value: &str) -> bool {
-    if let Ok(mut conf) = Ini::load_from_file(CONFIG_FILE_PATH) {
-        // Set a value:
+    update_config(|conf| {
         conf.set_to(Some(section), key.into(), new_value.into());
-        if let Ok(_) = conf.write_to_file(CONFIG_FILE_PATH) {
-            return true;
-        }
-    }
-    false
+    })
 }
 
 /// generate document rev
@@ -72,6 +95,12 @@ pub fn generate_rev(n: u64, data: &str) -> String {
     rev
 }
 
+/// split a document rev into its generation and hash
+pub fn parse_rev(rev: &str) -> Option<(u64, &str)> {
+    let (generation, hash) = rev.split_once('-')?;
+    Some((generation.parse().ok()?, hash))
+}
+
 /// produce a simple hash
 pub fn hash_string(data: &str) -> Cow<'static, str> {
     // Calculate MD5 hash
@@ -81,6 +110,56 @@ pub fn hash_string(data: &str) -> Cow<'static, str> {
     format!("{:x}", hash).into()
 }
 
+/// hash the secret of an API key.
+/// The secrets are random and long, so a fast unsalted hash does not make them guessable.
+pub fn hash_api_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// check the secret of an API key against its hash
+pub fn verify_api_secret(secret: &str, hash: &str) -> bool {
+    constant_time_eq(&hash_api_secret(secret), hash)
+}
+
+/// compare two secrets in a time that does not depend on where they differ
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// hash a secret with Argon2, producing a PHC string that embeds its salt
+pub fn hash_secret(secret: &str) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .ok()
+        .map(|hash| hash.to_string())
+}
+
+/// check a secret against its Argon2 hash.
+/// MD5 digests stored before secrets were hashed with Argon2 are still recognized.
+pub fn verify_secret(secret: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed_hash) => Argon2::default()
+            .verify_password(secret.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => !hash.is_empty() && constant_time_eq(&hash_string(secret), hash),
+    }
+}
+
+/// remove a value from the config file
+pub fn remove_config(section: &str, key: &str) -> bool {
+    update_config(|conf| {
+        conf.delete_from(Some(section), key);
+    })
+}
+
+/// remove a section from the config file
+pub fn remove_config_section(section: &str) -> bool {
+    update_config(|conf| {
+        conf.delete(Some(section));
+    })
+}
+
 /// merge two json values together
 pub fn merge_json_values(mut base: Value, mut override_value: Value) -> Value {
     match (base.as_object_mut(), override_value.as_object_mut()) {
@@ -100,6 +179,14 @@ pub fn merge_json_values(mut base: Value, mut override_value: Value) -> Value {
     base
 }
 
+/// convert a dotted JSON path, e.g. `contact.email`, into a JSON pointer
+pub fn json_pointer(path: &str) -> String {
+    format!(
+        "/{}",
+        path.replace('~', "~0").replace('/', "~1").replace('.', "/")
+    )
+}
+
 /// remove a field from a Value and return it
 pub fn remove_field(mut value: Value, field_name: &str) -> (Value, Option<Value>) {
     let mut removed_field = None;
@@ -133,13 +220,54 @@ pub fn generate_strong_password(length: usize) -> String {
 
 // check for important config and refuse to start the database if they are not set\
 pub fn check_start_config() -> String {
-    // read in blockchain config
-    let mnemonic = read_config("contract", "mnemonic");
+    // read in blockchain config, from the encrypted keystore if the config has been migrated
+    let mnemonic = match keystore::read_secret("contract", "mnemonic") {
+        Ok(mnemonic) => mnemonic,
+        Err(e) => {
+            println!("Could not unlock the keystore: {}", e);
+            process::exit(2);
+        }
+    };
+
     if mnemonic.is_empty() {
         // kill process
-        println!("Please check the config.ini file and input a mnemonic for your funded application account.");
+        println!(
+            "Please check the {} file and input a mnemonic for your funded application account.",
+            config::path().display()
+        );
+        process::exit(2);
+    }
+
+    if !keystore::exists() {
+        println!("The mnemonic is stored in plaintext, run `samaritan-db keystore migrate` to encrypt it.");
+    }
+
+    mnemonic
+}
+
+// load the master key of document encryption, and refuse to start the database if it is unavailable
+pub fn check_encryption_config() -> Option<[u8; 32]> {
+    if !config::get().encryption.enabled {
+        return None;
+    }
+
+    // the master key is never written to the config file
+    if !keystore::exists() {
+        println!("Encryption at rest requires a keystore, run `samaritan-db keystore migrate` to create one.");
+        process::exit(2);
+    }
+
+    let master_key = keystore::read_or_create_secret("encryption", "master_key", || {
+        STANDARD.encode(rand::random::<[u8; 32]>())
+    })
+    .ok()
+    .and_then(|key| STANDARD.decode(key).ok())
+    .and_then(|key| <[u8; 32]>::try_from(key).ok());
+
+    if master_key.is_none() {
+        println!("Could not load the master key of encryption at rest from the keystore.");
         process::exit(2);
     }
 
-    mnemonic.to_string()
+    master_key
 }